@@ -1,6 +1,10 @@
-/// Library to help sort out a few things
+//! Library to help sort out a few things
 
-use std::{fmt, fs, error::Error, path::Path, ffi::OsStr, collections::HashMap, cmp::Ordering};
+use std::{fmt, fs, io, error::Error, path::Path, ffi::OsStr, collections::HashMap, cmp::Ordering, convert::TryFrom};
+use std::io::{Read, Seek, SeekFrom};
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use unicode_width::UnicodeWidthStr;
 
 // Useful constants
 const MILLIS_PER_SECOND: usize = 1000;
@@ -119,11 +123,11 @@ impl SimpleTime {
         // Note: upcast to 128 in case large number; should be rare case
         let new_millis: i128 = self.to_milliseconds() as i128 + offset as i128;
         if new_millis < 0 {
-            return Err(NegativeSimpleTime)
+            Err(NegativeSimpleTime)
         }
         else {
             *self = SimpleTime::from_milliseconds(new_millis as usize);
-            return Ok(())
+            Ok(())
         }
     }
 }
@@ -134,6 +138,13 @@ impl PartialOrd for SimpleTime {
     }
 }
 
+impl fmt::Display for SimpleTime {
+    /// Format as HH:MM:SS.mmm
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", VttWriter::timestamp(self))
+    }
+}
+
 
 /// Error type for trying to make a negative SimpleTime
 #[derive(Debug, Clone)]
@@ -147,6 +158,274 @@ impl fmt::Display for NegativeSimpleTime {
     }
 }
 
+/// Frame rates supported for SMPTE timecode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameRate {
+    /// 24000/1001 frames per second
+    Fps23_976,
+    Fps24,
+    Fps25,
+    /// 30000/1001 frames per second, drop-frame timecode
+    Fps29_97Df,
+    /// 30000/1001 frames per second, non-drop-frame timecode
+    Fps29_97Ndf,
+    Fps30,
+    Fps50,
+    /// 60000/1001 frames per second, drop-frame timecode
+    Fps59_94Df,
+    /// 60000/1001 frames per second, non-drop-frame timecode
+    Fps59_94Ndf,
+    Fps60,
+}
+
+impl FrameRate {
+    /// Look up a frame rate by name, e.g. "25", "29.97df" or "59.94ndf".
+    ///
+    /// NTSC rates given without a suffix use non-drop-frame timecode.
+    pub fn from_name(name: &str) -> Option<FrameRate> {
+        match name.to_lowercase().as_str() {
+            "23.976" | "23.98" => Some(FrameRate::Fps23_976),
+            "24" => Some(FrameRate::Fps24),
+            "25" => Some(FrameRate::Fps25),
+            "29.97df" | "29.97-df" => Some(FrameRate::Fps29_97Df),
+            "29.97" | "29.97ndf" | "29.97-ndf" => Some(FrameRate::Fps29_97Ndf),
+            "30" => Some(FrameRate::Fps30),
+            "50" => Some(FrameRate::Fps50),
+            "59.94df" | "59.94-df" => Some(FrameRate::Fps59_94Df),
+            "59.94" | "59.94ndf" | "59.94-ndf" => Some(FrameRate::Fps59_94Ndf),
+            "60" => Some(FrameRate::Fps60),
+            _ => None,
+        }
+    }
+    /// The frame rate as a fraction of frames per second
+    pub fn ratio(&self) -> (usize, usize) {
+        match self {
+            FrameRate::Fps23_976 => (24000, 1001),
+            FrameRate::Fps24 => (24, 1),
+            FrameRate::Fps25 => (25, 1),
+            FrameRate::Fps29_97Df | FrameRate::Fps29_97Ndf => (30000, 1001),
+            FrameRate::Fps30 => (30, 1),
+            FrameRate::Fps50 => (50, 1),
+            FrameRate::Fps59_94Df | FrameRate::Fps59_94Ndf => (60000, 1001),
+            FrameRate::Fps60 => (60, 1),
+        }
+    }
+    /// Frames per second
+    pub fn fps(&self) -> f64 {
+        let (num, den) = self.ratio();
+        num as f64 / den as f64
+    }
+    /// Number of frames counted per timecode second
+    pub fn nominal(&self) -> usize {
+        let (num, den) = self.ratio();
+        (num + den - 1) / den
+    }
+    /// Whether timecode at this rate skips frame numbers to stay in step with the clock
+    pub fn drop_frame(&self) -> bool {
+        matches!(self, FrameRate::Fps29_97Df | FrameRate::Fps59_94Df)
+    }
+    /// Frame numbers dropped at the start of each minute not divisible by ten
+    fn dropped_per_minute(&self) -> usize {
+        if self.drop_frame() { self.nominal() / 15 } else { 0 }
+    }
+    /// The drop-frame equivalent of this rate, if it has one
+    fn with_drop_frame(&self) -> FrameRate {
+        match self {
+            FrameRate::Fps29_97Ndf => FrameRate::Fps29_97Df,
+            FrameRate::Fps59_94Ndf => FrameRate::Fps59_94Df,
+            r => *r,
+        }
+    }
+    /// Length of a number of frames in milliseconds, rounded to the nearest millisecond
+    pub fn frames_to_milliseconds(&self, frames: usize) -> usize {
+        let (num, den) = self.ratio();
+        (frames * MILLIS_PER_SECOND * den * 2 + num) / (2 * num)
+    }
+    /// Number of whole frames closest to a number of milliseconds
+    pub fn milliseconds_to_frames(&self, millis: usize) -> usize {
+        let (num, den) = self.ratio();
+        (millis * num * 2 + MILLIS_PER_SECOND * den) / (2 * MILLIS_PER_SECOND * den)
+    }
+}
+
+impl fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FrameRate::Fps23_976 => "23.976",
+            FrameRate::Fps24 => "24",
+            FrameRate::Fps25 => "25",
+            FrameRate::Fps29_97Df => "29.97df",
+            FrameRate::Fps29_97Ndf => "29.97ndf",
+            FrameRate::Fps30 => "30",
+            FrameRate::Fps50 => "50",
+            FrameRate::Fps59_94Df => "59.94df",
+            FrameRate::Fps59_94Ndf => "59.94ndf",
+            FrameRate::Fps60 => "60",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// SMPTE timecode of hours, minutes, seconds and frames at a given frame rate.
+///
+/// Drop-frame timecode is written with a semicolon before the frames (`01:00:00;00`) and skips
+/// the first frame numbers of every minute except each tenth, so that it keeps in step with
+/// the clock at 29.97 and 59.94 frames per second.
+///
+/// # Examples
+/// Convert drop-frame timecode to a SimpleTime
+/// ```
+/// use ccap::{FrameRate, Timecode};
+///
+/// let tc = Timecode::parse("00:10:00;00", FrameRate::Fps29_97Df).unwrap();
+/// assert_eq!(tc.to_frames(), 17982);
+/// assert_eq!(tc.to_simple_time().to_milliseconds(), 599_999);
+/// ```
+///
+/// Convert a SimpleTime to the nearest frame
+/// ```
+/// use ccap::{FrameRate, SimpleTime, Timecode};
+///
+/// let tc = Timecode::from_simple_time(&SimpleTime::from_milliseconds(1_480), FrameRate::Fps25);
+/// assert_eq!(tc.to_string(), "00:00:01:12");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timecode {
+    hours: usize,
+    minutes: usize,
+    seconds: usize,
+    frames: usize,
+    rate: FrameRate,
+}
+
+impl Timecode {
+    /// Create a Timecode from hours, minutes, seconds and frames
+    pub fn from_parts(hours: usize, minutes: usize, seconds: usize, frames: usize, rate: FrameRate)
+    -> Result<Timecode, TimecodeError> {
+        let tc = Timecode { hours, minutes, seconds, frames, rate };
+        if minutes >= 60 || seconds >= 60 || frames >= rate.nominal() {
+            return Err(TimecodeError::InvalidTimecode(tc.to_string()));
+        }
+        // Drop-frame timecode never labels these frames
+        if seconds == 0 && minutes % 10 != 0 && frames < rate.dropped_per_minute() {
+            return Err(TimecodeError::DroppedFrame(tc.to_string()));
+        }
+        Ok(tc)
+    }
+    /// Whether a string looks like SMPTE timecode, i.e. `HH:MM:SS:FF` or `HH:MM:SS;FF`
+    pub fn is_timecode(s: &str) -> bool {
+        let fields: Vec<&str> = s.split([':', ';']).collect();
+        fields.len() == 4 && fields.iter().all(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_digit()))
+    }
+    /// Parse `HH:MM:SS:FF` timecode, or `HH:MM:SS;FF` for drop-frame.
+    ///
+    /// A semicolon selects drop-frame counting for 29.97 and 59.94 frames per second, and is
+    /// invalid at other rates.
+    pub fn parse(s: &str, rate: FrameRate) -> Result<Timecode, TimecodeError> {
+        if !Timecode::is_timecode(s) {
+            return Err(TimecodeError::InvalidTimecode(s.to_string()));
+        }
+        let rate = match s.find(';') {
+            None => rate,
+            // Only the frames follow a semicolon
+            Some(i) if Some(i) == s.rfind([':', ';']) && rate.with_drop_frame().drop_frame() => {
+                rate.with_drop_frame()
+            },
+            Some(_) => return Err(TimecodeError::InvalidTimecode(s.to_string())),
+        };
+        let fields: Vec<usize> = s.split([':', ';'])
+            .map(|f| f.parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| TimecodeError::InvalidTimecode(s.to_string()))?;
+        Timecode::from_parts(fields[0], fields[1], fields[2], fields[3], rate)
+    }
+    /// Create a Timecode from a count of frames since zero
+    pub fn from_frames(frames: usize, rate: FrameRate) -> Timecode {
+        let nominal = rate.nominal();
+        let mut n = frames;
+        let drop = rate.dropped_per_minute();
+        if drop > 0 {
+            // Add back the frame numbers that were skipped
+            let per_ten_minutes = nominal * 600 - drop * 9;
+            let per_minute = nominal * 60 - drop;
+            let tens = n / per_ten_minutes;
+            let remainder = n % per_ten_minutes;
+            n += drop * 9 * tens;
+            if remainder > drop {
+                n += drop * ((remainder - drop) / per_minute);
+            }
+        }
+        let frames = n % nominal;
+        let total_seconds = n / nominal;
+        Timecode {
+            hours: total_seconds / 3600,
+            minutes: (total_seconds / 60) % 60,
+            seconds: total_seconds % 60,
+            frames,
+            rate,
+        }
+    }
+    /// Count the frames since zero
+    pub fn to_frames(&self) -> usize {
+        let nominal = self.rate.nominal();
+        let total_minutes = self.hours * 60 + self.minutes;
+        let labelled = (self.hours * 3600 + self.minutes * 60 + self.seconds) * nominal + self.frames;
+        labelled - self.rate.dropped_per_minute() * (total_minutes - total_minutes / 10)
+    }
+    /// Convert a SimpleTime to the timecode of the nearest frame
+    pub fn from_simple_time(t: &SimpleTime, rate: FrameRate) -> Timecode {
+        Timecode::from_frames(rate.milliseconds_to_frames(t.to_milliseconds()), rate)
+    }
+    /// Convert to a SimpleTime, rounded to the nearest millisecond
+    pub fn to_simple_time(&self) -> SimpleTime {
+        SimpleTime::from_milliseconds(self.rate.frames_to_milliseconds(self.to_frames()))
+    }
+    /// Get hours
+    pub fn hour(&self) -> usize { self.hours }
+    /// Get minutes
+    pub fn minute(&self) -> usize { self.minutes }
+    /// Get seconds
+    pub fn second(&self) -> usize { self.seconds }
+    /// Get frames
+    pub fn frame(&self) -> usize { self.frames }
+    /// Get the frame rate
+    pub fn rate(&self) -> FrameRate { self.rate }
+}
+
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}{}{:02}",
+            self.hours,
+            self.minutes,
+            self.seconds,
+            if self.rate.drop_frame() { ';' } else { ':' },
+            self.frames
+        )
+    }
+}
+
+/// Error type for Timecode
+#[derive(Debug, Clone)]
+pub enum TimecodeError {
+    InvalidTimecode(String),
+    DroppedFrame(String),
+}
+
+impl fmt::Display for TimecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimecodeError::InvalidTimecode(s) => write!(f, "invalid timecode {}", s),
+            TimecodeError::DroppedFrame(s) => {
+                write!(f, "timecode {} labels a frame skipped by drop-frame counting", s)
+            },
+        }
+    }
+}
+
+impl Error for TimecodeError {}
+
 /// General parser for any caption file
 pub fn parse_file(fname: &str) -> Result<Caption, Box<dyn Error>> {
     match Path::new(&fname).extension().and_then(OsStr::to_str) {
@@ -154,6 +433,7 @@ pub fn parse_file(fname: &str) -> Result<Caption, Box<dyn Error>> {
             match ext {
                 "vtt" | "txt" => Ok(VttParser::from_file(fname)?),
                 "srt" => Ok(SrtParser::from_file(fname)?),
+                "json" => Ok(AsrParser::from_file(fname)?),
                 _ => Err(CaptionParserError::UnsupportedFileType(ext.to_string()))?,
             }
         }
@@ -166,8 +446,8 @@ pub fn write_caption(fname: &str, caption: &Caption) -> Result<(), Box<dyn Error
     match Path::new(&fname).extension().and_then(OsStr::to_str) {
         Some(ext) => {
             match ext {
-                "vtt" | "txt" => VttWriter::to_file(&fname, &caption)?,
-                "srt" => SrtWriter::to_file(&fname, &caption)?,
+                "vtt" | "txt" => VttWriter::to_file(fname, caption)?,
+                "srt" => SrtWriter::to_file(fname, caption)?,
                 _ => Err(CaptionParserError::UnsupportedFileType(fname.to_string()))?,
             }
         },
@@ -196,6 +476,30 @@ impl fmt::Display for CaptionParserError {
 }
 
 
+/// Group the lines of a caption file into blocks.
+///
+/// Each block starts with a blank line and runs until the next blank line, so that cues may
+/// carry more than one line of text.  Runs of blank lines do not produce empty blocks.
+fn group_blocks(lines: &[&str]) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for line in lines.iter() {
+        if line.is_empty() && current.iter().any(|l| !l.is_empty()) {
+            chunks.push(current.join("\n"));
+            current.clear();
+        }
+        if line.is_empty() && !current.is_empty() {
+            // Collapse repeated blank lines
+            continue;
+        }
+        current.push(line);
+    }
+    if current.iter().any(|l| !l.is_empty()) {
+        chunks.push(current.join("\n"));
+    }
+    chunks
+}
+
 /// Type for parsing VTT caption files.
 /// This parser assumes a format of:
 /// - Header
@@ -204,7 +508,8 @@ impl fmt::Display for CaptionParserError {
 ///   - Line 1: Block Number
 ///   - Line 2: Speaker: HH:MM:SS.mmm --> HH:MM:SS.mmm
 ///     - NOTE: Speaker is optional
-///   - Line 3: Text to display for the caption
+///   - Line 3 onwards: Text to display for the caption, one or more lines
+///
 /// and will return a Caption object when asked to parse.
 pub struct VttParser;
 
@@ -218,35 +523,16 @@ impl VttParser {
     /// Parse a Caption
     pub fn parse(contents: &str) -> Result<Caption, VttParserError> {
         // First, find the header
-        let (header, vtt_line) = VttParser::header(&contents)?;
+        let (header, vtt_line) = VttParser::header(contents)?;
         let start_line = vtt_line + 1;
-        // Get the length of the file in lines, to check blocking
-        let total_lines = contents.lines().count();
-        // Figure out if the total number of lines remaining is going to break into even blocks
-        let blocks_remaining = (total_lines - start_line) / 4;
-        if (blocks_remaining as f32) != ((total_lines as f32 - start_line as f32) / 4.0) {
-            return Err(VttParserError::UnexpectedEndOfFile)?;
-        }
-        // We have the right number of blocks.
-        // Vector for storing CaptionBlock items
-        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(blocks_remaining);
-        // Skip lines we've already seen
-        let mut line_iter = contents.lines();
-        for _ in 0..(start_line) {
-            line_iter.next();
-        }
         // Create a vector of all remaining lines
-        let lines: Vec<&str> = line_iter.collect();
-        // Iterate and process blocks
-        for i in 0..blocks_remaining {
-            let block_line_start = i * 4;
-            let block_line_end = (i * 4) + 3;
-            let current_block = lines[block_line_start..(block_line_end + 1)]
-                .iter()
-                .map(|a| a.to_string())
-                .collect::<Vec<String>>()
-                .join("\n");
-            blocks.push(VttParser::block(&current_block)?);
+        let lines: Vec<&str> = contents.lines().skip(start_line).collect();
+        // Blocks are separated by blank lines and may carry several lines of text
+        let chunks = group_blocks(&lines);
+        // Vector for storing CaptionBlock items
+        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(chunks.len());
+        for current_block in chunks.iter() {
+            blocks.push(VttParser::block(current_block)?);
         }
         // We're all good, pass along the caption object
         Ok(
@@ -263,7 +549,7 @@ impl VttParser {
 
         if let Some(n) = s.lines().position(is_webvtt) {
             let header_opt = match n {
-                0 | 1 | 2 => None,
+                0..=2 => None,
                 nn => {
                     // Fetch all the lines preceding N -2
                     let header = s.lines().take(nn - 2)
@@ -281,8 +567,8 @@ impl VttParser {
     }
     /// Parse a block
     fn block(s: &str) -> Result<CaptionBlock, VttParserError> {
-        // Make sure we have exactly four lines to parse
-        if s.lines().count() != 4 {
+        // Make sure we have at least four lines to parse
+        if s.lines().count() < 4 {
             return Err(VttParserError::UnexpectedEndOfFile);
         }
 
@@ -299,13 +585,15 @@ impl VttParser {
         let _ = VttParser::block_number(block_line)?;
         let header_line = s_iter.next().ok_or(VttParserError::UnexpectedEndOfFile)?;
         let (speaker, start, end) = VttParser::block_header(header_line)?;
-        let text_line = s_iter.next().ok_or(VttParserError::UnexpectedEndOfFile)?;
-        let text = VttParser::block_text(text_line);
+        let text_lines = s_iter.collect::<Vec<&str>>().join("\n");
+        let (text_lines, words) = VttParser::inline_timestamps(&text_lines, start, end)?;
+        let text = StyledText::from_vtt(&VttParser::block_text(&text_lines));
         Ok(CaptionBlock {
             speaker,
             start,
             end,
             text,
+            words,
         })
     }
     /// Parse a string slice into a block number
@@ -381,16 +669,18 @@ impl VttParser {
     /// Parse a string slice into a tuple of block header information
     fn block_header(s: &str) -> Result<(Option<String>, SimpleTime, SimpleTime), VttParserError> {
         // See if we have a line to begin with
-        if s.len() == 0 {
+        if s.is_empty() {
             return Err(VttParserError::UnexpectedEndOfFile);
         }
         if s.chars().nth(0).unwrap().is_numeric() {
             // Pass entire string to have timestamps parsed
             let (start, end) = VttParser::block_header_timestamps(s)?;
-            return Ok((None, start, end));
+            Ok((None, start, end))
         } else {
-            // Find first timestamp
-            let first_loc = match s.find(char::is_numeric) {
+            // Find first timestamp, which is the word before the arrow; the name may have
+            // digits in it
+            let before_arrow = s.find(" --> ").and_then(|n| s[..n].rfind(' ')).map(|n| n + 1);
+            let first_loc = match before_arrow.or_else(|| s.find(char::is_numeric)) {
                 Some(n) => n,
                 None => Err(VttParserError::BlockHeaderInvalid(
                         String::from(s)))?,
@@ -419,7 +709,7 @@ impl VttParser {
                     },
                 }
             )?;
-            return Ok((Some(name.to_string()), start, end));
+            Ok((Some(name.to_string()), start, end))
         }
     }
     /// Parse the remainder of a line for start, end timestamps
@@ -437,31 +727,93 @@ impl VttParser {
                         // Need to process the timestamps
                         let start = VttParser::block_timestamp(ts1)?;
                         let end = VttParser::block_timestamp(ts2)?;
-                        return Ok((start, end));
+                        Ok((start, end))
 
                     } else {
-                        return Err(
+                        Err(
                             VttParserError::InvalidTimestamp(
-                                String::from(s)));
+                                String::from(s)))
                     }
                 } else {
-                    return Err(
+                    Err(
                         VttParserError::InvalidTimestamp(
-                            String::from(s)));
+                            String::from(s)))
                 }
             } else {
-                return Err(VttParserError::InvalidTimestamp(
-                    String::from(s)));
+                Err(VttParserError::InvalidTimestamp(
+                    String::from(s)))
             }
         } else {
-            return Err(
-                VttParserError::InvalidTimestamp(String::from(s)));
+            Err(
+                VttParserError::InvalidTimestamp(String::from(s)))
         }
     }
     /// Parse the text of a block; thin wrapper for to_string()
     fn block_text(s: &str) -> String {
         s.to_string()
     }
+    /// Remove inline timestamps such as `<00:00:01.500>` from the text of a block.
+    ///
+    /// Returns the remaining text along with the words delimited by the timestamps.  Text before
+    /// the first timestamp is timed from the start of the block, and each word lasts until the
+    /// next timestamp or the end of the block.  If there are no timestamps, no words are returned.
+    fn inline_timestamps(s: &str, start: SimpleTime, end: SimpleTime)
+    -> Result<(String, Vec<TimedWord>), VttParserError> {
+        let mut text = String::with_capacity(s.len());
+        // Start time and text of each stretch between timestamps
+        let mut stretches: Vec<(SimpleTime, String)> = vec!((start, String::new()));
+        let mut rest = s;
+        while let Some(open) = rest.find('<') {
+            let tail = &rest[open + 1..];
+            let close = match tail.find('>') {
+                Some(n) => n,
+                None => break,
+            };
+            let tag = &tail[..close];
+            if tag.starts_with(|c: char| c.is_ascii_digit()) {
+                let t = VttParser::inline_timestamp(tag)?;
+                text.push_str(&rest[..open]);
+                let (previous, stretch) = stretches.last_mut().unwrap();
+                stretch.push_str(&rest[..open]);
+                // Keep timestamps in order and inside the block
+                let t = if t < *previous { *previous } else if t > end { end } else { t };
+                stretches.push((t, String::new()));
+            }
+            else {
+                // Some other tag; leave it in the text
+                let kept = &rest[..open + close + 2];
+                text.push_str(kept);
+                stretches.last_mut().unwrap().1.push_str(kept);
+            }
+            rest = &tail[close + 1..];
+        }
+        text.push_str(rest);
+        stretches.last_mut().unwrap().1.push_str(rest);
+        if stretches.len() == 1 {
+            return Ok((text, Vec::new()));
+        }
+        let mut words: Vec<TimedWord> = Vec::with_capacity(stretches.len());
+        for (i, (t, stretch)) in stretches.iter().enumerate() {
+            let plain = StyledText::from_vtt(stretch).to_plain();
+            let word = plain.trim();
+            if word.is_empty() {
+                continue;
+            }
+            let word_end = match stretches.get(i + 1) {
+                Some((next, _)) => *next,
+                None => end,
+            };
+            words.push(TimedWord::from(word, *t, word_end));
+        }
+        Ok((text, words))
+    }
+    /// Parse an inline timestamp, which may leave out the hours
+    fn inline_timestamp(s: &str) -> Result<SimpleTime, VttParserError> {
+        match s.len() {
+            9 => VttParser::block_timestamp(&format!("00:{}", s)),
+            _ => VttParser::block_timestamp(s),
+        }
+    }
 }
 
 /// Error type for VttParser
@@ -506,18 +858,15 @@ pub struct VttWriter;
 impl VttWriter {
     /// Write a full VTT file to disk
     pub fn to_file(fname: &str, cap: &Caption) -> Result<(), Box<dyn Error>> {
-        fs::write(fname, VttWriter::write(&cap))?;
+        fs::write(fname, VttWriter::write(cap))?;
         Ok(())
     }
     /// Write a full VTT file to a string
     pub fn write(cap: &Caption) -> String {
         let mut components: Vec<String> = Vec::with_capacity(cap.blocks.len() + 1);
-        components.push(VttWriter::header(&cap));
-        let mut block_num = 1;
-
-        for block in cap.blocks.iter() {
-            components.push(VttWriter::block(block, block_num));
-            block_num += 1;
+        components.push(VttWriter::header(cap));
+        for (block_num, block) in cap.blocks.iter().enumerate() {
+            components.push(VttWriter::block(block, block_num + 1));
         }
         components.join("\n")
     }
@@ -533,9 +882,26 @@ impl VttWriter {
                 Some(person) => format!("{} {} --> {}", person, ts_start, ts_end),
                 None => format!("{} --> {}", ts_start, ts_end),
             },
-            cb.text
+            VttWriter::text(cb)
         )
     }
+    /// Write the text of a VTT block, marking word timings with inline timestamps
+    fn text(cb: &CaptionBlock) -> String {
+        // Find where each timed word begins in the plain text
+        let plain = cb.text.to_plain();
+        let mut markers: Vec<(usize, String)> = Vec::with_capacity(cb.words.len());
+        let mut cursor = 0;
+        for (i, w) in cb.words.iter().enumerate() {
+            // Words that can no longer be found in the text (e.g. after editing) are skipped
+            if let Some(pos) = plain[cursor..].find(&w.text) {
+                if i > 0 || w.start > cb.start {
+                    markers.push((cursor + pos, format!("<{}>", VttWriter::timestamp(&w.start))));
+                }
+                cursor += pos + w.text.len();
+            }
+        }
+        cb.text.render(TextDialect::Vtt, &markers)
+    }
     /// Write a VTT timestamp
     fn timestamp(t: &SimpleTime) -> String {
         format!(
@@ -561,10 +927,11 @@ impl VttWriter {
 /// - Blocks of caption with
 ///   - Line 1: Block Number
 ///   - Line 2: HH:MM:SS.mmm --> HH:MM:SS.mmm
-///   - Line 3: [Speaker] subtitle
+///   - Line 3 onwards: [Speaker] subtitle, one or more lines
 ///     - Note: Speaker is optional, and will be parsed if enclosed in brackets. If the speaker is
 ///       identified in some other way, then it will be displayed for other formats, but may not be
 ///       formatted as the speaker.
+///
 /// and will return a Caption object when asked to parse.
 pub struct SrtParser;
 
@@ -579,27 +946,13 @@ impl SrtParser {
     pub fn parse(contents: &str) -> Result<Caption, SrtParserError> {
         // Inject a newline for simplicity in processing
         let contents = &("\n".to_owned() + contents);
-        let total_lines = contents.lines().count();
-        // Figure out if the total number of lines remaining is going to break into even blocks
-        let blocks_remaining = total_lines / 4;
-        if (blocks_remaining as f32) != (total_lines as f32 ) / 4.0 {
-            return Err(SrtParserError::UnexpectedEndOfFile)?;
-        }
-        // We have the right number of blocks.
-        // Vector for storing CaptionBlock items
-        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(blocks_remaining);
-        // Create a vector of all remaining lines, prepending one blank line
         let lines: Vec<&str> = contents.lines().collect();
-        // Iterate and process blocks
-        for i in 0..blocks_remaining {
-            let block_line_start = i * 4;
-            let block_line_end = (i * 4) + 3;
-            let current_block = lines[block_line_start..(block_line_end + 1)]
-                .iter()
-                .map(|a| a.to_string())
-                .collect::<Vec<String>>()
-                .join("\n");
-            blocks.push(SrtParser::block(&current_block)?);
+        // Blocks are separated by blank lines and may carry several lines of text
+        let chunks = group_blocks(&lines);
+        // Vector for storing CaptionBlock items
+        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(chunks.len());
+        for current_block in chunks.iter() {
+            blocks.push(SrtParser::block(current_block)?);
         }
         // We're all good, pass along the caption object
         Ok(
@@ -611,8 +964,8 @@ impl SrtParser {
     }
     /// Parse a block
     fn block(s: &str) -> Result<CaptionBlock, SrtParserError> {
-        // Make sure we have exactly four lines to parse
-        if s.lines().count() != 4 {
+        // Make sure we have at least four lines to parse
+        if s.lines().count() < 4 {
             return Err(SrtParserError::UnexpectedEndOfFile);
         }
 
@@ -629,13 +982,14 @@ impl SrtParser {
         let _ = SrtParser::block_number(block_line)?;
         let header_line = s_iter.next().ok_or(SrtParserError::UnexpectedEndOfFile)?;
         let (start, end) = SrtParser::block_timestamps(header_line)?;
-        let text_line = s_iter.next().ok_or(SrtParserError::UnexpectedEndOfFile)?;
-        let (speaker, text) = SrtParser::block_text(text_line)?;
+        let text_lines = s_iter.collect::<Vec<&str>>().join("\n");
+        let (speaker, text) = SrtParser::block_text(&text_lines)?;
         Ok(CaptionBlock {
             speaker,
             start,
             end,
-            text,
+            text: StyledText::from_srt(&text),
+            words: Vec::new(),
         })
     }
     /// Parse a string slice into a block number
@@ -723,38 +1077,39 @@ impl SrtParser {
                         // Need to process the timestamps
                         let start = SrtParser::block_timestamp(ts1)?;
                         let end = SrtParser::block_timestamp(ts2)?;
-                        return Ok((start, end));
+                        Ok((start, end))
 
                     } else {
-                        return Err(
+                        Err(
                             SrtParserError::InvalidTimestamp(
-                                String::from(s)));
+                                String::from(s)))
                     }
                 } else {
-                    return Err(
+                    Err(
                         SrtParserError::InvalidTimestamp(
-                            String::from(s)));
+                            String::from(s)))
                 }
             } else {
-                return Err(SrtParserError::InvalidTimestamp(
-                    String::from(s)));
+                Err(SrtParserError::InvalidTimestamp(
+                    String::from(s)))
             }
         } else {
-            return Err(
-                SrtParserError::InvalidTimestamp(String::from(s)));
+            Err(
+                SrtParserError::InvalidTimestamp(String::from(s)))
         }
     }
     /// Parse the text and optional speaker of a block
     fn block_text(s: &str) -> Result<(Option<String>, String), SrtParserError> {
-        // See if we have a speaker
-        if let Some(n0) = s.chars().position(|x| x == '[') {
+        // See if we have a speaker; brackets on later lines are text, such as [music]
+        let first_line = s.lines().next().unwrap_or("");
+        if let Some(n0) = first_line.chars().position(|x| x == '[') {
             if n0 != 0 {
                 return Err(SrtParserError::InvalidSpeakerPlacement(s.to_string()));
             }
-            if let Some(n1) = s.chars().position(|x| x == ']') {
+            if let Some(n1) = first_line.chars().position(|x| x == ']') {
                 // Valid Speaker
                 let speaker = s.get((n0 + 1)..n1).unwrap().to_string();
-                let text = s.get((n1 + 2)..).unwrap().to_string();
+                let text = s.get((n1 + 2)..).unwrap_or("").to_string();
                 return Ok((Some(speaker.to_string()), text.to_string()));
             }
             else {
@@ -814,17 +1169,14 @@ pub struct SrtWriter;
 impl SrtWriter {
     /// Write a full VTT file to disk
     pub fn to_file(fname: &str, cap: &Caption) -> Result<(), Box<dyn Error>> {
-        fs::write(fname, SrtWriter::write(&cap))?;
+        fs::write(fname, SrtWriter::write(cap))?;
         Ok(())
     }
     /// Write a full VTT file to a string
     pub fn write(cap: &Caption) -> String {
         let mut components: Vec<String> = Vec::with_capacity(cap.blocks.len());
-        let mut block_num = 1;
-
-        for block in cap.blocks.iter() {
-            components.push(SrtWriter::block(block, block_num));
-            block_num += 1;
+        for (block_num, block) in cap.blocks.iter().enumerate() {
+            components.push(SrtWriter::block(block, block_num + 1));
         }
         components.join("\n")
     }
@@ -834,12 +1186,13 @@ impl SrtWriter {
         let ts_end = SrtWriter::timestamp(&cb.end);
 
         format!(
-            "{}\n{}\n{}\n",
+            "{}\n{} --> {}\n{}\n",
             n,
-            format!("{} --> {}", ts_start, ts_end),
+            ts_start,
+            ts_end,
             match &cb.speaker {
-                Some(person) => format!("[{}] {}", person, cb.text),
-                None => format!("{}", cb.text),
+                Some(person) => format!("[{}] {}", person, cb.text.to_srt()),
+                None => cb.text.to_srt(),
             },
         )
     }
@@ -857,152 +1210,4505 @@ impl SrtWriter {
 
         
 
-/// Caption blocks contain an optional speaker, start and end times, and the text that will be
-/// displayed on the screen during the block.
-/// Not particularly useful on their own.
-///
-/// # Examples
-/// Create a CaptionBlock with no speaker, from 0 seconds to 1 second, and a text of "Hello!"
-/// ```
-/// use ccap::{CaptionBlock, SimpleTime};
-///
-/// let block = CaptionBlock::from(
-///     None,
-///     SimpleTime::from_milliseconds(0),
-///     SimpleTime::from_milliseconds(1000),
-///     String::from("Hello!")).unwrap();
-/// assert_eq!(block.speaker(), None);
-/// assert_eq!(block.start().second(), 0);
-/// assert_eq!(block.end().second(), 1);
-/// assert_eq!(block.text(), "Hello!");
-/// ```
-#[derive(Debug, Clone)]
-pub struct CaptionBlock {
-    speaker: Option<String>,
-    start: SimpleTime,
-    end: SimpleTime,
-    text: String,
+/// Speech recognition services whose word-level JSON output can be imported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsrFormat {
+    /// OpenAI Whisper (and WhisperX) output with `segments[].words[]`
+    Whisper,
+    /// AWS Transcribe output with `results.items[]`
+    AwsTranscribe,
+    /// Google Speech-to-Text output with `results[].alternatives[].words[]`
+    GoogleStt,
 }
 
-impl CaptionBlock {
-    /// Construct a CaptionBlock from its parts
-    pub fn from(speaker: Option<String>, start: SimpleTime, end: SimpleTime, text: String) -> Result<CaptionBlock, CaptionBlockError> {
-        // Verify start is less than end
-        let diff = (end.to_milliseconds() as i128) - (start.to_milliseconds() as i128);
-        if diff < 0 {
-            Err(CaptionBlockError::EndsBeforeStart(start, end))
-        }
-        else {
-            Ok(
-                CaptionBlock {
-                    speaker,
-                    start,
-                    end,
-                    text,
-                }
-            )
-        }
-    }
-    /// Get a copy of this block's text
-    pub fn text(&self) -> String {
-        self.text.clone()
-    }
-    /// Get a copy of this block's speaker
-    pub fn speaker(&self) -> Option<String> {
-        self.speaker.clone()
-    }
-    /// Get a copy of this caption block's start time
-    pub fn start(&self) -> SimpleTime {
-        self.start.clone()
-    }
-    /// Get a copy of this caption block's end time
-    pub fn end(&self) -> SimpleTime {
-        self.end.clone()
-    }
-    /// Get the length of this caption block in milliseconds
-    pub fn length_millis(&self) -> usize {
-        // We're guaranteed that end > start, no need to worry about negatives
-        self.end.to_milliseconds() - self.start.to_milliseconds()
-    }
-    /// Offset the times in this caption block
-    pub fn offset_milliseconds(&mut self, n: isize) -> Result<(), NegativeSimpleTime> {
-        self.start.offset(n)?;
-        self.end.offset(n)?;
-        Ok(())
-    }
-    /// Set the start time
-    pub fn set_start(&mut self, start: SimpleTime) -> Result<(), NegativeSimpleTime> {
-        if start.to_milliseconds() <= self.end.to_milliseconds() {
-            self.start = start;
-            Ok(())
-        }
-        else {
-            Err(NegativeSimpleTime)
-        }
-    }
-    pub fn set_end(&mut self, end: SimpleTime) -> Result<(), NegativeSimpleTime> {
-        if end.to_milliseconds() >= self.start.to_milliseconds() {
-            self.end = end;
-            Ok(())
-        }
-        else {
-            Err(NegativeSimpleTime)
+impl AsrFormat {
+    /// Look up a format from its command line name
+    pub fn from_name(name: &str) -> Option<AsrFormat> {
+        match name.to_lowercase().as_str() {
+            "whisper" | "whisperx" => Some(AsrFormat::Whisper),
+            "aws" | "transcribe" | "aws-transcribe" => Some(AsrFormat::AwsTranscribe),
+            "google" | "google-stt" | "gcp" => Some(AsrFormat::GoogleStt),
+            _ => None,
         }
     }
 }
 
-/// Error types for CaptionBlock
-#[derive(Debug)]
-pub enum CaptionBlockError {
-    EndsBeforeStart(SimpleTime, SimpleTime)
+/// A single recognised word from a speech recognition transcript
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsrWord {
+    pub text: String,
+    pub start: SimpleTime,
+    pub end: SimpleTime,
+    pub confidence: Option<f64>,
+    pub speaker: Option<String>,
+    /// Punctuation tokens are attached to the preceding word without a space
+    pub punctuation: bool,
 }
 
-/// Simple Speaker type for ordering tables
-#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
-struct SimpleSpeaker {
-    name: String,
-    talk_time: usize,
+/// Limits used when grouping recognised words into caption blocks
+#[derive(Debug, Clone)]
+pub struct SegmentationOptions {
+    /// Maximum number of characters on a single line of text
+    pub max_chars_per_line: usize,
+    /// Maximum number of lines of text in a block
+    pub max_lines: usize,
+    /// Maximum length of a block in milliseconds
+    pub max_duration: usize,
+    /// Start a new block whenever the speaker changes
+    pub break_on_speaker_change: bool,
+    /// Start a new block when the silence between words exceeds this many milliseconds
+    pub max_pause: Option<usize>,
 }
 
-impl SimpleSpeaker {
-    pub fn new(name: String, talk_time: usize) -> SimpleSpeaker {
-        SimpleSpeaker {
-            name,
-            talk_time,
+impl Default for SegmentationOptions {
+    fn default() -> Self {
+        SegmentationOptions {
+            max_chars_per_line: 42,
+            max_lines: 2,
+            max_duration: 7000,
+            break_on_speaker_change: true,
+            max_pause: Some(1500),
         }
     }
 }
 
-/// Type for storing captions
+/// Parser for word-level speech recognition JSON files.
 ///
-/// They are generated by parsers, and can be used by generators to write out new caption files.
-#[derive(Debug)]
-pub struct Caption {
-    pub header: Option<String>,
-    pub blocks: Vec<CaptionBlock>,
-}
+/// Words are read from the file and grouped into caption blocks according to a set of
+/// SegmentationOptions.  The source service is detected from the structure of the file unless
+/// it is given explicitly.
+///
+/// # Examples
+/// ```
+/// use ccap::{AsrParser, SegmentationOptions};
+///
+/// let json = r#"{"segments": [{"start": 0.0, "end": 1.0, "text": " Hello there.",
+///     "words": [{"word": " Hello", "start": 0.0, "end": 0.4, "probability": 0.98},
+///               {"word": " there.", "start": 0.5, "end": 1.0, "probability": 0.91}]}]}"#;
+/// let cap = AsrParser::parse(json, None, &SegmentationOptions::default()).unwrap();
+/// assert_eq!(cap.blocks.len(), 1);
+/// assert_eq!(cap.blocks[0].text(), "Hello there.");
+/// assert_eq!(cap.blocks[0].end().to_milliseconds(), 1000);
+/// ```
+pub struct AsrParser;
 
-impl Caption {
-    /// Construct a Caption from its components
-    pub fn from(header_str: Option<&str>, blocks: Vec<CaptionBlock>) -> Caption {
-        Caption {
-            header: match header_str {
-                Some(s) => Some(s.to_string()),
-                None => None,
-            },
-            blocks: blocks,
-        }
+impl AsrParser {
+    /// Parse File into a Caption, detecting its format and using default segmentation
+    pub fn from_file(fname: &str) -> Result<Caption, Box<dyn Error>> {
+        let s = fs::read_to_string(fname)?;
+        let cap = AsrParser::parse(&s, None, &SegmentationOptions::default())?;
+        Ok(cap)
     }
-    /// Offset all of the timestamps in this caption
-    pub fn offset_milliseconds(&mut self, n: isize) -> Result<(), NegativeSimpleTime> {
-        for b in self.blocks.iter_mut() {
-            b.start.offset(n)?;
-            b.end.offset(n)?;
-        }
-        Ok(())
+    /// Parse a Caption from speech recognition output
+    pub fn parse(contents: &str, format: Option<AsrFormat>, opts: &SegmentationOptions)
+    -> Result<Caption, AsrParserError> {
+        let words = AsrParser::words(contents, format)?;
+        Ok(
+            Caption {
+                header: None,
+                blocks: AsrParser::segment(&words, opts),
+            }
+        )
     }
-    /// Get the first time in milliseconds from a caption
-    pub fn time_head(&self) -> usize {
+    /// Read the recognised words out of speech recognition output
+    pub fn words(contents: &str, format: Option<AsrFormat>) -> Result<Vec<AsrWord>, AsrParserError> {
+        let value: serde_json::Value = serde_json::from_str(contents)
+            .map_err(|e| AsrParserError::InvalidJson(e.to_string()))?;
+        let format = match format {
+            Some(f) => f,
+            None => AsrParser::detect(&value).ok_or(AsrParserError::UnknownFormat)?,
+        };
+        let mut words = match format {
+            AsrFormat::Whisper => AsrParser::whisper_words(&value)?,
+            AsrFormat::AwsTranscribe => AsrParser::aws_words(&value)?,
+            AsrFormat::GoogleStt => AsrParser::google_words(&value)?,
+        };
+        // Guard against services listing words out of order
+        words.sort_by_key(|w| w.start.to_milliseconds());
+        Ok(words)
+    }
+    /// Guess which service produced a JSON document
+    fn detect(value: &serde_json::Value) -> Option<AsrFormat> {
+        if value.get("segments").is_some_and(|v| v.is_array()) {
+            Some(AsrFormat::Whisper)
+        }
+        else if value.pointer("/results/items").is_some_and(|v| v.is_array()) {
+            Some(AsrFormat::AwsTranscribe)
+        }
+        else if value.get("results").is_some_and(|v| v.is_array()) {
+            Some(AsrFormat::GoogleStt)
+        }
+        else {
+            None
+        }
+    }
+    /// Read words from Whisper output
+    fn whisper_words(value: &serde_json::Value) -> Result<Vec<AsrWord>, AsrParserError> {
+        let segments = value.get("segments")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| AsrParserError::MissingField("segments".to_string()))?;
+        let mut words = Vec::new();
+        for segment in segments.iter() {
+            let segment_speaker = segment.get("speaker")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string());
+            match segment.get("words").and_then(|v| v.as_array()) {
+                Some(segment_words) => {
+                    for w in segment_words.iter() {
+                        let text = AsrParser::field_str(w, "word")?.trim().to_string();
+                        if text.is_empty() {
+                            continue;
+                        }
+                        // WhisperX leaves out timings for words it could not align
+                        let (start, end) = match (w.get("start"), w.get("end")) {
+                            (Some(s), Some(e)) => (AsrParser::seconds(s)?, AsrParser::seconds(e)?),
+                            _ => match words.last() {
+                                Some(AsrWord { end, .. }) => (*end, *end),
+                                None => (AsrParser::seconds(AsrParser::field(segment, "start")?)?,
+                                         AsrParser::seconds(AsrParser::field(segment, "start")?)?),
+                            },
+                        };
+                        words.push(AsrWord {
+                            text,
+                            start,
+                            end,
+                            confidence: w.get("probability")
+                                .or_else(|| w.get("score"))
+                                .and_then(|v| v.as_f64()),
+                            speaker: w.get("speaker")
+                                .and_then(|v| v.as_str())
+                                .map(|s| s.to_string())
+                                .or_else(|| segment_speaker.clone()),
+                            punctuation: false,
+                        });
+                    }
+                },
+                None => {
+                    // No word timestamps; spread the segment's words over its duration
+                    let start = AsrParser::seconds(AsrParser::field(segment, "start")?)?
+                        .to_milliseconds();
+                    let end = AsrParser::seconds(AsrParser::field(segment, "end")?)?
+                        .to_milliseconds();
+                    let text = AsrParser::field_str(segment, "text")?;
+                    let total_chars: usize = text.split_whitespace()
+                        .map(|w| w.chars().count())
+                        .sum();
+                    let mut elapsed = 0;
+                    for w in text.split_whitespace() {
+                        let n = w.chars().count();
+                        let w_start = start + end.saturating_sub(start) * elapsed / total_chars.max(1);
+                        elapsed += n;
+                        let w_end = start + end.saturating_sub(start) * elapsed / total_chars.max(1);
+                        words.push(AsrWord {
+                            text: w.to_string(),
+                            start: SimpleTime::from_milliseconds(w_start),
+                            end: SimpleTime::from_milliseconds(w_end),
+                            confidence: None,
+                            speaker: segment_speaker.clone(),
+                            punctuation: false,
+                        });
+                    }
+                },
+            }
+        }
+        Ok(words)
+    }
+    /// Read words from AWS Transcribe output
+    fn aws_words(value: &serde_json::Value) -> Result<Vec<AsrWord>, AsrParserError> {
+        let items = value.pointer("/results/items")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| AsrParserError::MissingField("results.items".to_string()))?;
+        // Older output only lists speakers in a separate table keyed on start time
+        let mut speaker_table: HashMap<String, String> = HashMap::new();
+        if let Some(segments) = value.pointer("/results/speaker_labels/segments")
+            .and_then(|v| v.as_array()) {
+            for segment in segments.iter() {
+                let segment_items = segment.get("items").and_then(|v| v.as_array());
+                for item in segment_items.into_iter().flatten() {
+                    if let (Some(t), Some(spk)) = (
+                        item.get("start_time").and_then(|v| v.as_str()),
+                        item.get("speaker_label").and_then(|v| v.as_str())
+                    ) {
+                        speaker_table.insert(t.to_string(), spk.to_string());
+                    }
+                }
+            }
+        }
+        let mut words: Vec<AsrWord> = Vec::with_capacity(items.len());
+        for item in items.iter() {
+            let alternative = item.pointer("/alternatives/0")
+                .ok_or_else(|| AsrParserError::MissingField("alternatives".to_string()))?;
+            let text = AsrParser::field_str(alternative, "content")?.to_string();
+            let confidence = alternative.get("confidence").and_then(|v| {
+                v.as_f64().or_else(|| v.as_str().and_then(|s| s.parse::<f64>().ok()))
+            });
+            if item.get("type").and_then(|v| v.as_str()) == Some("punctuation") {
+                // Punctuation has no timing of its own; borrow the previous word's
+                let (start, end, speaker) = match words.last() {
+                    Some(w) => (w.end, w.end, w.speaker.clone()),
+                    None => continue,
+                };
+                words.push(AsrWord { text, start, end, confidence, speaker, punctuation: true });
+                continue;
+            }
+            let start = AsrParser::seconds(AsrParser::field(item, "start_time")?)?;
+            let end = AsrParser::seconds(AsrParser::field(item, "end_time")?)?;
+            let speaker = item.get("speaker_label")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .or_else(|| {
+                    item.get("start_time")
+                        .and_then(|v| v.as_str())
+                        .and_then(|t| speaker_table.get(t).cloned())
+                });
+            words.push(AsrWord { text, start, end, confidence, speaker, punctuation: false });
+        }
+        Ok(words)
+    }
+    /// Read words from Google Speech-to-Text output
+    fn google_words(value: &serde_json::Value) -> Result<Vec<AsrWord>, AsrParserError> {
+        let results = value.get("results")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| AsrParserError::MissingField("results".to_string()))?;
+        let word_list = |r: &serde_json::Value| -> Vec<serde_json::Value> {
+            r.pointer("/alternatives/0/words")
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default()
+        };
+        // With diarization enabled, the final result repeats every word with a speaker tag
+        let diarized = results.last()
+            .map(word_list)
+            .filter(|w| !w.is_empty() && w.iter().all(|x| {
+                x.get("speakerTag").is_some() || x.get("speakerLabel").is_some()
+            }));
+        let all_words: Vec<serde_json::Value> = match diarized {
+            Some(w) => w,
+            None => results.iter().flat_map(word_list).collect(),
+        };
+        let mut words: Vec<AsrWord> = Vec::with_capacity(all_words.len());
+        for w in all_words.iter() {
+            let start = w.get("startTime").or_else(|| w.get("startOffset"));
+            let end = w.get("endTime").or_else(|| w.get("endOffset"));
+            let start = match start {
+                Some(v) => AsrParser::seconds(v)?,
+                // Google omits zero offsets entirely
+                None => SimpleTime::from_milliseconds(0),
+            };
+            let end = match end {
+                Some(v) => AsrParser::seconds(v)?,
+                None => start,
+            };
+            let speaker = match (w.get("speakerLabel"), w.get("speakerTag")) {
+                (Some(label), _) => label.as_str().map(|s| s.to_string()),
+                (None, Some(tag)) => tag.as_u64().map(|n| format!("Speaker {}", n)),
+                _ => None,
+            };
+            words.push(AsrWord {
+                text: AsrParser::field_str(w, "word")?.to_string(),
+                start,
+                end,
+                confidence: w.get("confidence").and_then(|v| v.as_f64()),
+                speaker,
+                punctuation: false,
+            });
+        }
+        Ok(words)
+    }
+    /// Fetch a required field from a JSON object
+    fn field<'a>(v: &'a serde_json::Value, name: &str) -> Result<&'a serde_json::Value, AsrParserError> {
+        v.get(name).ok_or_else(|| AsrParserError::MissingField(name.to_string()))
+    }
+    /// Fetch a required string field from a JSON object
+    fn field_str<'a>(v: &'a serde_json::Value, name: &str) -> Result<&'a str, AsrParserError> {
+        AsrParser::field(v, name)?
+            .as_str()
+            .ok_or_else(|| AsrParserError::MissingField(name.to_string()))
+    }
+    /// Convert a JSON time in seconds to a SimpleTime.
+    ///
+    /// Accepts plain numbers (`1.5`), strings (`"1.5"`, `"1.500s"`) and protobuf durations
+    /// (`{"seconds": "1", "nanos": 500000000}`).
+    fn seconds(v: &serde_json::Value) -> Result<SimpleTime, AsrParserError> {
+        let invalid = || AsrParserError::InvalidTimestamp(v.to_string());
+        let secs = match v {
+            serde_json::Value::Number(n) => n.as_f64().ok_or_else(invalid)?,
+            serde_json::Value::String(s) => {
+                s.trim_end_matches('s').parse::<f64>().map_err(|_| invalid())?
+            },
+            serde_json::Value::Object(o) => {
+                let whole = match o.get("seconds") {
+                    Some(serde_json::Value::String(s)) => s.parse::<f64>().map_err(|_| invalid())?,
+                    Some(serde_json::Value::Number(n)) => n.as_f64().ok_or_else(invalid)?,
+                    _ => 0.0,
+                };
+                let nanos = o.get("nanos").and_then(|n| n.as_f64()).unwrap_or(0.0);
+                whole + nanos / 1e9
+            },
+            _ => return Err(invalid()),
+        };
+        if secs < 0.0 || !secs.is_finite() {
+            return Err(invalid());
+        }
+        Ok(SimpleTime::from_milliseconds((secs * MILLIS_PER_SECOND as f64).round() as usize))
+    }
+    /// Group words into caption blocks obeying the segmentation limits
+    pub fn segment(words: &[AsrWord], opts: &SegmentationOptions) -> Vec<CaptionBlock> {
+        let mut blocks: Vec<CaptionBlock> = Vec::new();
+        let mut current: Vec<&AsrWord> = Vec::new();
+        let mut lines: Vec<String> = Vec::new();
+
+        let flush = |current: &mut Vec<&AsrWord>, lines: &mut Vec<String>, blocks: &mut Vec<CaptionBlock>| {
+            if let (Some(first), Some(last)) = (current.first(), current.last()) {
+                let end = if last.end > first.start { last.end } else { first.start };
+                // Keep the word timings, with punctuation folded into the word before it
+                let mut timed: Vec<TimedWord> = Vec::with_capacity(current.len());
+                for w in current.iter() {
+                    match timed.last_mut() {
+                        Some(t) if w.punctuation => t.text.push_str(&w.text),
+                        _ => timed.push(TimedWord::from(
+                            &w.text,
+                            w.start,
+                            if w.end < end { w.end } else { end },
+                        )),
+                    }
+                }
+                blocks.push(CaptionBlock {
+                    speaker: first.speaker.clone(),
+                    start: first.start,
+                    end,
+                    text: StyledText::from(lines.join("\n")),
+                    words: timed,
+                });
+            }
+            current.clear();
+            lines.clear();
+        };
+
+        for word in words.iter() {
+            if word.punctuation {
+                // Punctuation always stays with the word it follows
+                if let Some(line) = lines.last_mut() {
+                    line.push_str(&word.text);
+                    current.push(word);
+                }
+                continue;
+            }
+            if let (Some(first), Some(prev)) = (current.first(), current.last()) {
+                let speaker_changed = opts.break_on_speaker_change && word.speaker != first.speaker;
+                let paused = match opts.max_pause {
+                    Some(pause) => word.start.to_milliseconds()
+                        .saturating_sub(prev.end.to_milliseconds()) > pause,
+                    None => false,
+                };
+                let too_long = word.end.to_milliseconds()
+                    .saturating_sub(first.start.to_milliseconds()) > opts.max_duration;
+                if speaker_changed || paused || too_long {
+                    flush(&mut current, &mut lines, &mut blocks);
+                }
+            }
+            let fits = match lines.last() {
+                Some(line) => line.chars().count() + 1 + word.text.chars().count() <= opts.max_chars_per_line,
+                None => false,
+            };
+            if fits {
+                let line = lines.last_mut().unwrap();
+                line.push(' ');
+                line.push_str(&word.text);
+            }
+            else {
+                if lines.len() >= opts.max_lines.max(1) {
+                    flush(&mut current, &mut lines, &mut blocks);
+                }
+                lines.push(word.text.clone());
+            }
+            current.push(word);
+        }
+        flush(&mut current, &mut lines, &mut blocks);
+        blocks
+    }
+}
+
+/// Error type for AsrParser
+#[derive(Debug, Clone)]
+pub enum AsrParserError {
+    InvalidJson(String),
+    UnknownFormat,
+    MissingField(String),
+    InvalidTimestamp(String),
+}
+
+impl fmt::Display for AsrParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsrParserError::InvalidJson(s) => write!(f, "invalid JSON: {}", s),
+            AsrParserError::UnknownFormat => {
+                write!(f, "could not recognise speech recognition output format")
+            },
+            AsrParserError::MissingField(s) => write!(f, "missing field {}", s),
+            AsrParserError::InvalidTimestamp(s) => write!(f, "invalid time {}", s),
+        }
+    }
+}
+
+impl Error for AsrParserError {}
+
+/// Matroska element IDs
+const MKV_EBML: u32 = 0x1A45_DFA3;
+const MKV_SEGMENT: u32 = 0x1853_8067;
+const MKV_INFO: u32 = 0x1549_A966;
+const MKV_TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const MKV_DURATION: u32 = 0x4489;
+const MKV_CLUSTER: u32 = 0x1F43_B675;
+const MKV_TRACKS: u32 = 0x1654_AE6B;
+const MKV_TRACK_ENTRY: u32 = 0xAE;
+const MKV_TRACK_NUMBER: u32 = 0xD7;
+const MKV_TRACK_TYPE: u32 = 0x83;
+const MKV_CODEC_ID: u32 = 0x86;
+const MKV_LANGUAGE: u32 = 0x22_B59C;
+const MKV_LANGUAGE_BCP47: u32 = 0x22_B59D;
+const MKV_NAME: u32 = 0x536E;
+const MKV_TIMESTAMP: u32 = 0xE7;
+const MKV_SIMPLE_BLOCK: u32 = 0xA3;
+const MKV_BLOCK_GROUP: u32 = 0xA0;
+const MKV_BLOCK: u32 = 0xA1;
+const MKV_BLOCK_DURATION: u32 = 0x9B;
+/// Elements that can follow a cluster of unknown size in a segment
+const MKV_TOP_LEVEL: [u32; 8] = [
+    MKV_CLUSTER, MKV_INFO, MKV_TRACKS, 0x114D_9B74, 0x1C53_BB6B, 0x1254_C367, 0x1043_A770, 0x1941_A469,
+];
+/// Matroska track type for subtitles
+const MKV_SUBTITLE_TRACK: u64 = 0x11;
+
+/// How long a cue without a duration stays up when nothing follows it, in milliseconds
+const DEFAULT_CUE_LENGTH: usize = 2000;
+
+/// Type for reading video container headers, without decoding any video.
+///
+/// Supports MP4 (and MOV, which shares its layout) and Matroska (including WebM).
+pub struct MediaFile;
+
+impl MediaFile {
+    /// Read the duration of a video file in milliseconds
+    pub fn duration_from_file(fname: &str) -> Result<usize, Box<dyn Error>> {
+        let mut f = fs::File::open(fname)?;
+        Ok(MediaFile::duration(&mut f)?)
+    }
+    /// Read the duration of a video in milliseconds.
+    ///
+    /// MP4 durations come from the movie header (mvhd), or the longest track header (mdhd) if
+    /// the movie header has none; Matroska durations from Segment/Info/Duration.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::io::Cursor;
+    /// use ccap::MediaFile;
+    ///
+    /// // An MP4 holding just a version 0 movie header: 90000 units at 600 per second
+    /// let mut mvhd = vec!(0u8; 8);
+    /// mvhd.extend_from_slice(&600u32.to_be_bytes());
+    /// mvhd.extend_from_slice(&90_000u32.to_be_bytes());
+    /// let mut data = Vec::new();
+    /// data.extend_from_slice(&(8 + 12 + mvhd.len() as u32).to_be_bytes());
+    /// data.extend_from_slice(b"moov");
+    /// data.extend_from_slice(&(12 + mvhd.len() as u32).to_be_bytes());
+    /// data.extend_from_slice(b"mvhd");
+    /// data.extend_from_slice(&[0, 0, 0, 0]);
+    /// data.extend_from_slice(&mvhd);
+    /// assert_eq!(MediaFile::duration(&mut Cursor::new(data)).unwrap(), 150_000);
+    /// ```
+    pub fn duration<R: Read + Seek>(r: &mut R) -> Result<usize, MediaError> {
+        match MediaFile::container(r)? {
+            Container::Mp4 => MediaFile::mp4_duration(r),
+            Container::Matroska => MediaFile::mkv_duration(r),
+        }
+    }
+    /// Work out the container from the first bytes of a file
+    fn container<R: Read + Seek>(r: &mut R) -> Result<Container, MediaError> {
+        let mut magic = [0u8; 8];
+        r.seek(SeekFrom::Start(0))?;
+        r.read_exact(&mut magic)?;
+        r.seek(SeekFrom::Start(0))?;
+        if magic[..4] == MKV_EBML.to_be_bytes() {
+            return Ok(Container::Matroska);
+        }
+        match &magic[4..] {
+            b"ftyp" | b"moov" | b"mdat" | b"free" | b"skip" | b"wide" | b"pnot" => Ok(Container::Mp4),
+            _ => Err(MediaError::UnknownContainer),
+        }
+    }
+    /// Find a top level MP4 box and read its contents
+    fn mp4_top_level<R: Read + Seek>(r: &mut R, kind: &[u8; 4]) -> Result<Option<Vec<u8>>, MediaError> {
+        let end = r.seek(SeekFrom::End(0))?;
+        let mut pos = r.seek(SeekFrom::Start(0))?;
+        while pos + 8 <= end {
+            let mut header = [0u8; 8];
+            r.read_exact(&mut header)?;
+            let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+            let mut header_len = 8;
+            if size == 1 {
+                let mut large = [0u8; 8];
+                r.read_exact(&mut large)?;
+                size = u64::from_be_bytes(large);
+                header_len = 16;
+            }
+            else if size == 0 {
+                size = end - pos;
+            }
+            if size < header_len || pos.checked_add(size).map_or(true, |box_end| box_end > end) {
+                return Err(MediaError::Truncated);
+            }
+            if &header[4..] == kind {
+                let mut contents = vec!(0u8; (size - header_len) as usize);
+                r.read_exact(&mut contents)?;
+                return Ok(Some(contents));
+            }
+            pos = r.seek(SeekFrom::Start(pos + size))?;
+        }
+        Ok(None)
+    }
+    /// Split the contents of an MP4 box into its child boxes
+    fn mp4_boxes(data: &[u8]) -> Result<Vec<Mp4Box<'_>>, MediaError> {
+        let mut boxes = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let mut size = be_uint(&data[pos..pos + 4]) as usize;
+            let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+            let mut header_len = 8;
+            if size == 1 {
+                if pos + 16 > data.len() {
+                    return Err(MediaError::Truncated);
+                }
+                size = be_uint(&data[pos + 8..pos + 16]) as usize;
+                header_len = 16;
+            }
+            else if size == 0 {
+                size = data.len() - pos;
+            }
+            if size < header_len || pos.checked_add(size).map_or(true, |box_end| box_end > data.len()) {
+                return Err(MediaError::Truncated);
+            }
+            boxes.push((kind, &data[pos + header_len..pos + size]));
+            pos += size;
+        }
+        Ok(boxes)
+    }
+    /// Read the timescale and duration from an mvhd or mdhd box
+    fn mp4_header_duration(data: &[u8]) -> Result<(u64, u64), MediaError> {
+        // Version 1 headers have 64 bit times and durations
+        let (timescale, duration) = match data.first() {
+            Some(1) if data.len() >= 32 => (&data[20..24], &data[24..32]),
+            Some(0) if data.len() >= 20 => (&data[12..16], &data[16..20]),
+            _ => return Err(MediaError::Truncated),
+        };
+        Ok((be_uint(timescale), be_uint(duration)))
+    }
+    /// Round a duration in milliseconds, if it is one a caption can be offset by
+    fn duration_millis(millis: f64) -> Option<usize> {
+        // Offsets are isizes, so infinite and absurdly large durations can't be used
+        if millis.is_finite() && millis > 0.0 && millis < isize::MAX as f64 {
+            Some(millis.round() as usize)
+        }
+        else {
+            None
+        }
+    }
+    /// Read the duration of an MP4 file
+    fn mp4_duration<R: Read + Seek>(r: &mut R) -> Result<usize, MediaError> {
+        let moov = MediaFile::mp4_top_level(r, b"moov")?.ok_or(MediaError::MissingDuration)?;
+        let to_millis = |(timescale, duration): (u64, u64)| -> Option<usize> {
+            // Unknown durations are all ones
+            if timescale == 0 || duration == 0 || duration == u32::MAX as u64 || duration == u64::MAX {
+                None
+            }
+            else {
+                MediaFile::duration_millis(duration as f64 * 1000.0 / timescale as f64)
+            }
+        };
+        let mut longest_track = None;
+        for (kind, contents) in MediaFile::mp4_boxes(&moov)? {
+            match &kind {
+                b"mvhd" => {
+                    if let Some(d) = to_millis(MediaFile::mp4_header_duration(contents)?) {
+                        return Ok(d);
+                    }
+                },
+                b"trak" => {
+                    for (kind, contents) in MediaFile::mp4_boxes(contents)? {
+                        if &kind != b"mdia" {
+                            continue;
+                        }
+                        for (kind, contents) in MediaFile::mp4_boxes(contents)? {
+                            if &kind == b"mdhd" {
+                                let d = to_millis(MediaFile::mp4_header_duration(contents)?);
+                                longest_track = longest_track.max(d);
+                            }
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+        longest_track.ok_or(MediaError::MissingDuration)
+    }
+    /// Read an EBML variable length integer, returning its value, length and whether it was
+    /// all ones (an unknown size)
+    fn ebml_vint<R: Read>(r: &mut R, keep_marker: bool) -> Result<(u64, u64, bool), MediaError> {
+        let mut first = [0u8; 1];
+        r.read_exact(&mut first)?;
+        let len = first[0].leading_zeros() as usize + 1;
+        if len > 8 {
+            return Err(MediaError::Truncated);
+        }
+        let mut rest = [0u8; 7];
+        r.read_exact(&mut rest[..len - 1])?;
+        let marker = if keep_marker { 0 } else { 0x80u8 >> (len - 1) };
+        let mut value = (first[0] & !marker) as u64;
+        for b in rest[..len - 1].iter() {
+            value = (value << 8) | *b as u64;
+        }
+        let all_ones = value == (1u64 << (7 * len)) - 1;
+        Ok((value, len as u64, all_ones && !keep_marker))
+    }
+    /// Read the ID and size of an EBML element; the size is None if unknown
+    fn ebml_header<R: Read>(r: &mut R) -> Result<(u32, u64, Option<u64>), MediaError> {
+        let (id, id_len, _) = MediaFile::ebml_vint(r, true)?;
+        let (size, size_len, unknown) = MediaFile::ebml_vint(r, false)?;
+        Ok((id as u32, id_len + size_len, if unknown { None } else { Some(size) }))
+    }
+    /// Split the contents of an EBML element into its children
+    fn ebml_elements(data: &[u8]) -> Result<Vec<(u32, &[u8])>, MediaError> {
+        let mut elements = Vec::new();
+        let mut r = io::Cursor::new(data);
+        while (r.position() as usize) < data.len() {
+            let (id, _, size) = MediaFile::ebml_header(&mut r)?;
+            let start = r.position() as usize;
+            let end = match size {
+                Some(size) => usize::try_from(size).ok()
+                    .and_then(|size| start.checked_add(size))
+                    .filter(|end| *end <= data.len())
+                    .ok_or(MediaError::Truncated)?,
+                None => data.len(),
+            };
+            elements.push((id, &data[start..end]));
+            r.set_position(end as u64);
+        }
+        Ok(elements)
+    }
+    /// Find the position and size of the Matroska segment, checking the EBML header first
+    fn mkv_segment<R: Read + Seek>(r: &mut R) -> Result<(u64, u64), MediaError> {
+        let end = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(0))?;
+        let (id, _, size) = MediaFile::ebml_header(r)?;
+        let size = size.ok_or(MediaError::Truncated)?;
+        if id != MKV_EBML {
+            return Err(MediaError::UnknownContainer);
+        }
+        r.seek(SeekFrom::Current(size as i64))?;
+        loop {
+            let (id, _, size) = MediaFile::ebml_header(r)?;
+            let start = r.stream_position()?;
+            let size = size.unwrap_or(end - start).min(end - start);
+            if id == MKV_SEGMENT {
+                return Ok((start, size));
+            }
+            r.seek(SeekFrom::Start(start + size))?;
+        }
+    }
+    /// Read every text subtitle track from a video file
+    pub fn subtitles_from_file(fname: &str) -> Result<Vec<SubtitleTrack>, Box<dyn Error>> {
+        let mut f = fs::File::open(fname)?;
+        Ok(MediaFile::subtitles(&mut f)?)
+    }
+    /// Read every text subtitle track from a video, in the order they appear in the file.
+    ///
+    /// Reads `tx3g` and `wvtt` tracks from MP4 files, keeping bold, italic and underline from
+    /// tx3g style records, and `S_TEXT/UTF8`, `S_TEXT/WEBVTT` and `S_TEXT/ASS` (or SSA) tracks
+    /// from Matroska files.  Fragmented MP4 files are not supported, and other tracks, such as
+    /// image-based subtitles, are skipped.
+    pub fn subtitles<R: Read + Seek>(r: &mut R) -> Result<Vec<SubtitleTrack>, MediaError> {
+        match MediaFile::container(r)? {
+            Container::Mp4 => MediaFile::mp4_subtitles(r),
+            Container::Matroska => MediaFile::mkv_subtitles(r),
+        }
+    }
+    /// Find the first child box of a kind
+    fn mp4_child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>, MediaError> {
+        Ok(MediaFile::mp4_boxes(data)?.into_iter().find(|(k, _)| k == kind).map(|(_, c)| c))
+    }
+    /// Follow a path of child boxes
+    fn mp4_path<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Result<&'a [u8], MediaError> {
+        let mut current = data;
+        for kind in path.iter() {
+            current = MediaFile::mp4_child(current, kind)?.ok_or(MediaError::Truncated)?;
+        }
+        Ok(current)
+    }
+    /// Read a full box table of 32 bit entries of the given width after its entry count
+    fn mp4_table(data: &[u8], offset: usize, width: usize) -> Result<Vec<Vec<u64>>, MediaError> {
+        if data.len() < offset + 4 {
+            return Err(MediaError::Truncated);
+        }
+        let count = be_uint(&data[offset..offset + 4]) as usize;
+        let entries = &data[offset + 4..];
+        if entries.len() / (4 * width) < count {
+            return Err(MediaError::Truncated);
+        }
+        Ok(entries.chunks(4 * width).take(count)
+            .map(|e| e.chunks(4).map(be_uint).collect())
+            .collect())
+    }
+    /// Read the subtitle tracks of an MP4 file
+    fn mp4_subtitles<R: Read + Seek>(r: &mut R) -> Result<Vec<SubtitleTrack>, MediaError> {
+        let moov = MediaFile::mp4_top_level(r, b"moov")?.ok_or(MediaError::Truncated)?;
+        let mut tracks = Vec::new();
+        for (kind, trak) in MediaFile::mp4_boxes(&moov)? {
+            if &kind != b"trak" {
+                continue;
+            }
+            let mdia = MediaFile::mp4_path(trak, &[b"mdia"])?;
+            let stsd = MediaFile::mp4_path(mdia, &[b"minf", b"stbl", b"stsd"])?;
+            let codec = match MediaFile::mp4_boxes(stsd.get(8..).ok_or(MediaError::Truncated)?)?.first() {
+                Some((k, _)) if k == b"tx3g" || k == b"wvtt" => String::from_utf8_lossy(k).to_string(),
+                _ => continue,
+            };
+            let tkhd = MediaFile::mp4_path(trak, &[b"tkhd"])?;
+            let number = match tkhd.first() {
+                Some(1) if tkhd.len() >= 24 => be_uint(&tkhd[20..24]),
+                Some(_) if tkhd.len() >= 16 => be_uint(&tkhd[12..16]),
+                _ => return Err(MediaError::Truncated),
+            };
+            let mdhd = MediaFile::mp4_path(mdia, &[b"mdhd"])?;
+            let (timescale, _) = MediaFile::mp4_header_duration(mdhd)?;
+            if timescale == 0 {
+                return Err(MediaError::Truncated);
+            }
+            // ISO 639-2 code packed as three five bit letters
+            let packed = match mdhd.first() {
+                Some(1) if mdhd.len() >= 34 => be_uint(&mdhd[32..34]),
+                _ if mdhd.len() >= 22 => be_uint(&mdhd[20..22]),
+                _ => return Err(MediaError::Truncated),
+            };
+            let language: String = [10, 5, 0].iter()
+                .map(|shift| (((packed >> shift) & 0x1F) as u8 + 0x60) as char)
+                .collect();
+            let name = MediaFile::mp4_child(mdia, b"hdlr")?
+                .and_then(|h| h.get(24..))
+                .map(|n| String::from_utf8_lossy(n).trim_end_matches('\0').trim().to_string())
+                .filter(|n| !n.is_empty());
+            let samples = MediaFile::mp4_samples(r, mdia)?;
+            let mut blocks = Vec::new();
+            for (start, end, data) in samples {
+                let to_millis = |t: u64| SimpleTime::from_milliseconds((t as f64 * 1000.0 / timescale as f64).round() as usize);
+                let (start, end) = (to_millis(start), to_millis(end));
+                let texts = if codec == "tx3g" {
+                    MediaFile::tx3g_text(&data)?.into_iter().collect()
+                }
+                else {
+                    MediaFile::wvtt_text(&data)?
+                };
+                for text in texts {
+                    let mut cb = CaptionBlock::from(None, start, end, String::new()).unwrap();
+                    cb.set_text(text);
+                    blocks.push(cb);
+                }
+            }
+            tracks.push(SubtitleTrack {
+                number,
+                codec,
+                language: if language == "und" || !language.chars().all(|c| c.is_ascii_lowercase()) {
+                    None
+                } else {
+                    Some(language)
+                },
+                name,
+                caption: Caption::from(None, blocks),
+            });
+        }
+        Ok(tracks)
+    }
+    /// Read the samples of a track, with their start and end in the track's timescale
+    fn mp4_samples<R: Read + Seek>(r: &mut R, mdia: &[u8]) -> Result<Vec<(u64, u64, Vec<u8>)>, MediaError> {
+        let stbl = MediaFile::mp4_path(mdia, &[b"minf", b"stbl"])?;
+        let stts = MediaFile::mp4_table(MediaFile::mp4_path(stbl, &[b"stts"])?, 4, 2)?;
+        let stsc = MediaFile::mp4_table(MediaFile::mp4_path(stbl, &[b"stsc"])?, 4, 3)?;
+        let stsz = MediaFile::mp4_path(stbl, &[b"stsz"])?;
+        if stsz.len() < 12 {
+            return Err(MediaError::Truncated);
+        }
+        let file_end = r.seek(SeekFrom::End(0))?;
+        let sizes: Vec<u64> = match be_uint(&stsz[4..8]) {
+            0 => MediaFile::mp4_table(stsz, 8, 1)?.into_iter().map(|e| e[0]).collect(),
+            size => {
+                // Samples of a fixed size have no table, so check they could fit in the file
+                let count = be_uint(&stsz[8..12]);
+                if count.checked_mul(size).map_or(true, |total| total > file_end) {
+                    return Err(MediaError::Truncated);
+                }
+                vec!(size; count as usize)
+            },
+        };
+        let offsets: Vec<u64> = match MediaFile::mp4_child(stbl, b"co64")? {
+            Some(co64) => MediaFile::mp4_table(co64, 4, 2)?.into_iter().map(|e| (e[0] << 32) | e[1]).collect(),
+            None => MediaFile::mp4_table(MediaFile::mp4_path(stbl, &[b"stco"])?, 4, 1)?
+                .into_iter().map(|e| e[0]).collect(),
+        };
+        // Where each sample is, going through the chunks in order
+        let mut positions: Vec<u64> = Vec::with_capacity(sizes.len());
+        for (n, chunk_offset) in offsets.iter().enumerate() {
+            let chunk = n as u64 + 1;
+            let per_chunk = stsc.iter().rev().find(|e| e[0] <= chunk).map_or(0, |e| e[1]);
+            let mut offset = *chunk_offset;
+            for _ in 0..per_chunk {
+                match sizes.get(positions.len()) {
+                    Some(size) => {
+                        positions.push(offset);
+                        offset = offset.saturating_add(*size);
+                    },
+                    None => break,
+                }
+            }
+        }
+        let mut samples = Vec::with_capacity(positions.len());
+        let mut deltas = stts.iter().flat_map(|e| std::iter::repeat(e[1]).take(e[0] as usize));
+        let mut time = 0;
+        for (position, size) in positions.iter().zip(sizes.iter()) {
+            let delta = deltas.next().ok_or(MediaError::Truncated)?;
+            if position.checked_add(*size).map_or(true, |sample_end| sample_end > file_end) {
+                return Err(MediaError::Truncated);
+            }
+            let mut data = vec!(0u8; *size as usize);
+            r.seek(SeekFrom::Start(*position))?;
+            r.read_exact(&mut data)?;
+            samples.push((time, time + delta, data));
+            time += delta;
+        }
+        Ok(samples)
+    }
+    /// Read the text of a tx3g sample; empty samples clear the screen and give None
+    fn tx3g_text(data: &[u8]) -> Result<Option<StyledText>, MediaError> {
+        if data.len() < 2 {
+            return Err(MediaError::Truncated);
+        }
+        let len = be_uint(&data[..2]) as usize;
+        let raw = data.get(2..2 + len).ok_or(MediaError::Truncated)?;
+        let text = if raw.starts_with(&[0xFE, 0xFF]) {
+            let units: Vec<u16> = raw[2..].chunks(2).filter(|c| c.len() == 2).map(|c| be_uint(c) as u16).collect();
+            String::from_utf16_lossy(&units)
+        }
+        else {
+            String::from_utf8_lossy(raw).to_string()
+        };
+        let text = text.replace("\r\n", "\n");
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        // Style records give character ranges with bold (1), italic (2) and underline (4) flags
+        let mut styles: Vec<(usize, usize, u8)> = Vec::new();
+        if let Some(styl) = MediaFile::mp4_child(&data[2 + len..], b"styl")? {
+            let count = styl.get(..2).map_or(0, be_uint) as usize;
+            for record in styl.get(2..).unwrap_or(&[]).chunks(12).take(count) {
+                if record.len() == 12 {
+                    styles.push((be_uint(&record[0..2]) as usize, be_uint(&record[2..4]) as usize, record[6]));
+                }
+            }
+        }
+        let chars: Vec<char> = text.chars().collect();
+        let flags_at = |n: usize| styles.iter()
+            .find(|(start, end, _)| *start <= n && n < *end)
+            .map_or(0, |s| s.2 & 7);
+        let mut spans = Vec::new();
+        let mut n = 0;
+        while n < chars.len() {
+            let flags = flags_at(n);
+            let mut end = n + 1;
+            while end < chars.len() && flags_at(end) == flags {
+                end += 1;
+            }
+            let mut span = TextSpan::Text(chars[n..end].iter().collect());
+            for (bit, style) in [(4, TextStyle::Underline), (2, TextStyle::Italic), (1, TextStyle::Bold)] {
+                if flags & bit != 0 {
+                    span = TextSpan::Styled(style, vec!(span));
+                }
+            }
+            spans.push(span);
+            n = end;
+        }
+        Ok(Some(StyledText::from_spans(spans)))
+    }
+    /// Read the cues of a wvtt sample; a sample may hold several cues, or none
+    fn wvtt_text(data: &[u8]) -> Result<Vec<StyledText>, MediaError> {
+        let mut texts = Vec::new();
+        for (kind, contents) in MediaFile::mp4_boxes(data)? {
+            if &kind == b"vttc" {
+                if let Some(payload) = MediaFile::mp4_child(contents, b"payl")? {
+                    texts.push(StyledText::from_vtt(String::from_utf8_lossy(payload).trim_end()));
+                }
+            }
+        }
+        Ok(texts)
+    }
+    /// Read the subtitle tracks of a Matroska file
+    fn mkv_subtitles<R: Read + Seek>(r: &mut R) -> Result<Vec<SubtitleTrack>, MediaError> {
+        let (start, size) = MediaFile::mkv_segment(r)?;
+        let mut scale: u64 = 1_000_000;
+        let mut tracks: Vec<SubtitleTrack> = Vec::new();
+        // Start, optional duration and contents of each block, by track
+        let mut cues: Vec<Vec<MkvCue>> = Vec::new();
+        let mut pos = start;
+        while pos < start + size {
+            r.seek(SeekFrom::Start(pos))?;
+            let (id, header_len, element_size) = MediaFile::ebml_header(r)?;
+            let contents = match element_size {
+                Some(element_size) => {
+                    // Only read what we need; clusters may be large but are read one at a time
+                    let mut contents = Vec::new();
+                    if [MKV_INFO, MKV_TRACKS, MKV_CLUSTER].contains(&id) {
+                        contents.resize(element_size.min((start + size).saturating_sub(pos + header_len)) as usize, 0);
+                        r.read_exact(&mut contents)?;
+                    }
+                    pos += header_len + element_size;
+                    contents
+                },
+                None if id == MKV_CLUSTER => {
+                    let contents = MediaFile::mkv_unknown_cluster(r, start + size)?;
+                    pos = r.stream_position()?;
+                    contents
+                },
+                None => break,
+            };
+            match id {
+                MKV_INFO => {
+                    for (id, value) in MediaFile::ebml_elements(&contents)? {
+                        if id == MKV_TIMECODE_SCALE {
+                            scale = be_uint(value);
+                        }
+                    }
+                },
+                MKV_TRACKS => {
+                    for (id, entry) in MediaFile::ebml_elements(&contents)? {
+                        if id != MKV_TRACK_ENTRY {
+                            continue;
+                        }
+                        if let Some(track) = MediaFile::mkv_track(entry)? {
+                            tracks.push(track);
+                            cues.push(Vec::new());
+                        }
+                    }
+                },
+                MKV_CLUSTER => MediaFile::mkv_cluster(&contents, &tracks, &mut cues)?,
+                _ => {},
+            }
+        }
+        let to_millis = |t: u64| (t as f64 * scale as f64 / 1_000_000.0).round() as usize;
+        for (track, mut track_cues) in tracks.iter_mut().zip(cues) {
+            track_cues.sort_by_key(|c| c.0);
+            let mut blocks = Vec::with_capacity(track_cues.len());
+            for (n, (start, duration, data)) in track_cues.iter().enumerate() {
+                let start_ms = to_millis(*start);
+                let end_ms = match duration {
+                    // Corrupt durations and timestamps can be far too large, so saturate
+                    Some(d) => to_millis(start.saturating_add(*d)),
+                    None => track_cues.get(n + 1)
+                        .map_or(start_ms.saturating_add(DEFAULT_CUE_LENGTH), |c| to_millis(c.0)),
+                };
+                let payload = String::from_utf8_lossy(data);
+                let (speaker, text) = match track.codec.as_str() {
+                    "S_TEXT/WEBVTT" => (None, StyledText::from_vtt(payload.trim_end())),
+                    "S_TEXT/ASS" | "S_TEXT/SSA" => {
+                        // ReadOrder, Layer, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+                        let fields: Vec<&str> = payload.splitn(9, ',').collect();
+                        if fields.len() < 9 {
+                            continue;
+                        }
+                        let name = fields[3].trim();
+                        (
+                            if name.is_empty() { None } else { Some(name.to_string()) },
+                            StyledText::from_ass(fields[8].trim_end()),
+                        )
+                    },
+                    _ => (None, StyledText::from_srt(&payload.trim_end().replace("\r\n", "\n"))),
+                };
+                let mut cb = CaptionBlock::from(
+                    speaker,
+                    SimpleTime::from_milliseconds(start_ms),
+                    SimpleTime::from_milliseconds(end_ms.max(start_ms)),
+                    String::new()
+                ).unwrap();
+                cb.set_text(text);
+                blocks.push(cb);
+            }
+            track.caption = Caption::from(None, blocks);
+        }
+        Ok(tracks)
+    }
+    /// Read a cluster of unknown size, which ends at the next top level element
+    fn mkv_unknown_cluster<R: Read + Seek>(r: &mut R, end: u64) -> Result<Vec<u8>, MediaError> {
+        let mut contents = Vec::new();
+        loop {
+            let pos = r.stream_position()?;
+            if pos >= end {
+                break;
+            }
+            let (id, header_len, size) = MediaFile::ebml_header(r)?;
+            if MKV_TOP_LEVEL.contains(&id) {
+                r.seek(SeekFrom::Start(pos))?;
+                break;
+            }
+            let size = size.ok_or(MediaError::Truncated)?;
+            if size > end.saturating_sub(pos + header_len) {
+                return Err(MediaError::Truncated);
+            }
+            let mut element = vec!(0u8; (header_len + size) as usize);
+            r.seek(SeekFrom::Start(pos))?;
+            r.read_exact(&mut element)?;
+            contents.extend(element);
+        }
+        Ok(contents)
+    }
+    /// Read a Matroska track entry, if it is a text subtitle track
+    fn mkv_track(entry: &[u8]) -> Result<Option<SubtitleTrack>, MediaError> {
+        let mut number = None;
+        let mut kind = 0;
+        let mut codec = String::new();
+        // Matroska's default language is English
+        let mut language = Some(String::from("eng"));
+        let mut language_bcp47 = None;
+        let mut name = None;
+        for (id, value) in MediaFile::ebml_elements(entry)? {
+            let text = || String::from_utf8_lossy(value).trim_end_matches('\0').to_string();
+            match id {
+                MKV_TRACK_NUMBER => number = Some(be_uint(value)),
+                MKV_TRACK_TYPE => kind = be_uint(value),
+                MKV_CODEC_ID => codec = text(),
+                MKV_LANGUAGE => language = Some(text()).filter(|l| l != "und"),
+                MKV_LANGUAGE_BCP47 => language_bcp47 = Some(text()).filter(|l| l != "und"),
+                MKV_NAME => name = Some(text()),
+                _ => {},
+            }
+        }
+        let supported = ["S_TEXT/UTF8", "S_TEXT/WEBVTT", "S_TEXT/ASS", "S_TEXT/SSA"];
+        match number {
+            Some(number) if kind == MKV_SUBTITLE_TRACK && supported.contains(&codec.as_str()) => {
+                Ok(Some(SubtitleTrack {
+                    number,
+                    codec,
+                    language: language_bcp47.or(language),
+                    name,
+                    caption: Caption::from(None, Vec::new()),
+                }))
+            },
+            _ => Ok(None),
+        }
+    }
+    /// Collect the blocks of a cluster that belong to subtitle tracks
+    fn mkv_cluster(cluster: &[u8], tracks: &[SubtitleTrack], cues: &mut [Vec<MkvCue>])
+    -> Result<(), MediaError> {
+        let mut timestamp: u64 = 0;
+        for (id, contents) in MediaFile::ebml_elements(cluster)? {
+            let (block, duration) = match id {
+                MKV_TIMESTAMP => {
+                    timestamp = be_uint(contents);
+                    continue;
+                },
+                MKV_SIMPLE_BLOCK => (contents, None),
+                MKV_BLOCK_GROUP => {
+                    let mut block = None;
+                    let mut duration = None;
+                    for (id, value) in MediaFile::ebml_elements(contents)? {
+                        match id {
+                            MKV_BLOCK => block = Some(value),
+                            MKV_BLOCK_DURATION => duration = Some(be_uint(value)),
+                            _ => {},
+                        }
+                    }
+                    match block {
+                        Some(block) => (block, duration),
+                        None => continue,
+                    }
+                },
+                _ => continue,
+            };
+            // Track number, then a signed 16 bit time relative to the cluster and flags
+            let mut reader = io::Cursor::new(block);
+            let (number, _, _) = MediaFile::ebml_vint(&mut reader, false)?;
+            let header = reader.position() as usize;
+            if block.len() < header + 3 {
+                return Err(MediaError::Truncated);
+            }
+            let track = match tracks.iter().position(|t| t.number == number) {
+                Some(n) => n,
+                None => continue,
+            };
+            // Laced blocks hold several frames, which text tracks don't use
+            if block[header + 2] & 0x06 != 0 {
+                continue;
+            }
+            let relative = i16::from_be_bytes([block[header], block[header + 1]]);
+            let start = if relative < 0 {
+                timestamp.saturating_sub(relative.unsigned_abs() as u64)
+            }
+            else {
+                timestamp.saturating_add(relative as u64)
+            };
+            cues[track].push((start, duration, block[header + 3..].to_vec()));
+        }
+        Ok(())
+    }
+    /// Read the duration of a Matroska file
+    fn mkv_duration<R: Read + Seek>(r: &mut R) -> Result<usize, MediaError> {
+        let (start, size) = MediaFile::mkv_segment(r)?;
+        let mut pos = start;
+        while pos < start + size {
+            r.seek(SeekFrom::Start(pos))?;
+            let (id, header_len, element_size) = MediaFile::ebml_header(r)?;
+            let element_size = match element_size {
+                Some(s) => s,
+                // Only clusters of live streams have unknown sizes, and Info comes before them
+                None => break,
+            };
+            if id == MKV_INFO {
+                let mut info = vec!(0u8; element_size.min((start + size).saturating_sub(pos + header_len)) as usize);
+                r.read_exact(&mut info)?;
+                let mut scale: u64 = 1_000_000;
+                let mut duration = None;
+                for (id, contents) in MediaFile::ebml_elements(&info)? {
+                    match id {
+                        MKV_TIMECODE_SCALE => scale = be_uint(contents),
+                        MKV_DURATION => duration = be_float(contents),
+                        _ => {},
+                    }
+                }
+                // Durations are in units of the timecode scale, which is in nanoseconds
+                return duration
+                    .and_then(|d| MediaFile::duration_millis(d * scale as f64 / 1_000_000.0))
+                    .ok_or(MediaError::MissingDuration);
+            }
+            if id == MKV_CLUSTER {
+                break;
+            }
+            pos += header_len + element_size;
+        }
+        Err(MediaError::MissingDuration)
+    }
+}
+
+/// The type and contents of an MP4 box
+type Mp4Box<'a> = ([u8; 4], &'a [u8]);
+
+/// The start, duration if known and contents of a Matroska block, in timecode scale units
+type MkvCue = (u64, Option<u64>, Vec<u8>);
+
+/// A text subtitle track read from a video file by MediaFile
+#[derive(Debug, Clone)]
+pub struct SubtitleTrack {
+    /// Track number (MP4 track ID or Matroska track number)
+    pub number: u64,
+    /// Codec of the track, such as tx3g, wvtt or S_TEXT/UTF8
+    pub codec: String,
+    /// Language of the track, as an ISO 639-2 code or BCP 47 tag
+    pub language: Option<String>,
+    /// Name of the track
+    pub name: Option<String>,
+    /// The subtitles themselves
+    pub caption: Caption,
+}
+
+/// Container formats MediaFile can read
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Container {
+    Mp4,
+    Matroska,
+}
+
+/// Read a big-endian unsigned integer of up to 8 bytes
+fn be_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, b| (n << 8) | *b as u64)
+}
+
+/// Read a big-endian 4 or 8 byte float
+fn be_float(bytes: &[u8]) -> Option<f64> {
+    match bytes.len() {
+        4 => Some(f32::from_bits(be_uint(bytes) as u32) as f64),
+        8 => Some(f64::from_bits(be_uint(bytes))),
+        _ => None,
+    }
+}
+
+/// Error type for MediaFile
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaError {
+    FileNotReadable(String),
+    UnknownContainer,
+    Truncated,
+    MissingDuration,
+}
+
+impl From<io::Error> for MediaError {
+    fn from(e: io::Error) -> MediaError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => MediaError::Truncated,
+            _ => MediaError::FileNotReadable(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MediaError::FileNotReadable(s) => write!(f, "could not read file: {}", s),
+            MediaError::UnknownContainer => write!(f, "not an MP4 or Matroska file"),
+            MediaError::Truncated => write!(f, "file is truncated or corrupt"),
+            MediaError::MissingDuration => write!(f, "file does not give its duration"),
+        }
+    }
+}
+
+impl Error for MediaError {}
+
+/// Styles that can be applied to a span of caption text
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextStyle {
+    Italic,
+    Bold,
+    Underline,
+    /// Text colour as written in the source, e.g. `#ffff00` or `yellow`
+    Color(String),
+    /// VTT class span (`<c.yellow.loud>`)
+    Class(Vec<String>),
+    /// VTT language span (`<lang en-GB>`)
+    Lang(String),
+    /// VTT voice span (`<v Mary>`)
+    Voice(String),
+    /// Base text of a VTT ruby annotation (`<ruby>`)
+    Ruby,
+    /// Annotation text of a VTT ruby annotation (`<rt>`)
+    RubyText,
+}
+
+/// A node in the tree of styled caption text
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextSpan {
+    Text(String),
+    Styled(TextStyle, Vec<TextSpan>),
+}
+
+/// Caption text represented as a tree of styled spans.
+///
+/// Styled text is parsed from the markup used by each format (SRT HTML-like tags, VTT cue tags and
+/// ASS override codes) and rendered back out by each writer.  Markup a format cannot express is
+/// dropped while keeping the text it wraps, and plain-text uses strip all styling.
+///
+/// # Examples
+/// Convert SRT italics into VTT
+/// ```
+/// use ccap::StyledText;
+///
+/// let text = StyledText::from_srt("<i>Hello</i>, <font color=\"#ffff00\">world</font>");
+/// assert_eq!(text.to_plain(), "Hello, world");
+/// assert_eq!(text.to_vtt(), "<i>Hello</i>, <c.yellow>world</c>");
+/// ```
+///
+/// Read ASS override codes
+/// ```
+/// use ccap::StyledText;
+///
+/// let text = StyledText::from_ass(r"{\i1}Hello{\i0}\Nworld");
+/// assert_eq!(text.to_srt(), "<i>Hello</i>\nworld");
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StyledText {
+    spans: Vec<TextSpan>,
+}
+
+/// Markup flavours StyledText can be written as
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextDialect {
+    Vtt,
+    Srt,
+}
+
+/// A tag found while scanning HTML-like caption markup
+#[derive(Debug, Clone, PartialEq)]
+enum MarkupToken {
+    Text(String),
+    /// Tag name, classes and annotation (everything after the first space)
+    Open(String, Vec<String>, String),
+    Close(String),
+}
+
+/// Named colours with a default VTT class, along with their RGB values
+const VTT_COLORS: [(&str, &str); 8] = [
+    ("white", "#ffffff"),
+    ("lime", "#00ff00"),
+    ("cyan", "#00ffff"),
+    ("red", "#ff0000"),
+    ("yellow", "#ffff00"),
+    ("magenta", "#ff00ff"),
+    ("blue", "#0000ff"),
+    ("black", "#000000"),
+];
+
+lazy_static! {
+    static ref FONT_COLOR: Regex = Regex::new(r#"(?i)color\s*=\s*["']?([^"'\s>]+)"#).unwrap();
+}
+
+impl StyledText {
+    /// Construct styled text from a tree of spans
+    pub fn from_spans(spans: Vec<TextSpan>) -> StyledText {
+        let mut text = StyledText { spans };
+        text.spans = StyledText::normalize(text.spans);
+        text
+    }
+    /// Get the top level spans of this text
+    pub fn spans(&self) -> &[TextSpan] {
+        &self.spans
+    }
+    /// Whether the text carries no styling at all
+    pub fn is_plain(&self) -> bool {
+        self.spans.iter().all(|s| matches!(s, TextSpan::Text(_)))
+    }
+    /// Get the text with all styling removed; ruby annotations are left out
+    pub fn to_plain(&self) -> String {
+        let mut out = String::new();
+        StyledText::plain_spans(&self.spans, &mut out);
+        out
+    }
+    fn plain_spans(spans: &[TextSpan], out: &mut String) {
+        for span in spans.iter() {
+            match span {
+                TextSpan::Text(t) => out.push_str(t),
+                TextSpan::Styled(TextStyle::RubyText, _) => {},
+                TextSpan::Styled(_, children) => StyledText::plain_spans(children, out),
+            }
+        }
+    }
+    /// Parse SRT text, which uses `<i>`, `<b>`, `<u>` and `<font color>` tags and may contain
+    /// ASS-style override codes such as `{\i1}`
+    pub fn from_srt(s: &str) -> StyledText {
+        StyledText::build(StyledText::tokenize(s, false), StyledText::srt_style, StyledText::srt_text)
+    }
+    /// Parse VTT cue text, which uses `<b>`, `<i>`, `<u>`, `<c>`, `<lang>`, `<v>` and `<ruby>` tags
+    /// along with HTML character references
+    pub fn from_vtt(s: &str) -> StyledText {
+        StyledText::build(StyledText::tokenize(s, true), StyledText::vtt_style, StyledText::vtt_text)
+    }
+    /// Parse the text of an ASS dialogue event, which uses override codes such as `{\i1}`,
+    /// `{\b1}`, `{\u1}` and `{\c&H00FFFF&}`
+    pub fn from_ass(s: &str) -> StyledText {
+        let mut italic = false;
+        let mut bold = false;
+        let mut underline = false;
+        let mut color: Option<String> = None;
+        // Runs of text that share the same styling
+        let mut runs: Vec<(bool, bool, bool, Option<String>, String)> = Vec::new();
+        let mut current = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut block = String::new();
+                    for b in chars.by_ref() {
+                        if b == '}' {
+                            break;
+                        }
+                        block.push(b);
+                    }
+                    if !current.is_empty() {
+                        runs.push((italic, bold, underline, color.clone(), current.clone()));
+                        current.clear();
+                    }
+                    // Blocks without a backslash are comments
+                    for code in block.split('\\').skip(1) {
+                        let code = code.trim();
+                        if let Some(v) = code.strip_prefix('i') {
+                            if let Ok(n) = v.parse::<u32>() {
+                                italic = n != 0;
+                            }
+                        }
+                        else if let Some(v) = code.strip_prefix('b') {
+                            if let Ok(n) = v.parse::<u32>() {
+                                // Either a toggle or a font weight
+                                bold = n == 1 || n >= 600;
+                            }
+                        }
+                        else if let Some(v) = code.strip_prefix('u') {
+                            if let Ok(n) = v.parse::<u32>() {
+                                underline = n != 0;
+                            }
+                        }
+                        else if let Some(v) = code.strip_prefix("1c").or_else(|| code.strip_prefix('c')) {
+                            color = StyledText::ass_color(v);
+                        }
+                        else if code.starts_with('r') {
+                            italic = false;
+                            bold = false;
+                            underline = false;
+                            color = None;
+                        }
+                    }
+                },
+                '\\' => {
+                    match chars.peek() {
+                        Some('N') => { current.push('\n'); chars.next(); },
+                        Some('n') => { current.push(' '); chars.next(); },
+                        Some('h') => { current.push('\u{a0}'); chars.next(); },
+                        _ => current.push(c),
+                    }
+                },
+                _ => current.push(c),
+            }
+        }
+        if !current.is_empty() {
+            runs.push((italic, bold, underline, color, current));
+        }
+        let mut spans: Vec<TextSpan> = Vec::with_capacity(runs.len());
+        for (italic, bold, underline, color, text) in runs.into_iter() {
+            let mut span = TextSpan::Text(text);
+            if let Some(c) = color {
+                span = TextSpan::Styled(TextStyle::Color(c), vec!(span));
+            }
+            if underline {
+                span = TextSpan::Styled(TextStyle::Underline, vec!(span));
+            }
+            if bold {
+                span = TextSpan::Styled(TextStyle::Bold, vec!(span));
+            }
+            if italic {
+                span = TextSpan::Styled(TextStyle::Italic, vec!(span));
+            }
+            spans.push(span);
+        }
+        StyledText::from_spans(spans)
+    }
+    /// Convert an ASS `&HBBGGRR&` colour into `#rrggbb`; empty resets the colour
+    fn ass_color(v: &str) -> Option<String> {
+        let hex = v.trim_start_matches('&')
+            .trim_start_matches(['H', 'h'])
+            .trim_end_matches('&');
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        // Alpha may lead the colour, so take the last six digits
+        let hex = format!("{:0>6}", hex);
+        let bgr = &hex[hex.len() - 6..];
+        Some(format!("#{}{}{}", &bgr[4..6], &bgr[2..4], &bgr[0..2]).to_lowercase())
+    }
+    /// Render as VTT cue text
+    pub fn to_vtt(&self) -> String {
+        self.render(TextDialect::Vtt, &[])
+    }
+    /// Render as SRT text
+    pub fn to_srt(&self) -> String {
+        self.render(TextDialect::Srt, &[])
+    }
+    /// Re-break the text into lines no wider than the maximum line length, returning whether
+    /// it fits within both the line length and line count.
+    ///
+    /// Widths are measured in display columns, so wide characters count twice.  Text that fits
+    /// on one line is put on one line; otherwise it goes over as few lines as will fit, choosing
+    /// the break points that best match the preferred shape while breaking after punctuation or
+    /// before conjunctions and never leaving an article or preposition at the end of a line.
+    /// Styling is kept, with only the whitespace between words changed.  Text that needs more
+    /// than the maximum number of lines is filled line by line.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{StyledText, ReflowOptions};
+    ///
+    /// let mut text = StyledText::from_srt("We went to <i>the market</i>, and then we came home again");
+    /// let fits = text.reflow(&ReflowOptions { max_line_length: 32, ..ReflowOptions::default() });
+    /// assert!(fits);
+    /// assert_eq!(text.to_srt(), "We went to <i>the market</i>,\nand then we came home again");
+    /// ```
+    pub fn reflow(&mut self, opts: &ReflowOptions) -> bool {
+        let plain = self.to_plain();
+        let words: Vec<&str> = plain.split_whitespace().collect();
+        if words.is_empty() {
+            return true;
+        }
+        let widths: Vec<usize> = words.iter().map(|w| UnicodeWidthStr::width(*w)).collect();
+        let max = opts.max_line_length.max(1);
+        // Fill greedily first, which gives the fewest lines that fit
+        let mut greedy = vec!(false; words.len() - 1);
+        let mut width = widths[0];
+        for i in 1..words.len() {
+            if width + 1 + widths[i] > max {
+                greedy[i - 1] = true;
+                width = widths[i];
+            }
+            else {
+                width += 1 + widths[i];
+            }
+        }
+        let lines = greedy.iter().filter(|b| **b).count() + 1;
+        let breaks = if lines == 1 || lines > opts.max_lines || words.len() > REFLOW_MAX_WORDS {
+            greedy
+        }
+        else {
+            StyledText::best_breaks(&words, &widths, lines, max, opts.shape)
+        };
+        let mut gap = 0;
+        let mut started = false;
+        let mut spacing = false;
+        let spans = StyledText::rebreak_spans(&self.spans, &breaks, &mut gap, &mut started, &mut spacing);
+        self.spans = StyledText::normalize(spans);
+        let text = self.to_plain();
+        let lines: Vec<&str> = text.lines().collect();
+        lines.len() <= opts.max_lines && lines.iter().all(|l| UnicodeWidthStr::width(*l) <= max)
+    }
+    /// Choose where to break between words to give a number of lines at the lowest cost
+    fn best_breaks(words: &[&str], widths: &[usize], lines: usize, max: usize, shape: LineShape) -> Vec<bool> {
+        let n = words.len();
+        let bare = |w: &str| -> String {
+            w.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase()
+        };
+        // Cost of breaking after word i
+        let break_cost: Vec<usize> = (0..n - 1).map(|i| {
+            let word = words[i].trim_end_matches(|c: char| "\"')]»”’".contains(c));
+            if word.ends_with(|c: char| ".?!…".contains(c)) {
+                0
+            }
+            else if word.ends_with(|c: char| ",;:–—".contains(c)) {
+                10
+            }
+            else if REFLOW_CLINGING_WORDS.contains(&bare(words[i]).as_str()) {
+                200
+            }
+            else if REFLOW_LEADING_WORDS.contains(&bare(words[i + 1]).as_str()) {
+                20
+            }
+            else {
+                60
+            }
+        }).collect();
+        let line_width = |from: usize, to: usize| -> usize {
+            widths[from..to].iter().sum::<usize>() + (to - from - 1)
+        };
+        let line_cost = |from: usize, to: usize| -> usize {
+            line_width(from, to).saturating_sub(max) * REFLOW_OVERFLOW_COST
+        };
+        let shape_cost = |upper: usize, lower: usize| -> usize {
+            let d = upper.max(lower) - upper.min(lower);
+            let penalised = match shape {
+                LineShape::Balanced => false,
+                LineShape::BottomHeavy => upper > lower,
+                LineShape::TopHeavy => lower > upper,
+            };
+            if penalised { 4 * d * d } else { d * d }
+        };
+        // Cheapest way to fill the lines so far ending with words from..to, with where the line
+        // before started
+        type Table = HashMap<(usize, usize), (usize, Option<usize>)>;
+        let mut best: Table = HashMap::new();
+        let mut history: Vec<Table> = Vec::with_capacity(lines);
+        for to in 1..=n - (lines - 1) {
+            best.insert((0, to), (line_cost(0, to), None));
+        }
+        for line in 1..lines {
+            let mut next = HashMap::new();
+            for (&(from, to), &(cost, _)) in best.iter() {
+                let last = n - (lines - 1 - line);
+                for end in to + 1..=last {
+                    let total = cost + break_cost[to - 1] + line_cost(to, end)
+                        + shape_cost(line_width(from, to), line_width(to, end));
+                    let entry = next.entry((to, end)).or_insert((usize::MAX, None));
+                    if total < entry.0 {
+                        *entry = (total, Some(from));
+                    }
+                }
+            }
+            history.push(best);
+            best = next;
+        }
+        // Walk back from the cheapest arrangement that uses every word
+        let mut breaks = vec!(false; n - 1);
+        let mut key = *best.iter()
+            .filter(|((_, to), _)| *to == n)
+            .min_by_key(|((from, _), (cost, _))| (*cost, *from))
+            .unwrap().0;
+        let mut previous = best[&key].1;
+        for table in history.iter().rev() {
+            breaks[key.0 - 1] = true;
+            key = (previous.unwrap(), key.0);
+            previous = table[&key].1;
+        }
+        breaks
+    }
+    /// Copy spans replacing each run of whitespace between words with a space or line break
+    fn rebreak_spans(spans: &[TextSpan], breaks: &[bool], gap: &mut usize, started: &mut bool,
+                     spacing: &mut bool) -> Vec<TextSpan> {
+        spans.iter().map(|span| match span {
+            TextSpan::Text(t) => {
+                let mut out = String::with_capacity(t.len());
+                for c in t.chars() {
+                    if !c.is_whitespace() {
+                        out.push(c);
+                        *started = true;
+                        *spacing = false;
+                    }
+                    else if *started && !*spacing && *gap < breaks.len() {
+                        // The first space after a word stands for the whole run
+                        out.push(if breaks[*gap] { '\n' } else { ' ' });
+                        *gap += 1;
+                        *spacing = true;
+                    }
+                }
+                TextSpan::Text(out)
+            },
+            TextSpan::Styled(TextStyle::RubyText, _) => span.clone(),
+            TextSpan::Styled(style, children) => TextSpan::Styled(
+                style.clone(),
+                StyledText::rebreak_spans(children, breaks, gap, started, spacing)
+            ),
+        }).collect()
+    }
+    /// Split the text in two at a byte offset into the plain text
+    fn split_at(&self, at: usize) -> (StyledText, StyledText) {
+        let mut at = at;
+        let (left, right) = StyledText::split_spans(&self.spans, &mut at);
+        (StyledText::from_spans(left), StyledText::from_spans(right))
+    }
+    fn split_spans(spans: &[TextSpan], at: &mut usize) -> (Vec<TextSpan>, Vec<TextSpan>) {
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for span in spans.iter() {
+            match span {
+                TextSpan::Text(t) if *at >= t.len() => {
+                    *at -= t.len();
+                    left.push(span.clone());
+                },
+                TextSpan::Text(t) => {
+                    left.push(TextSpan::Text(t[..*at].to_string()));
+                    right.push(TextSpan::Text(t[*at..].to_string()));
+                    *at = 0;
+                },
+                _ if *at == 0 => right.push(span.clone()),
+                TextSpan::Styled(TextStyle::RubyText, _) => left.push(span.clone()),
+                TextSpan::Styled(style, children) => {
+                    let (l, r) = StyledText::split_spans(children, at);
+                    left.push(TextSpan::Styled(style.clone(), l));
+                    right.push(TextSpan::Styled(style.clone(), r));
+                },
+            }
+        }
+        (left, right)
+    }
+    /// Replace each word of the plain text with a list of words, keeping the styling around
+    /// it, and then break between the new words as given
+    fn replace_words(&self, words: &[Vec<String>], breaks: &[bool]) -> StyledText {
+        let mut index = 0;
+        let mut in_word = false;
+        let replaced = StyledText::replace_word_spans(&self.spans, words, &mut index, &mut in_word);
+        let (mut gap, mut started, mut spacing) = (0, false, false);
+        let spans = StyledText::rebreak_spans(&replaced, breaks, &mut gap, &mut started, &mut spacing);
+        StyledText::from_spans(spans)
+    }
+    fn replace_word_spans(spans: &[TextSpan], words: &[Vec<String>], index: &mut usize,
+                          in_word: &mut bool) -> Vec<TextSpan> {
+        spans.iter().map(|span| match span {
+            TextSpan::Text(t) => {
+                let mut out = String::with_capacity(t.len());
+                for c in t.chars() {
+                    if c.is_whitespace() {
+                        out.push(c);
+                        *in_word = false;
+                    }
+                    else if !*in_word {
+                        // A word goes where it started, even if it runs on into other spans
+                        out.push_str(&words.get(*index).map_or(String::new(), |w| w.join(" ")));
+                        *index += 1;
+                        *in_word = true;
+                    }
+                }
+                TextSpan::Text(out)
+            },
+            TextSpan::Styled(TextStyle::RubyText, _) => span.clone(),
+            TextSpan::Styled(style, children) => TextSpan::Styled(
+                style.clone(),
+                StyledText::replace_word_spans(children, words, index, in_word)
+            ),
+        }).collect()
+    }
+    /// Join another text onto the end of this one with a space
+    fn join(&self, other: &StyledText) -> StyledText {
+        let mut spans = self.spans.clone();
+        spans.push(TextSpan::Text(String::from(" ")));
+        spans.extend(other.spans.iter().cloned());
+        StyledText::from_spans(spans)
+    }
+    /// Apply a function to each stretch of text, keeping the styling around it
+    fn map_text<F: Fn(&str) -> String>(&self, f: &F) -> StyledText {
+        StyledText::from_spans(StyledText::map_spans(&self.spans, f))
+    }
+    fn map_spans<F: Fn(&str) -> String>(spans: &[TextSpan], f: &F) -> Vec<TextSpan> {
+        spans.iter().map(|span| match span {
+            TextSpan::Text(t) => TextSpan::Text(f(t)),
+            TextSpan::Styled(style, children) => TextSpan::Styled(style.clone(), StyledText::map_spans(children, f)),
+        }).collect()
+    }
+    /// Get the names of the speakers of VTT voice spans, in order
+    fn voices(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        StyledText::voice_spans(&self.spans, &mut names);
+        names
+    }
+    fn voice_spans(spans: &[TextSpan], names: &mut Vec<String>) {
+        for span in spans.iter() {
+            if let TextSpan::Styled(style, children) = span {
+                if let TextStyle::Voice(name) = style {
+                    names.push(name.clone());
+                }
+                StyledText::voice_spans(children, names);
+            }
+        }
+    }
+    /// Rename the speakers of VTT voice spans, removing the span where the new name is empty
+    fn map_voices<F: Fn(&str) -> Option<String>>(&self, f: &F) -> StyledText {
+        StyledText::from_spans(StyledText::map_voice_spans(&self.spans, f))
+    }
+    fn map_voice_spans<F: Fn(&str) -> Option<String>>(spans: &[TextSpan], f: &F) -> Vec<TextSpan> {
+        let mut out = Vec::with_capacity(spans.len());
+        for span in spans.iter() {
+            match span {
+                TextSpan::Text(_) => out.push(span.clone()),
+                TextSpan::Styled(TextStyle::Voice(name), children) => {
+                    let children = StyledText::map_voice_spans(children, f);
+                    match f(name) {
+                        Some(new) if new.is_empty() => out.extend(children),
+                        Some(new) => out.push(TextSpan::Styled(TextStyle::Voice(new), children)),
+                        None => out.push(TextSpan::Styled(TextStyle::Voice(name.clone()), children)),
+                    }
+                },
+                TextSpan::Styled(style, children) => {
+                    out.push(TextSpan::Styled(style.clone(), StyledText::map_voice_spans(children, f)));
+                },
+            }
+        }
+        out
+    }
+    /// Render in a dialect, inserting markers at byte offsets into the plain text
+    fn render(&self, dialect: TextDialect, markers: &[(usize, String)]) -> String {
+        let mut out = String::new();
+        let mut pos = 0;
+        let mut next_marker = 0;
+        StyledText::render_spans(&self.spans, dialect, markers, &mut next_marker, &mut pos, false, &mut out);
+        // Markers past the end of the text go at the end
+        for (_, m) in markers[next_marker..].iter() {
+            out.push_str(m);
+        }
+        out
+    }
+    fn render_spans(spans: &[TextSpan], dialect: TextDialect, markers: &[(usize, String)],
+                    next_marker: &mut usize, pos: &mut usize, in_annotation: bool, out: &mut String) {
+        for span in spans.iter() {
+            match span {
+                TextSpan::Text(t) => {
+                    if in_annotation {
+                        // Annotations are not part of the plain text
+                        out.push_str(&StyledText::escape(t, dialect));
+                        continue;
+                    }
+                    let mut written = 0;
+                    while let Some((at, m)) = markers.get(*next_marker) {
+                        if *at >= *pos + t.len() {
+                            break;
+                        }
+                        let split = at.saturating_sub(*pos).max(written);
+                        if !t.is_char_boundary(split) {
+                            break;
+                        }
+                        out.push_str(&StyledText::escape(&t[written..split], dialect));
+                        out.push_str(m);
+                        written = split;
+                        *next_marker += 1;
+                    }
+                    out.push_str(&StyledText::escape(&t[written..], dialect));
+                    *pos += t.len();
+                },
+                TextSpan::Styled(style, children) => {
+                    let (open, close) = StyledText::tags(style, dialect);
+                    let annotation = in_annotation || *style == TextStyle::RubyText;
+                    if dialect == TextDialect::Srt && *style == TextStyle::RubyText {
+                        continue;
+                    }
+                    out.push_str(&open);
+                    StyledText::render_spans(children, dialect, markers, next_marker, pos, annotation, out);
+                    out.push_str(&close);
+                },
+            }
+        }
+    }
+    /// Opening and closing markup for a style; empty if the dialect cannot express it
+    fn tags(style: &TextStyle, dialect: TextDialect) -> (String, String) {
+        let pair = |name: &str| (format!("<{}>", name), format!("</{}>", name));
+        let none = || (String::new(), String::new());
+        match (dialect, style) {
+            (_, TextStyle::Italic) => pair("i"),
+            (_, TextStyle::Bold) => pair("b"),
+            (_, TextStyle::Underline) => pair("u"),
+            (TextDialect::Vtt, TextStyle::Color(c)) => match StyledText::vtt_color_class(c) {
+                Some(class) => (format!("<c.{}>", class), "</c>".to_string()),
+                None => none(),
+            },
+            (TextDialect::Vtt, TextStyle::Class(classes)) => {
+                (format!("<c.{}>", classes.join(".")), "</c>".to_string())
+            },
+            (TextDialect::Vtt, TextStyle::Lang(l)) => (format!("<lang {}>", l), "</lang>".to_string()),
+            (TextDialect::Vtt, TextStyle::Voice(v)) => (format!("<v {}>", v), "</v>".to_string()),
+            (TextDialect::Vtt, TextStyle::Ruby) => pair("ruby"),
+            (TextDialect::Vtt, TextStyle::RubyText) => pair("rt"),
+            (TextDialect::Srt, TextStyle::Color(c)) => {
+                (format!("<font color=\"{}\">", c), "</font>".to_string())
+            },
+            (TextDialect::Srt, TextStyle::Class(classes)) => {
+                // Only the colour classes mean anything outside of VTT
+                match classes.iter().find(|c| VTT_COLORS.iter().any(|(name, _)| name == c)) {
+                    Some(c) => (format!("<font color=\"{}\">", c), "</font>".to_string()),
+                    None => none(),
+                }
+            },
+            (TextDialect::Srt, _) => none(),
+        }
+    }
+    /// Find the default VTT class for a colour, if there is one
+    fn vtt_color_class(color: &str) -> Option<&'static str> {
+        let color = color.to_lowercase();
+        VTT_COLORS.iter()
+            .find(|(name, rgb)| *name == color || *rgb == color)
+            .map(|(name, _)| *name)
+    }
+    /// Escape text for a dialect
+    fn escape(s: &str, dialect: TextDialect) -> String {
+        match dialect {
+            TextDialect::Vtt => s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"),
+            TextDialect::Srt => s.to_string(),
+        }
+    }
+    /// Undo VTT character references
+    fn unescape(s: &str) -> String {
+        s.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&nbsp;", "\u{a0}")
+            .replace("&lrm;", "\u{200e}")
+            .replace("&rlm;", "\u{200f}")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&")
+    }
+    /// Split HTML-like markup into text and tags
+    fn tokenize(s: &str, unescape: bool) -> Vec<MarkupToken> {
+        let mut tokens: Vec<MarkupToken> = Vec::new();
+        let mut text = String::new();
+        let mut rest = s;
+        while let Some(open) = rest.find('<') {
+            let tail = &rest[open + 1..];
+            let close = match tail.find('>') {
+                Some(n) => n,
+                None => break,
+            };
+            let inner = tail[..close].trim();
+            let is_tag = inner.trim_start_matches('/')
+                .starts_with(|c: char| c.is_ascii_alphabetic());
+            text.push_str(&rest[..open]);
+            if !is_tag {
+                // Not markup, e.g. "a < b > c"
+                text.push_str(&rest[open..open + close + 2]);
+                rest = &tail[close + 1..];
+                continue;
+            }
+            if !text.is_empty() {
+                tokens.push(MarkupToken::Text(if unescape { StyledText::unescape(&text) } else { text.clone() }));
+                text.clear();
+            }
+            if let Some(name) = inner.strip_prefix('/') {
+                tokens.push(MarkupToken::Close(name.trim().to_lowercase()));
+            }
+            else {
+                let (head, annotation) = match inner.find(char::is_whitespace) {
+                    Some(n) => (&inner[..n], inner[n..].trim()),
+                    None => (inner, ""),
+                };
+                let mut parts = head.split('.');
+                let name = parts.next().unwrap_or("").to_lowercase();
+                let classes = parts.filter(|c| !c.is_empty()).map(|c| c.to_string()).collect();
+                tokens.push(MarkupToken::Open(name, classes, annotation.to_string()));
+            }
+            rest = &tail[close + 1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            tokens.push(MarkupToken::Text(if unescape { StyledText::unescape(&text) } else { text }));
+        }
+        tokens
+    }
+    /// Append a span, merging neighbouring text
+    fn push_token_span(spans: &mut Vec<TextSpan>, span: TextSpan) {
+        if let TextSpan::Text(t) = &span {
+            if let Some(TextSpan::Text(last)) = spans.last_mut() {
+                last.push_str(t);
+                return;
+            }
+        }
+        spans.push(span);
+    }
+    /// Build a tree from tokens; tags the style function does not recognise are dropped
+    fn build(tokens: Vec<MarkupToken>,
+             style: fn(&str, &[String], &str) -> Option<TextStyle>,
+             text: fn(String) -> Vec<TextSpan>) -> StyledText {
+        // Stack of open tags, with the root at the bottom
+        let mut stack: Vec<(String, Option<TextStyle>, Vec<TextSpan>)> = vec!((String::new(), None, Vec::new()));
+        let pop = |stack: &mut Vec<(String, Option<TextStyle>, Vec<TextSpan>)>| {
+            let (_, s, children) = stack.pop().unwrap();
+            let parent = &mut stack.last_mut().unwrap().2;
+            match s {
+                Some(s) => parent.push(TextSpan::Styled(s, children)),
+                None => {
+                    for c in children.into_iter() {
+                        StyledText::push_token_span(parent, c);
+                    }
+                },
+            }
+        };
+        for token in tokens.into_iter() {
+            match token {
+                MarkupToken::Text(t) => {
+                    for span in text(t).into_iter() {
+                        StyledText::push_token_span(&mut stack.last_mut().unwrap().2, span);
+                    }
+                },
+                MarkupToken::Open(name, classes, annotation) => {
+                    let s = style(&name, &classes, &annotation);
+                    stack.push((name, s, Vec::new()));
+                },
+                MarkupToken::Close(name) => {
+                    // Close everything up to the matching tag; stray closing tags are ignored
+                    if let Some(n) = stack.iter().skip(1).rposition(|(open, _, _)| *open == name) {
+                        while stack.len() > n + 1 {
+                            pop(&mut stack);
+                        }
+                    }
+                },
+            }
+        }
+        while stack.len() > 1 {
+            pop(&mut stack);
+        }
+        StyledText::from_spans(stack.pop().unwrap().2)
+    }
+    /// Spans of SRT text between tags, which may hold override codes
+    fn srt_text(t: String) -> Vec<TextSpan> {
+        if t.contains("{\\") {
+            StyledText::from_ass(&t).spans
+        }
+        else {
+            vec!(TextSpan::Text(t))
+        }
+    }
+    /// Spans of VTT text between tags
+    fn vtt_text(t: String) -> Vec<TextSpan> {
+        vec!(TextSpan::Text(t))
+    }
+    /// Styles of SRT tags
+    fn srt_style(name: &str, _classes: &[String], annotation: &str) -> Option<TextStyle> {
+        match name {
+            "i" => Some(TextStyle::Italic),
+            "b" => Some(TextStyle::Bold),
+            "u" => Some(TextStyle::Underline),
+            "font" => FONT_COLOR.captures(annotation)
+                .map(|c| TextStyle::Color(c[1].to_string())),
+            _ => None,
+        }
+    }
+    /// Styles of VTT tags
+    fn vtt_style(name: &str, classes: &[String], annotation: &str) -> Option<TextStyle> {
+        match name {
+            "i" => Some(TextStyle::Italic),
+            "b" => Some(TextStyle::Bold),
+            "u" => Some(TextStyle::Underline),
+            "c" if !classes.is_empty() => Some(TextStyle::Class(classes.to_vec())),
+            "lang" => Some(TextStyle::Lang(annotation.to_string())),
+            "v" => Some(TextStyle::Voice(annotation.to_string())),
+            "ruby" => Some(TextStyle::Ruby),
+            "rt" => Some(TextStyle::RubyText),
+            _ => None,
+        }
+    }
+    /// Merge neighbouring text and neighbouring spans with the same style, and drop empty spans
+    fn normalize(spans: Vec<TextSpan>) -> Vec<TextSpan> {
+        let mut out: Vec<TextSpan> = Vec::with_capacity(spans.len());
+        for span in spans.into_iter() {
+            match span {
+                TextSpan::Text(t) => {
+                    if !t.is_empty() {
+                        StyledText::push_token_span(&mut out, TextSpan::Text(t));
+                    }
+                },
+                TextSpan::Styled(style, children) => {
+                    let children = StyledText::normalize(children);
+                    if children.is_empty() {
+                        continue;
+                    }
+                    if let Some(TextSpan::Styled(last_style, last_children)) = out.last_mut() {
+                        if *last_style == style && style != TextStyle::Ruby {
+                            last_children.extend(children);
+                            let merged = std::mem::take(last_children);
+                            *last_children = StyledText::normalize(merged);
+                            continue;
+                        }
+                    }
+                    out.push(TextSpan::Styled(style, children));
+                },
+            }
+        }
+        out
+    }
+}
+
+impl From<&str> for StyledText {
+    fn from(s: &str) -> Self {
+        StyledText::from_spans(vec!(TextSpan::Text(s.to_string())))
+    }
+}
+
+impl From<String> for StyledText {
+    fn from(s: String) -> Self {
+        StyledText::from_spans(vec!(TextSpan::Text(s)))
+    }
+}
+
+impl fmt::Display for StyledText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_plain())
+    }
+}
+
+/// Caption blocks contain an optional speaker, start and end times, and the text that will be
+/// displayed on the screen during the block.
+/// Not particularly useful on their own.
+///
+/// # Examples
+/// Create a CaptionBlock with no speaker, from 0 seconds to 1 second, and a text of "Hello!"
+/// ```
+/// use ccap::{CaptionBlock, SimpleTime};
+///
+/// let block = CaptionBlock::from(
+///     None,
+///     SimpleTime::from_milliseconds(0),
+///     SimpleTime::from_milliseconds(1000),
+///     String::from("Hello!")).unwrap();
+/// assert_eq!(block.speaker(), None);
+/// assert_eq!(block.start().second(), 0);
+/// assert_eq!(block.end().second(), 1);
+/// assert_eq!(block.text(), "Hello!");
+/// ```
+///
+/// Blocks may optionally carry timings for the individual words or phrases in their text, as
+/// produced by speech recognition or VTT inline timestamps.
+/// ```
+/// use ccap::{CaptionBlock, SimpleTime, TimedWord};
+///
+/// let mut block = CaptionBlock::from(
+///     None,
+///     SimpleTime::from_milliseconds(0),
+///     SimpleTime::from_milliseconds(1000),
+///     String::from("Hello world")).unwrap();
+/// block.set_words(vec!(
+///     TimedWord::from("Hello", SimpleTime::from_milliseconds(0), SimpleTime::from_milliseconds(400)),
+///     TimedWord::from("world", SimpleTime::from_milliseconds(400), SimpleTime::from_milliseconds(1000)),
+/// )).unwrap();
+/// block.offset_milliseconds(500).unwrap();
+/// assert_eq!(block.words()[1].start().to_milliseconds(), 900);
+/// ```
+#[derive(Debug, Clone)]
+pub struct CaptionBlock {
+    speaker: Option<String>,
+    start: SimpleTime,
+    end: SimpleTime,
+    text: StyledText,
+    words: Vec<TimedWord>,
+}
+
+impl CaptionBlock {
+    /// Construct a CaptionBlock from its parts
+    pub fn from(speaker: Option<String>, start: SimpleTime, end: SimpleTime, text: String) -> Result<CaptionBlock, CaptionBlockError> {
+        // Verify start is less than end
+        let diff = (end.to_milliseconds() as i128) - (start.to_milliseconds() as i128);
+        if diff < 0 {
+            Err(CaptionBlockError::EndsBeforeStart(start, end))
+        }
+        else {
+            Ok(
+                CaptionBlock {
+                    speaker,
+                    start,
+                    end,
+                    text: StyledText::from(text),
+                    words: Vec::new(),
+                }
+            )
+        }
+    }
+    /// Get a copy of this block's text, without styling
+    pub fn text(&self) -> String {
+        self.text.to_plain()
+    }
+    /// Get a copy of this block's styled text
+    pub fn styled_text(&self) -> StyledText {
+        self.text.clone()
+    }
+    /// Replace this block's text
+    pub fn set_text(&mut self, text: StyledText) {
+        self.text = text;
+    }
+    /// Get a copy of this block's speaker
+    pub fn speaker(&self) -> Option<String> {
+        self.speaker.clone()
+    }
+    /// Get a copy of this caption block's start time
+    pub fn start(&self) -> SimpleTime {
+        self.start
+    }
+    /// Get a copy of this caption block's end time
+    pub fn end(&self) -> SimpleTime {
+        self.end
+    }
+    /// Get the length of this caption block in milliseconds
+    pub fn length_millis(&self) -> usize {
+        // We're guaranteed that end > start, no need to worry about negatives
+        self.end.to_milliseconds() - self.start.to_milliseconds()
+    }
+    /// Get a copy of this block's word timings; empty if the block has none
+    pub fn words(&self) -> Vec<TimedWord> {
+        self.words.clone()
+    }
+    /// Set the word timings, which must be in order and lie within the block
+    pub fn set_words(&mut self, words: Vec<TimedWord>) -> Result<(), CaptionBlockError> {
+        let mut previous = self.start;
+        for w in words.iter() {
+            if w.start < previous || w.end < w.start || w.end > self.end {
+                return Err(CaptionBlockError::WordOutOfBounds(w.start, w.end));
+            }
+            previous = w.start;
+        }
+        self.words = words;
+        Ok(())
+    }
+    /// Offset the times in this caption block
+    pub fn offset_milliseconds(&mut self, n: isize) -> Result<(), NegativeSimpleTime> {
+        self.start.offset(n)?;
+        self.end.offset(n)?;
+        for w in self.words.iter_mut() {
+            w.start.offset(n)?;
+            w.end.offset(n)?;
+        }
+        Ok(())
+    }
+    /// Set the start time; word timings before the new start are trimmed
+    pub fn set_start(&mut self, start: SimpleTime) -> Result<(), NegativeSimpleTime> {
+        if start.to_milliseconds() <= self.end.to_milliseconds() {
+            self.start = start;
+            self.clamp_words();
+            Ok(())
+        }
+        else {
+            Err(NegativeSimpleTime)
+        }
+    }
+    /// Set the end time; word timings after the new end are trimmed
+    pub fn set_end(&mut self, end: SimpleTime) -> Result<(), NegativeSimpleTime> {
+        if end.to_milliseconds() >= self.start.to_milliseconds() {
+            self.end = end;
+            self.clamp_words();
+            Ok(())
+        }
+        else {
+            Err(NegativeSimpleTime)
+        }
+    }
+    /// Move every time in this block through a mapping of milliseconds to milliseconds.
+    ///
+    /// New times are rounded to the nearest millisecond and applied with SimpleTime::offset.
+    /// A block that had some length keeps at least one millisecond of it.
+    fn map_times<F: Fn(f64) -> f64>(&mut self, f: F) -> Result<(), NegativeSimpleTime> {
+        let new_time = |t: &SimpleTime| -> Result<isize, NegativeSimpleTime> {
+            let new_millis = f(t.to_milliseconds() as f64).round();
+            if new_millis < 0.0 || !new_millis.is_finite() {
+                return Err(NegativeSimpleTime);
+            }
+            Ok(new_millis as isize - t.to_milliseconds() as isize)
+        };
+        // Work out every change before applying any, so a failure leaves the block untouched
+        let start_offset = new_time(&self.start)?;
+        let mut end_offset = new_time(&self.end)?;
+        let new_start = self.start.to_milliseconds() as isize + start_offset;
+        let new_end = self.end.to_milliseconds() as isize + end_offset;
+        if self.end > self.start && new_end <= new_start {
+            end_offset += new_start + 1 - new_end;
+        }
+        let word_offsets = self.words.iter()
+            .map(|w| Ok((new_time(&w.start)?, new_time(&w.end)?)))
+            .collect::<Result<Vec<(isize, isize)>, NegativeSimpleTime>>()?;
+        self.start.offset(start_offset)?;
+        self.end.offset(end_offset)?;
+        for (w, (s, e)) in self.words.iter_mut().zip(word_offsets) {
+            w.start.offset(s)?;
+            w.end.offset(e)?;
+        }
+        self.clamp_words();
+        Ok(())
+    }
+    /// Drop words that fall outside the block and shorten those that straddle its edges
+    fn clamp_words(&mut self) {
+        let (start, end) = (self.start, self.end);
+        self.words.retain(|w| w.end > start && w.start < end);
+        for w in self.words.iter_mut() {
+            if w.start < start {
+                w.start = start;
+            }
+            if w.end > end {
+                w.end = end;
+            }
+        }
+    }
+}
+
+/// Error types for CaptionBlock
+#[derive(Debug)]
+pub enum CaptionBlockError {
+    EndsBeforeStart(SimpleTime, SimpleTime),
+    WordOutOfBounds(SimpleTime, SimpleTime),
+}
+
+/// A word, or run of words, within a caption block that has its own timing.
+///
+/// The text of a TimedWord is a piece of its block's text, so that formats with word timing
+/// (such as VTT inline timestamps) can mark where in the text each word begins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedWord {
+    text: String,
+    start: SimpleTime,
+    end: SimpleTime,
+}
+
+impl TimedWord {
+    /// Construct a TimedWord from its parts
+    pub fn from(text: &str, start: SimpleTime, end: SimpleTime) -> TimedWord {
+        TimedWord {
+            text: text.to_string(),
+            start,
+            end,
+        }
+    }
+    /// Get a copy of this word's text
+    pub fn text(&self) -> String {
+        self.text.clone()
+    }
+    /// Get a copy of this word's start time
+    pub fn start(&self) -> SimpleTime {
+        self.start
+    }
+    /// Get a copy of this word's end time
+    pub fn end(&self) -> SimpleTime {
+        self.end
+    }
+}
+
+/// A linear correction of caption times, where `new = old * rate + offset` in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearTransform {
+    pub rate: f64,
+    pub offset: f64,
+}
+
+impl LinearTransform {
+    /// Find the correction that moves two caption times to two video times
+    pub fn from_anchors(first: (SimpleTime, SimpleTime), second: (SimpleTime, SimpleTime))
+    -> Result<LinearTransform, SyncError> {
+        let (x0, y0) = (first.0.to_milliseconds() as f64, first.1.to_milliseconds() as f64);
+        let (x1, y1) = (second.0.to_milliseconds() as f64, second.1.to_milliseconds() as f64);
+        if x0 == x1 {
+            return Err(SyncError::AnchorsCoincide);
+        }
+        let rate = (y1 - y0) / (x1 - x0);
+        if rate <= 0.0 {
+            return Err(SyncError::AnchorsReversed);
+        }
+        Ok(
+            LinearTransform {
+                rate,
+                offset: y0 - rate * x0,
+            }
+        )
+    }
+    /// Apply the correction to a time in milliseconds
+    pub fn apply(&self, t: f64) -> f64 {
+        t * self.rate + self.offset
+    }
+}
+
+/// Options for estimating the timing of one caption against a reference
+#[derive(Debug, Clone)]
+pub struct AlignOptions {
+    /// Largest offset to consider, in milliseconds either way
+    pub max_offset: usize,
+    /// Also estimate a difference in rate (drift), not just an offset
+    pub estimate_drift: bool,
+    /// Largest difference in rate to consider, as a fraction (0.05 is 5%).  Values that are not
+    /// below 1 are treated as just under 1, and values that are not positive as no drift.
+    pub max_drift: f64,
+}
+
+impl Default for AlignOptions {
+    fn default() -> Self {
+        AlignOptions {
+            max_offset: 60_000,
+            estimate_drift: false,
+            max_drift: 0.05,
+        }
+    }
+}
+
+/// The estimated correction that lines a caption up with a reference
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    pub transform: LinearTransform,
+    /// How well cue activity matches after correction, from 0 (not at all) to 1 (exactly)
+    pub confidence: f64,
+}
+
+/// A point in a caption used to synchronise it with a video
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncAnchor {
+    /// The start of a cue, counting from 1 as numbered in VTT and SRT files
+    Cue(usize),
+    /// A time in the caption
+    Time(SimpleTime),
+}
+
+/// Error type for synchronising captions
+#[derive(Debug, Clone)]
+pub enum SyncError {
+    AnchorsCoincide,
+    AnchorsReversed,
+    CueOutOfRange(usize),
+    NegativeTime,
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::AnchorsCoincide => write!(f, "both anchors refer to the same caption time"),
+            SyncError::AnchorsReversed => write!(f, "anchors would reverse the order of the caption"),
+            SyncError::CueOutOfRange(n) => write!(f, "there is no cue number {}", n),
+            SyncError::NegativeTime => write!(f, "correction would move cues before zero"),
+        }
+    }
+}
+
+impl Error for SyncError {}
+
+/// A stretch of a revised transcript that could not be matched confidently to the original
+/// caption text, found by Caption::apply_transcript
+#[derive(Debug, Clone, PartialEq)]
+pub struct UncertainRegion {
+    /// Index of the first block affected, before any empty blocks were removed
+    pub first_block: usize,
+    /// Index of the last block affected, before any empty blocks were removed
+    pub last_block: usize,
+    /// The original words in this stretch
+    pub original: String,
+    /// The revised words in this stretch
+    pub revised: String,
+}
+
+/// Step in a word-by-word diff
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WordDiff {
+    Match(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Words that differ by more than this in a row are reported as uncertain
+const TRANSCRIPT_UNCERTAIN_RUN: usize = 3;
+
+/// Half width of the band of the word-by-word diff, in words
+const TRANSCRIPT_DIFF_BAND: usize = 200;
+
+/// What happens to a block that is on screen when a gap is inserted, used by Caption::insert_gaps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Straddle {
+    /// Split the block so its text is shown both before and after the gap
+    Split,
+    /// End the block at the gap so its text is only shown before it
+    Before,
+}
+
+/// Where to split a caption, used by Caption::split
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitPoint {
+    /// Split before a cue, counting from 1
+    Cue(usize),
+    /// Split at a time
+    Time(SimpleTime),
+}
+
+/// Error type for Caption::split
+#[derive(Debug, Clone, PartialEq)]
+pub enum SplitError {
+    CueOutOfRange(usize),
+}
+
+impl Error for SplitError {}
+
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SplitError::CueOutOfRange(n) => write!(f, "there is no cue {}", n),
+        }
+    }
+}
+
+/// A chapter marker, such as those in a video description or an ffmpeg metadata file
+///
+/// # Examples
+/// ```
+/// use ccap::Chapter;
+///
+/// let chapters = Chapter::parse_list("0:00 Intro\n12:30 - Interview\n1:02:03.500 Outro\n").unwrap();
+/// assert_eq!(chapters.len(), 3);
+/// assert_eq!(chapters[1].title, "Interview");
+/// assert_eq!(chapters[2].start.to_milliseconds(), 3_723_500);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub start: SimpleTime,
+    pub title: String,
+}
+
+impl Chapter {
+    /// Read chapters from a file
+    pub fn from_file(fname: &str) -> Result<Vec<Chapter>, Box<dyn Error>> {
+        let s = fs::read_to_string(fname)?;
+        Ok(Chapter::parse_list(&s)?)
+    }
+    /// Parse a list of chapters.
+    ///
+    /// Either an ffmpeg metadata file (starting with ;FFMETADATA1) or one chapter per line, in
+    /// the form `[H:]MM:SS[.mmm] Title` used in video descriptions.
+    pub fn parse_list(contents: &str) -> Result<Vec<Chapter>, ChapterError> {
+        let mut chapters = if contents.trim_start().starts_with(";FFMETADATA") {
+            Chapter::parse_ffmetadata(contents)?
+        }
+        else {
+            contents.lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(Chapter::parse_line)
+                .collect::<Result<Vec<Chapter>, ChapterError>>()?
+        };
+        chapters.sort_by_key(|c| c.start.to_milliseconds());
+        Ok(chapters)
+    }
+    /// Parse a chapter line such as `1:02:03 Title` or `12:30 - Title`
+    fn parse_line(line: &str) -> Result<Chapter, ChapterError> {
+        let line = line.trim();
+        let (time, title) = match line.find(char::is_whitespace) {
+            Some(n) => (&line[..n], line[n..].trim_start()),
+            None => (line, ""),
+        };
+        let title = title.trim_start_matches(['-', '–']).trim();
+        let invalid = || ChapterError::InvalidLine(String::from(line));
+        let (clock, millis) = match time.find('.') {
+            Some(n) => {
+                let fraction = &time[n + 1..];
+                if fraction.is_empty() || fraction.len() > 3 {
+                    return Err(invalid());
+                }
+                let digits = fraction.parse::<usize>().map_err(|_| invalid())?;
+                (&time[..n], digits * 10usize.pow(3 - fraction.len() as u32))
+            },
+            None => (time, 0),
+        };
+        let parts = clock.split(':')
+            .map(|p| if !p.is_empty() && p.len() <= 2 { p.parse::<usize>().ok() } else { None })
+            .collect::<Option<Vec<usize>>>()
+            .ok_or_else(invalid)?;
+        let (hours, minutes, seconds) = match parts[..] {
+            [m, s] => (0, m, s),
+            [h, m, s] => (h, m, s),
+            _ => return Err(invalid()),
+        };
+        if minutes >= 60 || seconds >= 60 {
+            return Err(invalid());
+        }
+        Ok(Chapter {
+            start: SimpleTime::from_parts(hours, minutes, seconds, millis),
+            title: String::from(title),
+        })
+    }
+    /// Parse the [CHAPTER] sections of an ffmpeg metadata file
+    fn parse_ffmetadata(contents: &str) -> Result<Vec<Chapter>, ChapterError> {
+        let mut chapters = Vec::new();
+        // Time base, start and title of the chapter being read
+        let mut current: Option<((u64, u64), Option<u64>, String)> = None;
+        let finish = |current: Option<((u64, u64), Option<u64>, String)>, chapters: &mut Vec<Chapter>| {
+            if let Some(((num, den), start, title)) = current {
+                let start = start.ok_or_else(|| ChapterError::InvalidLine(format!("[CHAPTER] {}", title)))?;
+                let millis = (start as u128 * num as u128 * 1000 / den as u128) as usize;
+                chapters.push(Chapter { start: SimpleTime::from_milliseconds(millis), title });
+            }
+            Ok(())
+        };
+        for line in contents.lines().map(|l| l.trim()) {
+            if line.starts_with('[') {
+                finish(current.take(), &mut chapters)?;
+                if line == "[CHAPTER]" {
+                    current = Some(((1, 1_000_000_000), None, String::new()));
+                }
+                continue;
+            }
+            let chapter = match current.as_mut() {
+                Some(c) => c,
+                None => continue,
+            };
+            let (key, value) = match line.find('=') {
+                Some(n) => (&line[..n], &line[n + 1..]),
+                None => continue,
+            };
+            let invalid = || ChapterError::InvalidLine(String::from(line));
+            match key {
+                "TIMEBASE" => {
+                    let (num, den) = match value.find('/') {
+                        Some(n) => (&value[..n], &value[n + 1..]),
+                        None => return Err(invalid()),
+                    };
+                    let num = num.parse::<u64>().map_err(|_| invalid())?;
+                    let den = den.parse::<u64>().map_err(|_| invalid())?;
+                    if num == 0 || den == 0 {
+                        return Err(invalid());
+                    }
+                    chapter.0 = (num, den);
+                },
+                "START" => chapter.1 = Some(value.parse::<u64>().map_err(|_| invalid())?),
+                "title" => chapter.2 = String::from(value),
+                _ => {},
+            }
+        }
+        finish(current.take(), &mut chapters)?;
+        Ok(chapters)
+    }
+}
+
+/// Error type for Chapter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChapterError {
+    InvalidLine(String),
+}
+
+impl Error for ChapterError {}
+
+impl fmt::Display for ChapterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChapterError::InvalidLine(s) => write!(f, "invalid chapter {}", s),
+        }
+    }
+}
+
+/// Where one caption goes when concatenating, used by Caption::concatenate_with
+///
+/// By default a caption starts where the previous one ended, which is the end of its last cue
+/// unless a duration is given.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SegmentTiming {
+    /// Time this caption starts at in the concatenated caption
+    pub start: Option<SimpleTime>,
+    /// Length of the video this caption belongs to, in milliseconds
+    pub duration: Option<usize>,
+}
+
+/// Options for Caption::concatenate_with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConcatenateOptions {
+    /// Milliseconds between one caption and the next, unless the next has its own start
+    pub gap: usize,
+    /// Keep the headers of the captions (such as styles), each distinct header once
+    pub merge_headers: bool,
+}
+
+/// Error type for Caption::concatenate_with; captions are counted from 1
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConcatenateError {
+    /// The caption would start before the previous one has ended
+    Overlap(usize),
+    /// The caption has cues after the end of its duration
+    DurationTooShort(usize),
+    /// The caption would end later than a time can hold
+    TooLate(usize),
+}
+
+impl Error for ConcatenateError {}
+
+impl fmt::Display for ConcatenateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConcatenateError::Overlap(n) => {
+                write!(f, "caption {} would start before the previous caption ends", n)
+            },
+            ConcatenateError::DurationTooShort(n) => {
+                write!(f, "caption {} has cues after the end of its duration", n)
+            },
+            ConcatenateError::TooLate(n) => {
+                write!(f, "caption {} would end later than a time can hold", n)
+            },
+        }
+    }
+}
+
+/// Index of the cues of a caption for finding what is on screen at a time, made by
+/// Caption::index.
+///
+/// Lookups take logarithmic time plus the number of cues found.  A cue is on screen from its
+/// start up to, but not including, its end.
+///
+/// # Examples
+/// ```
+/// use ccap::{Caption, CaptionBlock, SimpleTime};
+///
+/// let blocks = vec!(
+///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+///         SimpleTime::from_milliseconds(5000), String::from("[Music]")).unwrap(),
+///     CaptionBlock::from(None, SimpleTime::from_milliseconds(1000),
+///         SimpleTime::from_milliseconds(2000), String::from("Hello")).unwrap(),
+/// );
+/// let cap = Caption::from(None, blocks);
+/// let index = cap.index();
+/// assert_eq!(index.at(SimpleTime::from_milliseconds(1500)).len(), 2);
+/// assert_eq!(index.at(SimpleTime::from_milliseconds(2000))[0].text(), "[Music]");
+/// ```
+#[derive(Debug, Clone)]
+pub struct CueIndex<'a> {
+    caption: &'a Caption,
+    /// Start, end and block number of each cue, sorted by start
+    cues: Vec<(usize, usize, usize)>,
+    /// Latest end in the subtree rooted at each position, treating the sorted cues as a
+    /// balanced binary tree whose root is the middle cue
+    max_end: Vec<usize>,
+}
+
+impl<'a> CueIndex<'a> {
+    /// Index the cues of a caption
+    fn new(caption: &'a Caption) -> CueIndex<'a> {
+        let mut cues: Vec<(usize, usize, usize)> = caption.blocks.iter()
+            .enumerate()
+            .map(|(n, b)| (b.start.to_milliseconds(), b.end.to_milliseconds(), n))
+            .collect();
+        cues.sort_unstable();
+        let mut max_end = vec!(0; cues.len());
+        CueIndex::build(&cues, &mut max_end, 0, cues.len());
+        CueIndex { caption, cues, max_end }
+    }
+    /// Fill in the latest ends of the subtree over cues[lo..hi], returning it
+    fn build(cues: &[(usize, usize, usize)], max_end: &mut [usize], lo: usize, hi: usize) -> usize {
+        if lo >= hi {
+            return 0;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let left = CueIndex::build(cues, max_end, lo, mid);
+        let right = CueIndex::build(cues, max_end, mid + 1, hi);
+        max_end[mid] = cues[mid].1.max(left).max(right);
+        max_end[mid]
+    }
+    /// Collect the cues in cues[lo..hi] that overlap from..to
+    fn search(&self, lo: usize, hi: usize, from: usize, to: usize, found: &mut Vec<usize>) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        // Nothing in this subtree is still on screen by the start of the range
+        if self.max_end[mid] <= from {
+            return;
+        }
+        self.search(lo, mid, from, to, found);
+        let (start, end, n) = self.cues[mid];
+        // Everything to the right starts too late as well
+        if start >= to {
+            return;
+        }
+        if end > from {
+            found.push(n);
+        }
+        self.search(mid + 1, hi, from, to, found);
+    }
+    /// Get the cues on screen at a time, in caption order
+    pub fn at(&self, t: SimpleTime) -> Vec<&'a CaptionBlock> {
+        let t = t.to_milliseconds();
+        self.find(t, t + 1)
+    }
+    /// Get the cues on screen at any point from one time up to another, in caption order
+    pub fn overlapping(&self, from: SimpleTime, to: SimpleTime) -> Vec<&'a CaptionBlock> {
+        let (from, to) = (from.to_milliseconds(), to.to_milliseconds());
+        if from < to { self.find(from, to) } else { self.find(to, from) }
+    }
+    fn find(&self, from: usize, to: usize) -> Vec<&'a CaptionBlock> {
+        let mut found = Vec::new();
+        self.search(0, self.cues.len(), from, to, &mut found);
+        found.sort_unstable();
+        found.into_iter().map(|n| &self.caption.blocks[n]).collect()
+    }
+}
+
+/// Quality rules checked by Caption::lint; rules set to None are not checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintRules {
+    /// Whether cues may overlap
+    pub allow_overlap: bool,
+    /// Shortest gap between cues in milliseconds, unless they follow on directly
+    pub min_gap: Option<usize>,
+    /// Gaps shorter than this many milliseconds should be closed up to the minimum gap
+    pub close_gap: Option<usize>,
+    /// Shortest time a cue may be on screen in milliseconds
+    pub min_duration: Option<usize>,
+    /// Longest time a cue may be on screen in milliseconds
+    pub max_duration: Option<usize>,
+    /// Highest reading speed in characters per second, not counting line breaks
+    pub max_chars_per_second: Option<f64>,
+    /// Longest line in characters
+    pub max_line_length: Option<usize>,
+    /// Most lines in a cue
+    pub max_lines: Option<usize>,
+}
+
+impl Default for LintRules {
+    /// Common broadcast limits; the minimum gap is two frames at 24 fps
+    fn default() -> LintRules {
+        LintRules {
+            allow_overlap: false,
+            min_gap: Some(83),
+            close_gap: None,
+            min_duration: Some(833),
+            max_duration: None,
+            max_chars_per_second: Some(17.0),
+            max_line_length: Some(42),
+            max_lines: Some(2),
+        }
+    }
+}
+
+impl LintRules {
+    /// Names of the built-in profiles
+    pub const PROFILES: [&'static str; 4] = ["netflix-en", "bbc", "dcmp", "youtube"];
+    /// Get a built-in profile of rules from a delivery style guide.
+    ///
+    /// These follow the published limits of each guide as closely as the rules allow:
+    /// - `netflix-en`: Netflix English timed text style guide, at 24 fps
+    /// - `bbc`: BBC subtitle guidelines, at 25 fps
+    /// - `dcmp` (or `fcc`): DCMP captioning key, as used for FCC caption quality, at 29.97 fps
+    /// - `youtube`: YouTube's recommendations, which only limit line length and count
+    pub fn profile(name: &str) -> Option<LintRules> {
+        match name {
+            "netflix-en" => Some(LintRules {
+                allow_overlap: false,
+                // Two frames, with gaps under half a second closed up to that
+                min_gap: Some(83),
+                close_gap: Some(500),
+                min_duration: Some(833),
+                max_duration: Some(7000),
+                max_chars_per_second: Some(20.0),
+                max_line_length: Some(42),
+                max_lines: Some(2),
+            }),
+            "bbc" => Some(LintRules {
+                allow_overlap: false,
+                min_gap: Some(80),
+                close_gap: None,
+                min_duration: Some(1000),
+                max_duration: None,
+                // 160 to 180 words per minute
+                max_chars_per_second: Some(16.0),
+                max_line_length: Some(37),
+                max_lines: Some(2),
+            }),
+            "dcmp" | "fcc" => Some(LintRules {
+                allow_overlap: false,
+                min_gap: Some(67),
+                close_gap: None,
+                min_duration: Some(1000),
+                max_duration: Some(6000),
+                max_chars_per_second: Some(15.0),
+                max_line_length: Some(32),
+                max_lines: Some(2),
+            }),
+            "youtube" => Some(LintRules {
+                allow_overlap: false,
+                min_gap: None,
+                close_gap: None,
+                min_duration: None,
+                max_duration: None,
+                max_chars_per_second: None,
+                max_line_length: Some(42),
+                max_lines: Some(2),
+            }),
+            _ => None,
+        }
+    }
+    /// Read profiles from a TOML file
+    pub fn profiles_from_file(fname: &str) -> Result<HashMap<String, LintRules>, Box<dyn Error>> {
+        let s = fs::read_to_string(fname)?;
+        Ok(LintRules::profiles_from_toml(&s)?)
+    }
+    /// Read profiles from TOML, with one table per profile.
+    ///
+    /// A table named after a built-in profile changes it; other tables start from the default
+    /// rules, or from the profile named by `extends`.  Keys are the names of the LintRules
+    /// fields, where 0 turns a limit off.  Gaps can also be given in frames, as `min_gap_frames`
+    /// and `close_gap_frames` along with `fps`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::LintRules;
+    ///
+    /// let profiles = LintRules::profiles_from_toml(r#"
+    ///     [netflix-en]
+    ///     max_chars_per_second = 17
+    ///
+    ///     [client]
+    ///     extends = "bbc"
+    ///     max_lines = 3
+    ///     fps = 25
+    ///     min_gap_frames = 3
+    /// "#).unwrap();
+    /// assert_eq!(profiles["netflix-en"].max_chars_per_second, Some(17.0));
+    /// assert_eq!(profiles["netflix-en"].max_line_length, Some(42));
+    /// assert_eq!(profiles["client"].max_lines, Some(3));
+    /// assert_eq!(profiles["client"].min_gap, Some(120));
+    /// ```
+    pub fn profiles_from_toml(contents: &str) -> Result<HashMap<String, LintRules>, LintProfileError> {
+        let table = match contents.parse::<toml::Value>() {
+            Ok(toml::Value::Table(t)) => t,
+            Ok(_) => return Err(LintProfileError::InvalidToml(String::from("expected tables of profiles"))),
+            Err(e) => return Err(LintProfileError::InvalidToml(e.to_string())),
+        };
+        let mut profiles = HashMap::new();
+        for name in table.keys() {
+            profiles.insert(name.clone(), LintRules::resolve_profile(&table, name, 0)?);
+        }
+        Ok(profiles)
+    }
+    /// Work out a profile from a table of profiles, following `extends`
+    fn resolve_profile(table: &toml::value::Table, name: &str, depth: usize) -> Result<LintRules, LintProfileError> {
+        let profile = match table.get(name) {
+            Some(toml::Value::Table(t)) => t,
+            Some(_) => return Err(LintProfileError::InvalidValue(String::from(name))),
+            None => return LintRules::profile(name).ok_or_else(|| LintProfileError::UnknownProfile(String::from(name))),
+        };
+        if depth > table.len() {
+            return Err(LintProfileError::InvalidValue(format!("{}.extends", name)));
+        }
+        let invalid = |key: &str| LintProfileError::InvalidValue(format!("{}.{}", name, key));
+        let mut rules = match profile.get("extends") {
+            Some(toml::Value::String(base)) if base == name => {
+                LintRules::profile(name).ok_or_else(|| LintProfileError::UnknownProfile(base.clone()))?
+            },
+            Some(toml::Value::String(base)) => LintRules::resolve_profile(table, base, depth + 1)?,
+            Some(_) => return Err(invalid("extends")),
+            None => LintRules::profile(name).unwrap_or_default(),
+        };
+        // Limits are whole numbers, or any number for reading speed and frame rate; 0 is no limit
+        let number = |key: &str| -> Result<Option<f64>, LintProfileError> {
+            match profile.get(key) {
+                Some(toml::Value::Integer(n)) if *n >= 0 => Ok(Some(*n as f64)),
+                Some(toml::Value::Float(n)) if *n >= 0.0 => Ok(Some(*n)),
+                Some(_) => Err(invalid(key)),
+                None => Ok(None),
+            }
+        };
+        let limit = |key: &str| -> Result<Option<Option<usize>>, LintProfileError> {
+            match profile.get(key) {
+                Some(toml::Value::Integer(n)) if *n >= 0 => Ok(Some(if *n > 0 { Some(*n as usize) } else { None })),
+                Some(_) => Err(invalid(key)),
+                None => Ok(None),
+            }
+        };
+        for key in profile.keys() {
+            match key.as_str() {
+                "extends" | "fps" | "allow_overlap" | "min_gap" | "close_gap" | "min_gap_frames"
+                | "close_gap_frames" | "min_duration" | "max_duration" | "max_chars_per_second"
+                | "max_line_length" | "max_lines" => {},
+                _ => return Err(LintProfileError::UnknownKey(format!("{}.{}", name, key))),
+            }
+        }
+        match profile.get("allow_overlap") {
+            Some(toml::Value::Boolean(b)) => rules.allow_overlap = *b,
+            Some(_) => return Err(invalid("allow_overlap")),
+            None => {},
+        }
+        if let Some(n) = limit("min_gap")? {
+            rules.min_gap = n;
+        }
+        if let Some(n) = limit("close_gap")? {
+            rules.close_gap = n;
+        }
+        if let Some(n) = limit("min_duration")? {
+            rules.min_duration = n;
+        }
+        if let Some(n) = limit("max_duration")? {
+            rules.max_duration = n;
+        }
+        if let Some(n) = number("max_chars_per_second")? {
+            rules.max_chars_per_second = if n > 0.0 { Some(n) } else { None };
+        }
+        if let Some(n) = limit("max_line_length")? {
+            rules.max_line_length = n;
+        }
+        if let Some(n) = limit("max_lines")? {
+            rules.max_lines = n;
+        }
+        let frames = |key: &str, rules_value: &mut Option<usize>| -> Result<(), LintProfileError> {
+            if let Some(n) = number(key)? {
+                let fps = number("fps")?.filter(|f| *f > 0.0).ok_or_else(|| invalid("fps"))?;
+                *rules_value = if n > 0.0 { Some((n * 1000.0 / fps).round() as usize) } else { None };
+            }
+            Ok(())
+        };
+        frames("min_gap_frames", &mut rules.min_gap)?;
+        frames("close_gap_frames", &mut rules.close_gap)?;
+        Ok(rules)
+    }
+}
+
+/// Error type for reading lint profiles
+#[derive(Debug, Clone, PartialEq)]
+pub enum LintProfileError {
+    InvalidToml(String),
+    UnknownProfile(String),
+    UnknownKey(String),
+    InvalidValue(String),
+}
+
+impl fmt::Display for LintProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LintProfileError::InvalidToml(s) => write!(f, "invalid TOML: {}", s),
+            LintProfileError::UnknownProfile(s) => write!(f, "unknown profile {}", s),
+            LintProfileError::UnknownKey(s) => write!(f, "unknown setting {}", s),
+            LintProfileError::InvalidValue(s) => write!(f, "invalid value for {}", s),
+        }
+    }
+}
+
+impl Error for LintProfileError {}
+
+/// The rules Caption::lint can report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintRule {
+    Overlap,
+    MinGap,
+    CloseGap,
+    MinDuration,
+    MaxDuration,
+    ReadingSpeed,
+    LineLength,
+    LineCount,
+}
+
+impl LintRule {
+    /// Short name of the rule, as used in reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            LintRule::Overlap => "overlap",
+            LintRule::MinGap => "min-gap",
+            LintRule::CloseGap => "close-gap",
+            LintRule::MinDuration => "min-duration",
+            LintRule::MaxDuration => "max-duration",
+            LintRule::ReadingSpeed => "reading-speed",
+            LintRule::LineLength => "line-length",
+            LintRule::LineCount => "line-count",
+        }
+    }
+}
+
+/// A broken rule found by Caption::lint
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintViolation {
+    /// Index of the block breaking the rule
+    pub block: usize,
+    /// Start of the block breaking the rule
+    pub start: SimpleTime,
+    pub rule: LintRule,
+    /// What is wrong, e.g. "shown for 500 ms, under 833 ms"
+    pub message: String,
+}
+
+impl fmt::Display for LintViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cue {} at {}: {}: {}", self.block + 1, self.start, self.rule.name(), self.message)
+    }
+}
+
+/// A change to the timing of a block made by Caption::fix_timing
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingFix {
+    /// Index of the block changed, once the blocks are in order
+    pub block: usize,
+    /// The rule the change was made for
+    pub rule: LintRule,
+    /// Start and end before the change
+    pub before: (SimpleTime, SimpleTime),
+    /// Start and end after the change
+    pub after: (SimpleTime, SimpleTime),
+}
+
+impl fmt::Display for TimingFix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cue {}: {}: {} --> {} is now {} --> {}", self.block + 1, self.rule.name(),
+               self.before.0, self.before.1, self.after.0, self.after.1)
+    }
+}
+
+/// Preferred shape of the lines when reflowing text over several lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineShape {
+    /// Lines as close to the same width as possible
+    Balanced,
+    /// Upper lines shorter than the ones below them, in a pyramid
+    BottomHeavy,
+    /// Upper lines longer than the ones below them
+    TopHeavy,
+}
+
+/// Limits used when re-breaking the lines of caption text
+#[derive(Debug, Clone)]
+pub struct ReflowOptions {
+    /// Maximum width of a line in display columns
+    pub max_line_length: usize,
+    /// Maximum number of lines of text in a block
+    pub max_lines: usize,
+    /// Preferred shape of the lines
+    pub shape: LineShape,
+}
+
+impl Default for ReflowOptions {
+    fn default() -> Self {
+        ReflowOptions {
+            max_line_length: 42,
+            max_lines: 2,
+            shape: LineShape::BottomHeavy,
+        }
+    }
+}
+
+/// Limits used when splitting long cues and merging short ones
+#[derive(Debug, Clone)]
+pub struct CueLengthOptions {
+    /// Longest a cue should be on screen, in milliseconds
+    pub max_duration: usize,
+    /// Most characters of text in a cue, counting one space between words
+    pub max_chars: usize,
+    /// Cues on screen for less than this many milliseconds are merged into a neighbour
+    pub min_duration: usize,
+    /// Longest gap in milliseconds between two cues that can be merged
+    pub max_merge_gap: usize,
+}
+
+impl Default for CueLengthOptions {
+    fn default() -> Self {
+        CueLengthOptions {
+            max_duration: 7000,
+            max_chars: 84,
+            min_duration: 833,
+            max_merge_gap: 500,
+        }
+    }
+}
+
+/// Longest gap in milliseconds between cues for text in the second to repeat the first
+const DEDUPE_MAX_GAP: usize = 1000;
+/// Fewest words that count as a repeat, unless they are all of one cue
+const DEDUPE_MIN_WORDS: usize = 2;
+
+/// Words a line should not end on, as they belong with the word after them
+const REFLOW_CLINGING_WORDS: [&str; 34] = [
+    "a", "an", "the", "my", "your", "his", "her", "its", "our", "their", "this", "these", "those",
+    "and", "but", "or", "nor", "so", "to", "of", "in", "on", "at", "for", "with", "from", "by",
+    "into", "than", "if", "that", "mr", "mrs", "dr",
+];
+/// Words that make a good start for a line, as they begin a clause or phrase
+const REFLOW_LEADING_WORDS: [&str; 22] = [
+    "and", "but", "or", "nor", "so", "yet", "because", "although", "though", "while", "if",
+    "when", "whereas", "unless", "until", "that", "which", "who", "whom", "whose", "where", "since",
+];
+/// Cost of each display column a line runs over the maximum
+const REFLOW_OVERFLOW_COST: usize = 10_000;
+/// Most words reflowed carefully; longer text is filled greedily
+const REFLOW_MAX_WORDS: usize = 200;
+
+/// A find-and-replace rule for caption text, used by Caption::replace
+///
+/// The replacement may refer to capture groups as `$1`, `${1}` or `$name`, as in
+/// `Regex::replace_all`.
+///
+/// # Examples
+/// ```
+/// use ccap::ReplaceRule;
+///
+/// let rules = ReplaceRule::parse_list("# Fix names\nJon\\b => John\n(\\d+) ?pc => $1%\n", false, false).unwrap();
+/// assert_eq!(rules.len(), 2);
+/// assert_eq!(rules[1].apply("up 5 pc"), "up 5%");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReplaceRule {
+    regex: Regex,
+    replacement: String,
+}
+
+impl ReplaceRule {
+    /// Construct a rule, optionally ignoring case or only matching whole words
+    pub fn new(pattern: &str, replacement: &str, case_insensitive: bool, whole_word: bool)
+    -> Result<ReplaceRule, ReplaceError> {
+        let full = if whole_word { format!(r"\b(?:{})\b", pattern) } else { pattern.to_string() };
+        let regex = RegexBuilder::new(&full)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(|e| ReplaceError::InvalidPattern(pattern.to_string(), e.to_string()))?;
+        Ok(ReplaceRule { regex, replacement: replacement.to_string() })
+    }
+    /// Read rules from a file
+    pub fn from_file(fname: &str, case_insensitive: bool, whole_word: bool) -> Result<Vec<ReplaceRule>, Box<dyn Error>> {
+        let s = fs::read_to_string(fname)?;
+        Ok(ReplaceRule::parse_list(&s, case_insensitive, whole_word)?)
+    }
+    /// Parse a list of rules, one `PATTERN => REPLACEMENT` per line.
+    ///
+    /// Space around the pattern and replacement is ignored, and blank lines and lines starting
+    /// with `#` are skipped.
+    pub fn parse_list(contents: &str, case_insensitive: bool, whole_word: bool) -> Result<Vec<ReplaceRule>, ReplaceError> {
+        contents.lines()
+            .filter(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+            .map(|l| ReplaceRule::parse_line(l, case_insensitive, whole_word))
+            .collect()
+    }
+    /// Parse a rule written as `PATTERN => REPLACEMENT`
+    pub fn parse_line(line: &str, case_insensitive: bool, whole_word: bool) -> Result<ReplaceRule, ReplaceError> {
+        match line.find("=>") {
+            Some(n) if !line[..n].trim().is_empty() => {
+                ReplaceRule::new(line[..n].trim(), line[n + 2..].trim(), case_insensitive, whole_word)
+            },
+            _ => Err(ReplaceError::InvalidLine(line.to_string())),
+        }
+    }
+    /// Apply the rule to a string
+    pub fn apply(&self, s: &str) -> String {
+        self.regex.replace_all(s, self.replacement.as_str()).into_owned()
+    }
+}
+
+/// Error type for ReplaceRule
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplaceError {
+    /// The pattern, and why it is not a valid regular expression
+    InvalidPattern(String, String),
+    InvalidLine(String),
+}
+
+impl Error for ReplaceError {}
+
+impl fmt::Display for ReplaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplaceError::InvalidPattern(p, e) => write!(f, "invalid pattern {}: {}", p, e),
+            ReplaceError::InvalidLine(s) => write!(f, "expected PATTERN => REPLACEMENT, found {}", s),
+        }
+    }
+}
+
+/// Which part of a block a replacement applies to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaceTarget {
+    Text,
+    Speaker,
+    Both,
+}
+
+/// Which blocks Caption::replace changes, and which parts of them
+#[derive(Debug, Clone)]
+pub struct ReplaceScope {
+    pub target: ReplaceTarget,
+    /// First and last cue numbers (counting from 1) to change
+    pub cues: Option<(usize, usize)>,
+    /// Only change blocks on screen at or after this time
+    pub from: Option<SimpleTime>,
+    /// Only change blocks on screen before this time
+    pub to: Option<SimpleTime>,
+}
+
+impl Default for ReplaceScope {
+    fn default() -> Self {
+        ReplaceScope {
+            target: ReplaceTarget::Text,
+            cues: None,
+            from: None,
+            to: None,
+        }
+    }
+}
+
+/// A change made by Caption::replace
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replacement {
+    /// Index of the block changed
+    pub block: usize,
+    /// The part of the block changed, either the text or the speaker
+    pub target: ReplaceTarget,
+    pub before: String,
+    pub after: String,
+}
+
+impl fmt::Display for Replacement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let part = match self.target {
+            ReplaceTarget::Speaker => "speaker",
+            _ => "text",
+        };
+        write!(f, "cue {} {}: {:?} -> {:?}", self.block + 1, part, self.before, self.after)
+    }
+}
+
+/// What to look for in the text of a block, used by SearchQuery
+#[derive(Debug, Clone)]
+pub enum SearchPattern {
+    Regex(Regex),
+    /// Words to find in order, each allowed a few typos
+    Fuzzy(Vec<String>),
+}
+
+impl SearchPattern {
+    /// Search with a regular expression
+    pub fn regex(pattern: &str, case_insensitive: bool) -> Result<SearchPattern, regex::Error> {
+        Ok(SearchPattern::Regex(RegexBuilder::new(pattern).case_insensitive(case_insensitive).build()?))
+    }
+    /// Search for words, ignoring case and punctuation and allowing one typo in words of four
+    /// to seven letters and two in longer words
+    pub fn fuzzy(pattern: &str) -> SearchPattern {
+        SearchPattern::Fuzzy(pattern.split_whitespace().map(Caption::normalize_word).collect())
+    }
+    /// Whether some text matches
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::SearchPattern;
+    ///
+    /// let pattern = SearchPattern::fuzzy("the budgit");
+    /// assert!(pattern.is_match("And what about the Budget?"));
+    /// assert!(!pattern.is_match("The bucket list"));
+    /// ```
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            SearchPattern::Regex(r) => r.is_match(text),
+            SearchPattern::Fuzzy(query) => {
+                let words: Vec<String> = text.split_whitespace().map(Caption::normalize_word).collect();
+                query.is_empty() || words.windows(query.len()).any(|window| {
+                    window.iter().zip(query.iter()).all(|(w, q)| {
+                        let allowed = match q.chars().count() {
+                            0..=3 => 0,
+                            4..=7 => 1,
+                            _ => 2,
+                        };
+                        edit_distance(w, q) <= allowed
+                    })
+                })
+            },
+        }
+    }
+}
+
+/// Number of single character insertions, deletions and substitutions to turn one string into
+/// another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Which blocks to find with Caption::search
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub pattern: SearchPattern,
+    /// Only blocks whose speaker contains this, ignoring case
+    pub speaker: Option<String>,
+    /// Only blocks on screen at or after this time
+    pub from: Option<SimpleTime>,
+    /// Only blocks on screen before this time
+    pub to: Option<SimpleTime>,
+}
+
+/// Talk time and number of cues for a speaker, from Caption::speakers
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeakerSummary {
+    pub name: String,
+    /// Number of blocks spoken
+    pub cues: usize,
+    /// Total time on screen in milliseconds
+    pub talk_time: usize,
+}
+
+/// A mapping of speaker names to new names, used by Caption::rename_speakers
+///
+/// Names are matched ignoring case and the space around them.  Mapping several names to the
+/// same name merges them, and mapping a name to nothing removes it.
+///
+/// # Examples
+/// ```
+/// use ccap::SpeakerMap;
+///
+/// let map = SpeakerMap::parse("# The doctor\nDr Smith | Dr. Smith | SMITH => Dr Smith\nCrowd =>\n").unwrap();
+/// assert_eq!(map.get("smith"), Some("Dr Smith"));
+/// assert_eq!(map.get("Crowd"), Some(""));
+/// assert_eq!(map.get("Dr Jones"), None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpeakerMap {
+    names: HashMap<String, String>,
+}
+
+impl SpeakerMap {
+    /// Read a mapping from a file
+    pub fn from_file(fname: &str) -> Result<SpeakerMap, Box<dyn Error>> {
+        let s = fs::read_to_string(fname)?;
+        Ok(SpeakerMap::parse(&s)?)
+    }
+    /// Parse a mapping written as one `OLD => NEW` per line, where OLD may list several names
+    /// separated by `|`.  Blank lines and lines starting with `#` are skipped.
+    pub fn parse(contents: &str) -> Result<SpeakerMap, SpeakerMapError> {
+        let mut map = SpeakerMap::default();
+        for line in contents.lines().filter(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#')) {
+            map.parse_line(line)?;
+        }
+        Ok(map)
+    }
+    /// Add the names from a line written as `OLD => NEW`
+    pub fn parse_line(&mut self, line: &str) -> Result<(), SpeakerMapError> {
+        let invalid = || SpeakerMapError::InvalidLine(line.to_string());
+        let n = line.find("=>").ok_or_else(invalid)?;
+        let to = line[n + 2..].trim();
+        for from in line[..n].split('|') {
+            if from.trim().is_empty() {
+                return Err(invalid());
+            }
+            self.insert(from, to);
+        }
+        Ok(())
+    }
+    /// Map a name to a new name
+    pub fn insert(&mut self, from: &str, to: &str) {
+        self.names.insert(from.trim().to_lowercase(), to.trim().to_string());
+    }
+    /// Look up the new name for a speaker
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.names.get(&name.trim().to_lowercase()).map(|s| s.as_str())
+    }
+}
+
+/// Error type for SpeakerMap
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpeakerMapError {
+    InvalidLine(String),
+}
+
+impl Error for SpeakerMapError {}
+
+impl fmt::Display for SpeakerMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpeakerMapError::InvalidLine(s) => write!(f, "expected OLD => NEW, found {}", s),
+        }
+    }
+}
+
+/// A way of writing a speaker label at the start of caption text, used by
+/// Caption::extract_speakers
+///
+/// # Examples
+/// ```
+/// use ccap::SpeakerPattern;
+///
+/// let dash = SpeakerPattern::preset("dash").unwrap();
+/// assert_eq!(dash.find("- John: Over here!"), Some((String::from("John"), 8)));
+/// let custom = SpeakerPattern::new(r"(?P<speaker>\w+) >>").unwrap();
+/// assert_eq!(custom.find("MARY >> Hello").unwrap().0, "MARY");
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpeakerPattern {
+    regex: Regex,
+}
+
+impl SpeakerPattern {
+    /// Names of the built-in patterns, in the order they are best tried
+    pub const PRESETS: [&'static str; 5] = ["dash", "brackets", "parens", "caps", "colon"];
+    /// Names of the built-in patterns that rarely mistake other text for a speaker, for use
+    /// when none are chosen
+    pub const DEFAULT_PRESETS: [&'static str; 3] = ["dash", "brackets", "caps"];
+
+    /// Get a built-in pattern:
+    /// - `dash`: `- John: text` or `- Dr. Smith: text`, a name of up to three capitalised words
+    /// - `brackets`: `[John] text`
+    /// - `parens`: `(John) text`, where the name starts with a capital letter
+    /// - `caps`: `JOHN: text` or `DR. SMITH: text`
+    /// - `colon`: `John: text` or `Dr Smith: text`, a name of up to three words
+    ///
+    /// Sound descriptions such as `(LAUGHS)` look like speakers to `parens`, and text such as
+    /// `Note: ...` looks like a speaker to `colon`, so these are best used only when a file is
+    /// known to write speakers that way.
+    pub fn preset(name: &str) -> Option<SpeakerPattern> {
+        let pattern = match name {
+            "dash" => r"[-–—]\s*(?P<speaker>\p{Lu}[\p{L}\d.'-]*(?: \p{Lu}[\p{L}\d.'-]*){0,2}):",
+            "brackets" => r"\[(?P<speaker>[^\]\n]{1,40})\]",
+            "parens" => r"\((?P<speaker>\p{Lu}[^)\n]{0,39})\)",
+            "caps" => r"(?P<speaker>\p{Lu}[\p{Lu}\d .'-]{0,39}):",
+            "colon" => r"(?P<speaker>\p{Lu}[\p{L}\d.'-]*(?: [\p{L}\d.'-]+){0,2}):",
+            _ => return None,
+        };
+        Some(SpeakerPattern::new(pattern).unwrap())
+    }
+    /// Construct a pattern from a regular expression, which is matched at the start of the
+    /// text.  The name is the group named `speaker`, or the first group if there is none.
+    pub fn new(pattern: &str) -> Result<SpeakerPattern, SpeakerPatternError> {
+        let regex = Regex::new(&format!(r"^\s*(?:{})\s*", pattern))
+            .map_err(|e| SpeakerPatternError::InvalidPattern(pattern.to_string(), e.to_string()))?;
+        if regex.captures_len() < 2 {
+            return Err(SpeakerPatternError::MissingGroup(pattern.to_string()));
+        }
+        Ok(SpeakerPattern { regex })
+    }
+    /// Find a speaker label at the start of some text, returning the name and the length of
+    /// the label in bytes, including the space after it
+    pub fn find(&self, text: &str) -> Option<(String, usize)> {
+        let captures = self.regex.captures(text)?;
+        let name = captures.name("speaker").or_else(|| captures.get(1))?.as_str().trim();
+        if name.is_empty() {
+            return None;
+        }
+        Some((name.to_string(), captures.get(0).unwrap().end()))
+    }
+}
+
+/// Error type for SpeakerPattern
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpeakerPatternError {
+    /// The pattern, and why it is not a valid regular expression
+    InvalidPattern(String, String),
+    /// The pattern has no group to take the name from
+    MissingGroup(String),
+}
+
+impl Error for SpeakerPatternError {}
+
+impl fmt::Display for SpeakerPatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpeakerPatternError::InvalidPattern(p, e) => write!(f, "invalid pattern {}: {}", p, e),
+            SpeakerPatternError::MissingGroup(p) => write!(f, "pattern {} has no group for the speaker", p),
+        }
+    }
+}
+
+/// Simple Speaker type for ordering tables
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct SimpleSpeaker {
+    name: String,
+    talk_time: usize,
+}
+
+impl SimpleSpeaker {
+    pub fn new(name: String, talk_time: usize) -> SimpleSpeaker {
+        SimpleSpeaker {
+            name,
+            talk_time,
+        }
+    }
+}
+
+/// Type for storing captions
+///
+/// They are generated by parsers, and can be used by generators to write out new caption files.
+#[derive(Debug, Clone)]
+pub struct Caption {
+    pub header: Option<String>,
+    pub blocks: Vec<CaptionBlock>,
+}
+
+impl Caption {
+    /// Construct a Caption from its components
+    pub fn from(header_str: Option<&str>, blocks: Vec<CaptionBlock>) -> Caption {
+        Caption {
+            header: header_str.map(|s| s.to_string()),
+            blocks,
+        }
+    }
+    /// Offset all of the timestamps in this caption
+    pub fn offset_milliseconds(&mut self, n: isize) -> Result<(), NegativeSimpleTime> {
+        for b in self.blocks.iter_mut() {
+            b.offset_milliseconds(n)?;
+        }
+        Ok(())
+    }
+    /// Scale all of the timestamps in this caption by a factor, measured from an anchor time.
+    ///
+    /// Times are rounded to the nearest millisecond, and blocks are never shrunk to nothing.
+    /// This will panic if the factor is not positive.
+    ///
+    /// # Examples
+    /// Undo a PAL speed-up, where 23.976 fps video was played at 25 fps
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime};
+    ///
+    /// let block = CaptionBlock::from(
+    ///     None,
+    ///     SimpleTime::from_milliseconds(60_000),
+    ///     SimpleTime::from_milliseconds(62_000),
+    ///     String::from("Hello!")).unwrap();
+    /// let mut cap = Caption::from(None, vec!(block));
+    /// cap.scale(25.0 / (24000.0 / 1001.0), SimpleTime::from_milliseconds(0)).unwrap();
+    /// assert_eq!(cap.blocks[0].start().to_milliseconds(), 62_563);
+    /// ```
+    pub fn scale(&mut self, factor: f64, anchor: SimpleTime) -> Result<(), NegativeSimpleTime> {
+        if factor <= 0.0 || !factor.is_finite() {
+            panic!("Caption scale requires a positive factor (got {})", factor);
+        }
+        let anchor = anchor.to_milliseconds() as f64;
+        self.map_times(|t| anchor + (t - anchor) * factor)
+    }
+    /// Apply a linear correction to all of the timestamps in this caption
+    pub fn transform(&mut self, transform: &LinearTransform) -> Result<(), NegativeSimpleTime> {
+        self.map_times(|t| transform.apply(t))
+    }
+    /// Correct offset and drift from two anchors, each pairing a cue or caption time with the
+    /// time it should be at in the video.
+    ///
+    /// Returns the correction that was applied.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, SyncAnchor};
+    ///
+    /// let blocks = (0..3).map(|i| CaptionBlock::from(
+    ///     None,
+    ///     SimpleTime::from_milliseconds(i * 10_000),
+    ///     SimpleTime::from_milliseconds(i * 10_000 + 1000),
+    ///     format!("Cue {}", i + 1)).unwrap()).collect();
+    /// let mut cap = Caption::from(None, blocks);
+    /// // The first cue should be at 2s and the third at 22.02s
+    /// let sync = cap.sync(
+    ///     (SyncAnchor::Cue(1), SimpleTime::from_milliseconds(2_000)),
+    ///     (SyncAnchor::Cue(3), SimpleTime::from_milliseconds(22_020)),
+    /// ).unwrap();
+    /// assert_eq!(sync.offset, 2000.0);
+    /// assert_eq!(cap.blocks[1].start().to_milliseconds(), 12_010);
+    /// ```
+    pub fn sync(&mut self, first: (SyncAnchor, SimpleTime), second: (SyncAnchor, SimpleTime))
+    -> Result<LinearTransform, SyncError> {
+        let from_first = self.anchor_time(&first.0)?;
+        let from_second = self.anchor_time(&second.0)?;
+        let transform = LinearTransform::from_anchors((from_first, first.1), (from_second, second.1))?;
+        self.transform(&transform).map_err(|_| SyncError::NegativeTime)?;
+        Ok(transform)
+    }
+    /// Estimate the correction that lines this caption up with a reference caption.
+    ///
+    /// Only the times when cues are on screen are compared, not their text, so a translation can
+    /// be aligned with the original.  The confidence is the time both captions have something on
+    /// screen divided by the time either does.
+    pub fn estimate_alignment(&self, reference: &Caption, opts: &AlignOptions) -> Alignment {
+        let target = reference.active_intervals();
+        let source = self.active_intervals();
+        let identity = LinearTransform { rate: 1.0, offset: 0.0 };
+        if target.is_empty() || source.is_empty() {
+            return Alignment { transform: identity, confidence: 0.0 };
+        }
+        let max_offset = opts.max_offset as f64;
+        let max_drift = if opts.max_drift.is_finite() { opts.max_drift.clamp(0.0, 0.999) } else { 0.0 };
+        let target_total: f64 = target.iter().map(|(s, e)| e - s).sum();
+        // Find the best offset for a rate on a grid around a centre, scoring each by the overlap
+        // over the union so that stretching the caption is not rewarded for its own sake
+        let search = |rate: f64, centre: f64, radius: f64, step: f64| -> (f64, f64) {
+            let scaled: Vec<(f64, f64)> = source.iter().map(|(s, e)| (s * rate, e * rate)).collect();
+            let scaled_total: f64 = scaled.iter().map(|(s, e)| e - s).sum();
+            let mut best = (centre, -1.0);
+            let steps = (radius / step).round() as i64;
+            for i in -steps..=steps {
+                let offset = centre + i as f64 * step;
+                if offset.abs() > max_offset {
+                    continue;
+                }
+                let overlap = Caption::interval_overlap(&target, &scaled, offset);
+                let score = overlap / (target_total + scaled_total - overlap);
+                if score > best.1 {
+                    best = (offset, score);
+                }
+            }
+            best
+        };
+        // Coarse search over rates and offsets, starting with no drift and the usual conversions
+        let mut rates: Vec<f64> = vec!(1.0);
+        if opts.estimate_drift {
+            let pal: f64 = 25.0 / (24000.0 / 1001.0);
+            let film: f64 = 25.0 / 24.0;
+            let ntsc: f64 = 1001.0 / 1000.0;
+            for r in [pal, 1.0 / pal, film, 1.0 / film, ntsc, 1.0 / ntsc].iter() {
+                if (r - 1.0).abs() <= max_drift {
+                    rates.push(*r);
+                }
+            }
+            let steps = (max_drift / 0.001).floor() as i64;
+            for i in -steps..=steps {
+                if i != 0 {
+                    rates.push(1.0 + i as f64 * 0.001);
+                }
+            }
+        }
+        let mut best = (1.0, 0.0, -1.0);
+        for rate in rates.iter() {
+            // Refine each coarse offset a little so that rates are compared fairly
+            let (coarse, _) = search(*rate, 0.0, max_offset, 1000.0);
+            let (offset, score) = search(*rate, coarse, 1000.0, 100.0);
+            if score > best.2 {
+                best = (*rate, offset, score);
+            }
+        }
+        // Settle an offset down to the millisecond
+        let refine = |rate: f64, centre: f64| -> (f64, f64) {
+            let mut best = (centre, -1.0);
+            for (radius, step) in [(1000.0, 100.0), (100.0, 10.0), (10.0, 1.0)].iter() {
+                best = search(rate, best.0, *radius, *step);
+            }
+            best
+        };
+        let (offset, score) = refine(best.0, best.1);
+        best = (best.0, offset, score);
+        // Then narrow down the rate
+        if opts.estimate_drift {
+            for rate_step in [0.0001, 0.00001].iter() {
+                let (centre_rate, centre_offset) = (best.0, best.1);
+                for i in -10..=10 {
+                    let rate = centre_rate + i as f64 * rate_step;
+                    if i == 0 || (rate - 1.0).abs() > max_drift {
+                        continue;
+                    }
+                    let (offset, score) = refine(rate, centre_offset);
+                    if score > best.2 {
+                        best = (rate, offset, score);
+                    }
+                }
+            }
+        }
+        Alignment {
+            transform: LinearTransform { rate: best.0, offset: best.1 },
+            confidence: best.2.max(0.0),
+        }
+    }
+    /// Line this caption up with a reference caption, returning the correction applied
+    pub fn align(&mut self, reference: &Caption, opts: &AlignOptions) -> Result<Alignment, NegativeSimpleTime> {
+        let alignment = self.estimate_alignment(reference, opts);
+        self.transform(&alignment.transform)?;
+        Ok(alignment)
+    }
+    /// Times when any cue is on screen, as sorted and merged intervals in milliseconds
+    fn active_intervals(&self) -> Vec<(f64, f64)> {
+        let mut intervals: Vec<(f64, f64)> = self.blocks.iter()
+            .filter(|b| b.end > b.start)
+            .map(|b| (b.start.to_milliseconds() as f64, b.end.to_milliseconds() as f64))
+            .collect();
+        intervals.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(intervals.len());
+        for (s, e) in intervals.into_iter() {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        merged
+    }
+    /// Total overlap between two sets of sorted, merged intervals, with the second shifted
+    fn interval_overlap(a: &[(f64, f64)], b: &[(f64, f64)], shift: f64) -> f64 {
+        let (mut i, mut j) = (0, 0);
+        let mut total = 0.0;
+        while i < a.len() && j < b.len() {
+            let (bs, be) = (b[j].0 + shift, b[j].1 + shift);
+            let overlap = a[i].1.min(be) - a[i].0.max(bs);
+            if overlap > 0.0 {
+                total += overlap;
+            }
+            if a[i].1 < be {
+                i += 1;
+            }
+            else {
+                j += 1;
+            }
+        }
+        total
+    }
+    /// Find the caption time an anchor refers to
+    fn anchor_time(&self, anchor: &SyncAnchor) -> Result<SimpleTime, SyncError> {
+        match anchor {
+            SyncAnchor::Time(t) => Ok(*t),
+            SyncAnchor::Cue(n) => {
+                match n.checked_sub(1).and_then(|i| self.blocks.get(i)) {
+                    Some(b) => Ok(b.start),
+                    None => Err(SyncError::CueOutOfRange(*n)),
+                }
+            },
+        }
+    }
+    /// Move every time through a mapping of milliseconds, leaving the caption untouched on failure
+    fn map_times<F: Fn(f64) -> f64>(&mut self, f: F) -> Result<(), NegativeSimpleTime> {
+        let mut mapped = self.blocks.clone();
+        for b in mapped.iter_mut() {
+            b.map_times(&f)?;
+        }
+        self.blocks = mapped;
+        Ok(())
+    }
+    /// Check every block against a set of quality rules, returning what breaks them in caption
+    /// order.  Overlaps and gaps are reported on the later of the two blocks.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, LintRules, LintRule};
+    ///
+    /// let blocks = vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(500), String::from("Quick!")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(1000),
+    ///         SimpleTime::from_milliseconds(3000), String::from("That's better.")).unwrap(),
+    /// );
+    /// let cap = Caption::from(None, blocks);
+    /// let violations = cap.lint(&LintRules::default());
+    /// assert_eq!(violations.len(), 1);
+    /// assert_eq!(violations[0].rule, LintRule::MinDuration);
+    /// assert_eq!(violations[0].to_string(), "cue 1 at 00:00:00.000: min-duration: shown for 500 ms, under 833 ms");
+    /// ```
+    pub fn lint(&self, rules: &LintRules) -> Vec<LintViolation> {
+        let mut violations = Vec::new();
+        let mut previous: Option<&CaptionBlock> = None;
+        for (n, b) in self.blocks.iter().enumerate() {
+            let mut report = |rule: LintRule, message: String| {
+                violations.push(LintViolation { block: n, start: b.start, rule, message });
+            };
+            if let Some(p) = previous {
+                let (prev_end, start) = (p.end.to_milliseconds(), b.start.to_milliseconds());
+                if start < prev_end {
+                    if !rules.allow_overlap {
+                        report(LintRule::Overlap, format!("overlaps the previous cue by {} ms", prev_end - start));
+                    }
+                }
+                else {
+                    let gap = start - prev_end;
+                    let min_gap = rules.min_gap.unwrap_or(0);
+                    if gap > 0 && gap < min_gap {
+                        report(LintRule::MinGap, format!("gap of {} ms after the previous cue, under {} ms", gap, min_gap));
+                    }
+                    else if rules.close_gap.is_some_and(|close| gap > min_gap && gap < close) {
+                        report(LintRule::CloseGap, format!("gap of {} ms after the previous cue should be closed to {} ms", gap, min_gap));
+                    }
+                }
+            }
+            let length = b.length_millis();
+            if let Some(min_duration) = rules.min_duration {
+                if length < min_duration {
+                    report(LintRule::MinDuration, format!("shown for {} ms, under {} ms", length, min_duration));
+                }
+            }
+            if let Some(max_duration) = rules.max_duration {
+                if length > max_duration {
+                    report(LintRule::MaxDuration, format!("shown for {} ms, over {} ms", length, max_duration));
+                }
+            }
+            let text = b.text();
+            let lines: Vec<&str> = text.lines().collect();
+            if let Some(max_cps) = rules.max_chars_per_second {
+                let chars: usize = lines.iter().map(|l| l.chars().count()).sum();
+                if length > 0 {
+                    let cps = chars as f64 * 1000.0 / length as f64;
+                    if cps > max_cps {
+                        report(LintRule::ReadingSpeed, format!("{:.1} characters per second, over {}", cps, max_cps));
+                    }
+                }
+            }
+            if let Some(max_line_length) = rules.max_line_length {
+                for (l, line) in lines.iter().enumerate() {
+                    let chars = line.chars().count();
+                    if chars > max_line_length {
+                        report(LintRule::LineLength, format!("line {} has {} characters, over {}", l + 1, chars, max_line_length));
+                    }
+                }
+            }
+            if let Some(max_lines) = rules.max_lines {
+                if lines.len() > max_lines {
+                    report(LintRule::LineCount, format!("{} lines, over {}", lines.len(), max_lines));
+                }
+            }
+            // Compare with whichever earlier cue ends last, so long cues are not forgotten
+            if previous.map_or(true, |p| b.end > p.end) {
+                previous = Some(b);
+            }
+        }
+        violations
+    }
+    /// Fix the timing of the blocks to follow a set of rules, returning each change made.
+    ///
+    /// The blocks are put in order of start time first.  Then, working through each block in
+    /// turn, this:
+    /// - clamps it to the maximum duration;
+    /// - ends it before the next block starts if they overlap, leaving out the minimum gap if
+    ///   there is no room for it; blocks that start together are left overlapping, as neither
+    ///   can be moved without putting it out of sync;
+    /// - moves its end so there is exactly the minimum gap before the next block, if the gap is
+    ///   shorter than that or shorter than `close_gap` (chaining the blocks);
+    /// - extends it to the minimum duration as far as the gaps to its neighbours allow, first
+    ///   later and then earlier.
+    ///
+    /// Text and reading speed rules are left alone.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, LintRules, LintRule};
+    ///
+    /// let blocks = vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1500), String::from("Overlapping")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(1000),
+    ///         SimpleTime::from_milliseconds(3000), String::from("Me too")).unwrap(),
+    /// );
+    /// let mut cap = Caption::from(None, blocks);
+    /// let fixes = cap.fix_timing(&LintRules::default());
+    /// assert_eq!(fixes.len(), 1);
+    /// assert_eq!(fixes[0].rule, LintRule::Overlap);
+    /// assert_eq!(cap.blocks[0].end().to_milliseconds(), 917);
+    /// ```
+    pub fn fix_timing(&mut self, rules: &LintRules) -> Vec<TimingFix> {
+        self.blocks.sort_by_key(|b| b.start.to_milliseconds());
+        let mut times: Vec<(usize, usize)> = self.blocks.iter()
+            .map(|b| (b.start.to_milliseconds(), b.end.to_milliseconds()))
+            .collect();
+        let mut fixes = Vec::new();
+        let min_gap = rules.min_gap.unwrap_or(0);
+        let mut change = |times: &mut Vec<(usize, usize)>, block: usize, rule: LintRule, new: (usize, usize)| {
+            let old = times[block];
+            if old != new {
+                fixes.push(TimingFix {
+                    block,
+                    rule,
+                    before: (SimpleTime::from_milliseconds(old.0), SimpleTime::from_milliseconds(old.1)),
+                    after: (SimpleTime::from_milliseconds(new.0), SimpleTime::from_milliseconds(new.1)),
+                });
+                times[block] = new;
+            }
+        };
+        for n in 0..times.len() {
+            if let Some(max) = rules.max_duration {
+                let (start, end) = times[n];
+                change(&mut times, n, LintRule::MaxDuration, (start, end.min(start + max)));
+            }
+            if n + 1 < times.len() {
+                let (start, end) = times[n];
+                let next_start = times[n + 1].0;
+                if !rules.allow_overlap && next_start < end {
+                    // Without room for the gap, end the block as the next starts
+                    let new_end = match next_start.saturating_sub(min_gap) {
+                        e if e > start => e,
+                        _ => next_start,
+                    };
+                    if new_end > start {
+                        change(&mut times, n, LintRule::Overlap, (start, new_end));
+                    }
+                }
+                let (start, end) = times[n];
+                let next_start = times[n + 1].0;
+                if next_start >= end {
+                    let gap = next_start - end;
+                    let closes = rules.close_gap.is_some_and(|close| gap < close);
+                    if gap > 0 && gap != min_gap && (gap < min_gap || closes) && next_start > start + min_gap {
+                        let rule = if gap < min_gap { LintRule::MinGap } else { LintRule::CloseGap };
+                        change(&mut times, n, rule, (start, next_start - min_gap));
+                    }
+                }
+            }
+            if let Some(min) = rules.min_duration {
+                let (start, end) = times[n];
+                if end - start < min {
+                    // Room up to the next block and back to the previous one
+                    let latest = match times.get(n + 1) {
+                        Some(_) if rules.allow_overlap => usize::MAX,
+                        Some((next_start, _)) => next_start.saturating_sub(min_gap).max(end),
+                        None => usize::MAX,
+                    };
+                    let latest = rules.max_duration.map_or(latest, |max| latest.min(start + max));
+                    let new_end = (start + min).min(latest);
+                    let earliest = match n {
+                        0 => 0,
+                        _ if rules.allow_overlap => 0,
+                        _ => times[n - 1].1 + min_gap,
+                    };
+                    let new_start = new_end.saturating_sub(min).max(earliest).min(start);
+                    change(&mut times, n, LintRule::MinDuration, (new_start, new_end));
+                }
+            }
+        }
+        for (b, (start, end)) in self.blocks.iter_mut().zip(times) {
+            b.start = SimpleTime::from_milliseconds(start);
+            b.end = SimpleTime::from_milliseconds(end);
+            b.clamp_words();
+        }
+        fixes
+    }
+    /// Re-break the text of every block to fit the line limits, returning the indices of the
+    /// blocks that still do not fit (too long a word, or too much text for the lines).
+    ///
+    /// See StyledText::reflow for how lines are chosen.
+    pub fn reflow(&mut self, opts: &ReflowOptions) -> Vec<usize> {
+        let mut overflowing = Vec::new();
+        for (i, b) in self.blocks.iter_mut().enumerate() {
+            if !b.text.reflow(opts) {
+                overflowing.push(i);
+            }
+        }
+        overflowing
+    }
+    /// Split every block that is on screen too long or has too much text, returning how many
+    /// blocks were split.
+    ///
+    /// A block is split in two as near its middle as it can be, preferring the end of a
+    /// sentence, then the end of a clause, then any space between words, and the pieces are
+    /// split again until they fit.  Time is shared out in proportion to the characters in each
+    /// piece, unless the block has word timings, in which case each piece starts with its
+    /// first word.  A single word is never split.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, CueLengthOptions};
+    ///
+    /// let block = CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///     SimpleTime::from_milliseconds(8000),
+    ///     String::from("We waited. Nobody came, so we left for the harbour")).unwrap();
+    /// let mut cap = Caption::from(None, vec!(block));
+    /// assert_eq!(cap.split_long_cues(&CueLengthOptions::default()), 1);
+    /// assert_eq!(cap.blocks[0].text(), "We waited. Nobody came,");
+    /// assert_eq!(cap.blocks[0].end().to_milliseconds(), 3680);
+    /// assert_eq!(cap.blocks[1].text(), "so we left for the harbour");
+    /// ```
+    pub fn split_long_cues(&mut self, opts: &CueLengthOptions) -> usize {
+        let mut split = 0;
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+        for b in self.blocks.drain(..) {
+            let before = blocks.len();
+            Caption::split_block(b, opts, &mut blocks);
+            if blocks.len() > before + 1 {
+                split += 1;
+            }
+        }
+        self.blocks = blocks;
+        split
+    }
+    /// Length of text as it would be shown on one line
+    fn text_length(plain: &str) -> usize {
+        let words: Vec<&str> = plain.split_whitespace().collect();
+        words.iter().map(|w| w.chars().count()).sum::<usize>() + words.len().saturating_sub(1)
+    }
+    fn split_block(b: CaptionBlock, opts: &CueLengthOptions, out: &mut Vec<CaptionBlock>) {
+        let plain = b.text.to_plain();
+        let total = Caption::text_length(&plain);
+        if b.length_millis() <= opts.max_duration && total <= opts.max_chars {
+            out.push(b);
+            return;
+        }
+        // Find the spaces between words, along with how good a place to split each one is
+        let mut best: Option<(f64, usize, usize)> = None;
+        let mut run: Option<usize> = None;
+        for (i, c) in plain.char_indices() {
+            match (c.is_whitespace(), run) {
+                (true, None) if i > 0 => run = Some(i),
+                (false, Some(from)) => {
+                    let before = plain[..from].trim_end_matches(|c: char| "\"')]»”’".contains(c));
+                    let level = if before.ends_with(|c: char| ".?!…".contains(c)) {
+                        0.0
+                    }
+                    else if before.ends_with(|c: char| ",;:–—".contains(c)) {
+                        1.0
+                    }
+                    else {
+                        2.0
+                    };
+                    let left = Caption::text_length(&plain[..from]) as f64;
+                    let score = (left - total as f64 / 2.0).abs() / total as f64 + level * 0.2;
+                    if best.map_or(true, |(s, _, _)| score < s) {
+                        best = Some((score, from, i));
+                    }
+                    run = None;
+                },
+                _ => {},
+            }
+        }
+        let (from, to) = match best {
+            Some((_, from, to)) => (from, to),
+            None => {
+                out.push(b);
+                return;
+            },
+        };
+        let (start, end) = (b.start.to_milliseconds(), b.end.to_milliseconds());
+        let first_words = plain[..from].split_whitespace().count();
+        let timed = b.words.len() == plain.split_whitespace().count();
+        let at = if timed {
+            b.words[first_words].start.to_milliseconds()
+        }
+        else {
+            let share = Caption::text_length(&plain[..from]) as f64 / total as f64;
+            start + ((end - start) as f64 * share).round() as usize
+        };
+        if at <= start || at >= end {
+            out.push(b);
+            return;
+        }
+        let (left_text, rest) = b.text.split_at(from);
+        let (_, right_text) = rest.split_at(to - from);
+        let mut left = b.clone();
+        left.text = left_text;
+        left.end = SimpleTime::from_milliseconds(at);
+        let mut right = b;
+        right.text = right_text;
+        right.start = SimpleTime::from_milliseconds(at);
+        if timed {
+            left.words.truncate(first_words);
+            right.words.drain(..first_words);
+        }
+        left.clamp_words();
+        right.clamp_words();
+        Caption::split_block(left, opts, out);
+        Caption::split_block(right, opts, out);
+    }
+    /// Merge blocks on screen too briefly into the block before or after them, returning how
+    /// many merges were made.
+    ///
+    /// Two neighbouring blocks are merged when either is shorter than the minimum duration,
+    /// they have the same speaker, the gap between them is no longer than the maximum merge
+    /// gap and the merged block is within both the duration and character limits.  The text is
+    /// joined with a space, so it may need reflowing afterwards.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, CueLengthOptions};
+    ///
+    /// let mut cap = Caption::from(None, vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(200), String::from("Yes.")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(300),
+    ///         SimpleTime::from_milliseconds(2000), String::from("I agree.")).unwrap(),
+    /// ));
+    /// assert_eq!(cap.merge_short_cues(&CueLengthOptions::default()), 1);
+    /// assert_eq!(cap.blocks.len(), 1);
+    /// assert_eq!(cap.blocks[0].text(), "Yes. I agree.");
+    /// ```
+    pub fn merge_short_cues(&mut self, opts: &CueLengthOptions) -> usize {
+        let mut merged = 0;
+        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(self.blocks.len());
+        for b in self.blocks.drain(..) {
+            if let Some(prev) = blocks.last_mut() {
+                let short = prev.length_millis() < opts.min_duration || b.length_millis() < opts.min_duration;
+                let close = b.start >= prev.end
+                    && b.start.to_milliseconds() - prev.end.to_milliseconds() <= opts.max_merge_gap;
+                let fits = b.end.to_milliseconds().saturating_sub(prev.start.to_milliseconds()) <= opts.max_duration
+                    && Caption::text_length(&prev.text.to_plain()) + 1 + Caption::text_length(&b.text.to_plain())
+                        <= opts.max_chars;
+                if short && close && fits && prev.speaker == b.speaker {
+                    prev.end = b.end;
+                    prev.text = prev.text.join(&b.text);
+                    prev.words.extend(b.words);
+                    merged += 1;
+                    continue;
+                }
+            }
+            blocks.push(b);
+        }
+        self.blocks = blocks;
+        merged
+    }
+    /// Remove text repeated from one block to the next, as in rolling (roll-up) captions,
+    /// returning how many blocks had repeats removed.
+    ///
+    /// Automatic captions from services such as YouTube and Zoom scroll, so each line appears
+    /// in two or more consecutive blocks.  When the words a block starts with are the words the
+    /// block before it ended with, they are removed, along with the blocks left empty, giving
+    /// pop-on captions that show each word once.  The earlier block then ends when the next
+    /// starts, so the blocks no longer overlap.  A repeat must be at least two words long,
+    /// unless it is the whole of either block, and the blocks must have the same speaker and be
+    /// within a second of each other.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime};
+    ///
+    /// let mut cap = Caption::from(None, vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(2000), String::from("hello everyone and\nwelcome to the show")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(2000),
+    ///         SimpleTime::from_milliseconds(2010), String::from("welcome to the show")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(2010),
+    ///         SimpleTime::from_milliseconds(4000), String::from("welcome to the show\ntoday we have")).unwrap(),
+    /// ));
+    /// assert_eq!(cap.dedupe(), 2);
+    /// assert_eq!(cap.blocks.len(), 2);
+    /// assert_eq!(cap.blocks[0].end().to_milliseconds(), 2010);
+    /// assert_eq!(cap.blocks[1].text(), "today we have");
+    /// ```
+    pub fn dedupe(&mut self) -> usize {
+        let mut deduped = 0;
+        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(self.blocks.len());
+        // Words of the last block as it was before its repeat was removed
+        let mut shown: Vec<String> = Vec::new();
+        for mut b in self.blocks.drain(..) {
+            let plain = b.text.to_plain();
+            let words: Vec<String> = plain.split_whitespace().map(Caption::normalize_word).collect();
+            let repeat = match blocks.last() {
+                Some(prev) if prev.speaker == b.speaker
+                    && b.start.to_milliseconds() <= prev.end.to_milliseconds() + DEDUPE_MAX_GAP => {
+                    Caption::repeated_words(&shown, &words)
+                },
+                _ => 0,
+            };
+            shown = words;
+            if repeat == 0 {
+                blocks.push(b);
+                continue;
+            }
+            deduped += 1;
+            let prev = blocks.last_mut().unwrap();
+            if repeat == shown.len() {
+                // Nothing new, so the text before stays up instead
+                if b.end > prev.end {
+                    prev.end = b.end;
+                }
+                continue;
+            }
+            let at = plain.split_whitespace()
+                .nth(repeat)
+                .map(|w| w.as_ptr() as usize - plain.as_ptr() as usize)
+                .unwrap();
+            b.text = b.text.split_at(at).1;
+            let mut skip = repeat;
+            b.words.retain_mut(|w| {
+                let n = w.text.split_whitespace().count();
+                if skip >= n {
+                    skip -= n;
+                    return false;
+                }
+                if skip > 0 {
+                    w.text = w.text.split_whitespace().skip(skip).collect::<Vec<&str>>().join(" ");
+                    skip = 0;
+                }
+                true
+            });
+            if prev.end > b.start && b.start > prev.start {
+                prev.end = b.start;
+                prev.clamp_words();
+            }
+            blocks.push(b);
+        }
+        self.blocks = blocks;
+        deduped
+    }
+    /// Count the words at the start of a block that repeat the end of the one before
+    fn repeated_words(previous: &[String], current: &[String]) -> usize {
+        let longest = previous.len().min(current.len());
+        (1..=longest).rev()
+            .find(|k| previous[previous.len() - k..] == current[..*k])
+            .filter(|k| *k >= DEDUPE_MIN_WORDS || *k == previous.len() || *k == current.len())
+            .unwrap_or(0)
+    }
+    /// Apply find-and-replace rules in turn to the blocks in scope, returning each change made.
+    ///
+    /// Rules are applied to each stretch of text with the same styling, so the styling is kept
+    /// but a match cannot span a change of style.  Word timings are kept when the rules change
+    /// the words the same way, and dropped otherwise.  A speaker replaced with nothing is
+    /// removed.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, ReplaceRule, ReplaceScope, ReplaceTarget};
+    ///
+    /// let block = CaptionBlock::from(Some(String::from("DR SMITH")), SimpleTime::from_milliseconds(0),
+    ///     SimpleTime::from_milliseconds(2000), String::from("Colour me surprised, colour me")).unwrap();
+    /// let mut cap = Caption::from(None, vec!(block));
+    /// let rules = vec!(
+    ///     ReplaceRule::new("colour", "color", true, true).unwrap(),
+    ///     ReplaceRule::new("^DR (.*)$", "Dr. $1", false, false).unwrap(),
+    /// );
+    /// let scope = ReplaceScope { target: ReplaceTarget::Both, ..ReplaceScope::default() };
+    /// let changes = cap.replace(&rules, &scope);
+    /// assert_eq!(changes.len(), 2);
+    /// assert_eq!(cap.blocks[0].text(), "color me surprised, color me");
+    /// assert_eq!(cap.blocks[0].speaker(), Some(String::from("Dr. SMITH")));
+    /// ```
+    pub fn replace(&mut self, rules: &[ReplaceRule], scope: &ReplaceScope) -> Vec<Replacement> {
+        let apply = |s: &str| rules.iter().fold(s.to_string(), |s, r| r.apply(&s));
+        let mut changes = Vec::new();
+        for (i, b) in self.blocks.iter_mut().enumerate() {
+            if let Some((first, last)) = scope.cues {
+                if i + 1 < first || i + 1 > last {
+                    continue;
+                }
+            }
+            if scope.from.is_some_and(|from| b.end <= from) || scope.to.is_some_and(|to| b.start >= to) {
+                continue;
+            }
+            if scope.target != ReplaceTarget::Speaker {
+                let before = b.text.to_plain();
+                let text = b.text.map_text(&apply);
+                let after = text.to_plain();
+                if after != before {
+                    let words: Vec<TimedWord> = b.words.iter()
+                        .map(|w| TimedWord { text: apply(&w.text), ..w.clone() })
+                        .filter(|w| !w.text.trim().is_empty())
+                        .collect();
+                    let same = words.iter().flat_map(|w| w.text.split_whitespace())
+                        .eq(after.split_whitespace());
+                    b.words = if same { words } else { Vec::new() };
+                    b.text = text;
+                    changes.push(Replacement { block: i, target: ReplaceTarget::Text, before, after });
+                }
+            }
+            if scope.target != ReplaceTarget::Text {
+                if let Some(before) = b.speaker.clone() {
+                    let after = apply(&before);
+                    if after != before {
+                        b.speaker = if after.trim().is_empty() { None } else { Some(after.clone()) };
+                        changes.push(Replacement { block: i, target: ReplaceTarget::Speaker, before, after });
+                    }
+                }
+            }
+        }
+        changes
+    }
+    /// Find the blocks matching a query, returning their indices.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, SearchPattern, SearchQuery};
+    ///
+    /// let cap = Caption::from(None, vec!(
+    ///     CaptionBlock::from(Some(String::from("Host")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("What about the budget?")).unwrap(),
+    ///     CaptionBlock::from(Some(String::from("Guest")), SimpleTime::from_milliseconds(1000),
+    ///         SimpleTime::from_milliseconds(2000), String::from("The budget is fine.")).unwrap(),
+    /// ));
+    /// let query = SearchQuery {
+    ///     pattern: SearchPattern::regex("budget", false).unwrap(),
+    ///     speaker: Some(String::from("guest")),
+    ///     from: None,
+    ///     to: None,
+    /// };
+    /// assert_eq!(cap.search(&query), vec!(1));
+    /// ```
+    pub fn search(&self, query: &SearchQuery) -> Vec<usize> {
+        let speaker = query.speaker.as_ref().map(|s| s.to_lowercase());
+        self.blocks.iter().enumerate()
+            .filter(|(_, b)| match &speaker {
+                Some(s) => b.speaker.as_ref().is_some_and(|name| name.to_lowercase().contains(s.as_str())),
+                None => true,
+            })
+            .filter(|(_, b)| query.from.map_or(true, |from| b.end > from))
+            .filter(|(_, b)| query.to.map_or(true, |to| b.start < to))
+            .filter(|(_, b)| query.pattern.is_match(&b.text.to_plain()))
+            .map(|(i, _)| i)
+            .collect()
+    }
+    /// List the speakers in order of first appearance, with how many blocks each speaks and
+    /// for how long.  Blocks without a speaker are left out.
+    pub fn speakers(&self) -> Vec<SpeakerSummary> {
+        let mut speakers: Vec<SpeakerSummary> = Vec::new();
+        for b in self.blocks.iter() {
+            if let Some(name) = &b.speaker {
+                match speakers.iter_mut().find(|s| s.name == *name) {
+                    Some(s) => {
+                        s.cues += 1;
+                        s.talk_time += b.length_millis();
+                    },
+                    None => speakers.push(SpeakerSummary {
+                        name: name.clone(),
+                        cues: 1,
+                        talk_time: b.length_millis(),
+                    }),
+                }
+            }
+        }
+        speakers
+    }
+    /// Rename speakers, including in VTT voice spans, returning the number of blocks changed.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, SpeakerMap};
+    ///
+    /// let mut cap = Caption::from(None, vec!(
+    ///     CaptionBlock::from(Some(String::from("Dr. Smith")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap(),
+    ///     CaptionBlock::from(Some(String::from("SMITH")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap(),
+    ///     CaptionBlock::from(Some(String::from("Ann")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap(),
+    /// ));
+    /// let map = SpeakerMap::parse("Dr. Smith | Smith => Dr Smith").unwrap();
+    /// assert_eq!(cap.rename_speakers(&map), 2);
+    /// assert_eq!(cap.speakers().len(), 2);
+    /// assert_eq!(cap.speakers()[0].cues, 2);
+    /// ```
+    pub fn rename_speakers(&mut self, map: &SpeakerMap) -> usize {
+        let mut changed = 0;
+        for b in self.blocks.iter_mut() {
+            let text = b.text.map_voices(&|name: &str| map.get(name).map(String::from));
+            let speaker = match b.speaker.as_deref().and_then(|s| map.get(s)) {
+                Some("") => None,
+                Some(name) => Some(name.to_string()),
+                None => b.speaker.clone(),
+            };
+            if speaker != b.speaker || text != b.text {
+                b.speaker = speaker;
+                b.text = text;
+                changed += 1;
+            }
+        }
+        changed
+    }
+    /// Replace each speaker's name with a label and a number, such as `Speaker 1`, numbered in
+    /// order of first appearance, returning the mapping used.
+    ///
+    /// Names in VTT voice spans are replaced too, but names mentioned in the text are not.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime};
+    ///
+    /// let mut cap = Caption::from(None, vec!(
+    ///     CaptionBlock::from(Some(String::from("Ann")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap(),
+    ///     CaptionBlock::from(Some(String::from("Bob")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap(),
+    ///     CaptionBlock::from(Some(String::from("Ann")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap(),
+    /// ));
+    /// let key = cap.anonymise_speakers("Speaker");
+    /// assert_eq!(key[1], (String::from("Bob"), String::from("Speaker 2")));
+    /// assert_eq!(cap.blocks[2].speaker(), Some(String::from("Speaker 1")));
+    /// ```
+    pub fn anonymise_speakers(&mut self, label: &str) -> Vec<(String, String)> {
+        let mut key: Vec<(String, String)> = Vec::new();
+        let mut map = SpeakerMap::default();
+        for b in self.blocks.iter() {
+            for name in b.speaker.iter().cloned().chain(b.text.voices()) {
+                if map.get(&name).is_none() {
+                    let anonymous = format!("{} {}", label, key.len() + 1);
+                    map.insert(&name, &anonymous);
+                    key.push((name, anonymous));
+                }
+            }
+        }
+        self.rename_speakers(&map);
+        key
+    }
+    /// Move speaker labels written at the start of the text of blocks into their speaker,
+    /// returning how many blocks were changed.
+    ///
+    /// The patterns are tried in turn on each block without a speaker, and the first label
+    /// found is removed from the text.  Only a label at the very start of a block is moved, so
+    /// in a block with lines from two speakers the second label stays in the text.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, SpeakerPattern};
+    ///
+    /// let mut cap = Caption::from(None, vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("JOHN: Over here!")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("[Mary] Coming")).unwrap(),
+    /// ));
+    /// let patterns: Vec<SpeakerPattern> = SpeakerPattern::DEFAULT_PRESETS.iter()
+    ///     .map(|name| SpeakerPattern::preset(name).unwrap())
+    ///     .collect();
+    /// assert_eq!(cap.extract_speakers(&patterns), 2);
+    /// assert_eq!(cap.blocks[0].speaker(), Some(String::from("JOHN")));
+    /// assert_eq!(cap.blocks[0].text(), "Over here!");
+    /// assert_eq!(cap.blocks[1].speaker(), Some(String::from("Mary")));
+    /// ```
+    pub fn extract_speakers(&mut self, patterns: &[SpeakerPattern]) -> usize {
+        let mut changed = 0;
+        for b in self.blocks.iter_mut().filter(|b| b.speaker.is_none()) {
+            let plain = b.text.to_plain();
+            if let Some((name, length)) = patterns.iter().find_map(|p| p.find(&plain)) {
+                b.text = b.text.split_at(length).1;
+                b.speaker = Some(name);
+                changed += 1;
+            }
+        }
+        changed
+    }
+    /// Write the speaker of each block into its text, for formats without speakers, returning
+    /// how many blocks were changed.
+    ///
+    /// The label is made from a template, where `{speaker}` is replaced by the name and
+    /// `{SPEAKER}` by the name in capitals.  The speaker is then removed from the block.  If
+    /// `changes_only` is set, a label is only written when the speaker changes.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime};
+    ///
+    /// let mut cap = Caption::from(None, vec!(
+    ///     CaptionBlock::from(Some(String::from("John")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hi")).unwrap(),
+    ///     CaptionBlock::from(Some(String::from("John")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("there")).unwrap(),
+    ///     CaptionBlock::from(Some(String::from("Mary")), SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap(),
+    /// ));
+    /// assert_eq!(cap.render_speakers("- {SPEAKER}: ", true), 3);
+    /// let texts: Vec<String> = cap.blocks.iter().map(|b| b.text()).collect();
+    /// assert_eq!(texts, vec!("- JOHN: Hi", "there", "- MARY: Hello"));
+    /// assert_eq!(cap.blocks[0].speaker(), None);
+    /// ```
+    pub fn render_speakers(&mut self, template: &str, changes_only: bool) -> usize {
+        let mut changed = 0;
+        let mut previous: Option<String> = None;
+        for b in self.blocks.iter_mut() {
+            let speaker = b.speaker.take();
+            if let Some(name) = &speaker {
+                if !changes_only || previous.as_ref() != Some(name) {
+                    let label = template.replace("{speaker}", name).replace("{SPEAKER}", &name.to_uppercase());
+                    let mut spans = vec!(TextSpan::Text(label));
+                    spans.extend(b.text.spans.iter().cloned());
+                    b.text = StyledText::from_spans(spans);
+                }
+                changed += 1;
+            }
+            previous = speaker;
+        }
+        changed
+    }
+    /// Index the cues for quick lookups of what is on screen when
+    pub fn index(&self) -> CueIndex<'_> {
+        CueIndex::new(self)
+    }
+    /// Get the first time in milliseconds from a caption
+    pub fn time_head(&self) -> usize {
         self.blocks[0].start.to_milliseconds()
     }
     /// Get the last time in milliseconds from a caption
@@ -1043,6 +5749,236 @@ impl Caption {
         self.blocks = self.blocks[pos_start..pos_end + 1].to_vec();
         self.offset_milliseconds(0 - start_time.to_milliseconds() as isize).unwrap();
     }
+    /// Remove time ranges from the caption, closing the gaps they leave.
+    ///
+    /// Blocks that fall entirely inside a removed range are dropped, blocks that overlap one are
+    /// trimmed, and everything after a removed range moves earlier by its length.  Blocks with
+    /// no length are only dropped if they are strictly inside a range.  The text of a trimmed
+    /// block is kept as it is, but timed words inside a removed range are dropped.  Ranges may
+    /// be given in any order and may overlap.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime};
+    ///
+    /// let blocks = vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Before the break")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(2000),
+    ///         SimpleTime::from_milliseconds(3000), String::from("Buy now!")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(5000),
+    ///         SimpleTime::from_milliseconds(6000), String::from("After the break")).unwrap(),
+    /// );
+    /// let mut cap = Caption::from(None, blocks);
+    /// cap.cut(&[(SimpleTime::from_milliseconds(1500), SimpleTime::from_milliseconds(4500))]);
+    /// assert_eq!(cap.blocks.len(), 2);
+    /// assert_eq!(cap.blocks[1].start().to_milliseconds(), 2000);
+    /// ```
+    pub fn cut(&mut self, ranges: &[(SimpleTime, SimpleTime)]) {
+        // Sort the ranges and merge any that overlap
+        let mut cuts: Vec<(usize, usize)> = ranges.iter()
+            .map(|(a, b)| {
+                let (a, b) = (a.to_milliseconds(), b.to_milliseconds());
+                if a <= b { (a, b) } else { (b, a) }
+            })
+            .filter(|(a, b)| a < b)
+            .collect();
+        cuts.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(cuts.len());
+        for (a, b) in cuts {
+            match merged.last_mut() {
+                Some(last) if a <= last.1 => last.1 = last.1.max(b),
+                _ => merged.push((a, b)),
+            }
+        }
+        // Where a time ends up once the ranges are removed
+        let map = |t: usize| -> usize {
+            let mut removed = 0;
+            for (a, b) in merged.iter() {
+                if t >= *b {
+                    removed += b - a;
+                }
+                else if t > *a {
+                    removed += t - a;
+                }
+            }
+            t - removed
+        };
+        // Whether a span of time is cut away completely; an instant must be inside a range
+        let cut_away = |start: usize, end: usize| -> bool {
+            if start == end {
+                merged.iter().any(|(a, b)| *a < start && start < *b)
+            }
+            else {
+                map(end) <= map(start)
+            }
+        };
+        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(self.blocks.len());
+        for b in self.blocks.iter() {
+            if cut_away(b.start.to_milliseconds(), b.end.to_milliseconds()) {
+                continue;
+            }
+            let start = map(b.start.to_milliseconds());
+            let end = map(b.end.to_milliseconds());
+            let mut cb = b.clone();
+            cb.start = SimpleTime::from_milliseconds(start);
+            cb.end = SimpleTime::from_milliseconds(end);
+            cb.words = b.words.iter()
+                .filter(|w| !cut_away(w.start.to_milliseconds(), w.end.to_milliseconds()))
+                .map(|w| TimedWord {
+                    text: w.text.clone(),
+                    start: SimpleTime::from_milliseconds(map(w.start.to_milliseconds())),
+                    end: SimpleTime::from_milliseconds(map(w.end.to_milliseconds())),
+                })
+                .collect();
+            blocks.push(cb);
+        }
+        self.blocks = blocks;
+    }
+    /// Insert gaps of the given lengths in milliseconds at the given times.
+    ///
+    /// Blocks after each insertion point move later by the length of the gap.  A block that is on
+    /// screen at an insertion point is either split in two around the gap or ended at the gap,
+    /// depending on `straddle`.  Timed words go with the part of the block they start in.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, Straddle};
+    ///
+    /// let blocks = vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(2000), String::from("We'll be right back")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(3000),
+    ///         SimpleTime::from_milliseconds(4000), String::from("Welcome back")).unwrap(),
+    /// );
+    /// let mut cap = Caption::from(None, blocks);
+    /// cap.insert_gaps(&[(SimpleTime::from_milliseconds(1000), 30_000)], Straddle::Split);
+    /// assert_eq!(cap.blocks.len(), 3);
+    /// assert_eq!(cap.blocks[0].end().to_milliseconds(), 1000);
+    /// assert_eq!(cap.blocks[1].start().to_milliseconds(), 31_000);
+    /// assert_eq!(cap.blocks[2].start().to_milliseconds(), 33_000);
+    /// ```
+    pub fn insert_gaps(&mut self, gaps: &[(SimpleTime, usize)], straddle: Straddle) {
+        // Sort the insertion points and combine gaps at the same time
+        let mut points: Vec<(usize, usize)> = gaps.iter()
+            .filter(|(_, length)| *length > 0)
+            .map(|(t, length)| (t.to_milliseconds(), *length))
+            .collect();
+        points.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(points.len());
+        for (t, length) in points {
+            match merged.last_mut() {
+                Some(last) if last.0 == t => last.1 += length,
+                _ => merged.push((t, length)),
+            }
+        }
+        // Where a time ends up; times at an insertion point move after the gap if they start
+        // something and stay before it if they end something.  Something of zero length at an
+        // insertion point moves after the gap as a whole, so it never ends before it starts.
+        let map = |t: usize, starts: bool| -> SimpleTime {
+            let added: usize = merged.iter()
+                .filter(|(p, _)| *p < t || (starts && *p == t))
+                .map(|(_, length)| length)
+                .sum();
+            SimpleTime::from_milliseconds(t + added)
+        };
+        let mut blocks: Vec<CaptionBlock> = Vec::with_capacity(self.blocks.len());
+        for b in self.blocks.iter() {
+            let (start, end) = (b.start.to_milliseconds(), b.end.to_milliseconds());
+            let mut bounds: Vec<usize> = vec!(start);
+            bounds.extend(merged.iter().map(|(p, _)| *p).filter(|p| *p > start && *p < end));
+            bounds.push(end);
+            let pieces = match straddle {
+                Straddle::Split => bounds.len() - 1,
+                Straddle::Before => 1,
+            };
+            for piece in bounds.windows(2).take(pieces) {
+                let mut cb = b.clone();
+                cb.start = map(piece[0], true);
+                cb.end = map(piece[1], piece[0] == piece[1]);
+                cb.words = b.words.iter()
+                    .filter(|w| {
+                        let t = w.start.to_milliseconds();
+                        t >= piece[0] && (t < piece[1] || piece[0] == piece[1])
+                    })
+                    .map(|w| {
+                        let (start, end) = (w.start.to_milliseconds(), w.end.to_milliseconds().min(piece[1]));
+                        TimedWord {
+                            text: w.text.clone(),
+                            start: map(start, true),
+                            end: map(end, start == end),
+                        }
+                    })
+                    .collect();
+                blocks.push(cb);
+            }
+        }
+        self.blocks = blocks;
+    }
+    /// Get the times a caption would be split at by Caption::split, in order
+    pub fn split_times(&self, points: &[SplitPoint]) -> Result<Vec<SimpleTime>, SplitError> {
+        let mut times = points.iter()
+            .map(|p| match p {
+                SplitPoint::Time(t) => Ok(*t),
+                SplitPoint::Cue(n) => match self.blocks.get(n.wrapping_sub(1)) {
+                    Some(b) => Ok(b.start),
+                    None => Err(SplitError::CueOutOfRange(*n)),
+                },
+            })
+            .collect::<Result<Vec<SimpleTime>, SplitError>>()?;
+        times.sort_unstable_by_key(|t| t.to_milliseconds());
+        Ok(times)
+    }
+    /// Split the caption into consecutive parts, each rebased to start at zero.
+    ///
+    /// A caption split at n points gives n + 1 parts; points may be given in any order, and a
+    /// part may be empty.  Splitting at a cue starts a new part when that cue starts.  Like
+    /// Caption::crop, a block on screen at a split point is trimmed, but its remainder starts
+    /// the next part rather than being lost.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, SplitPoint};
+    ///
+    /// let blocks = vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(2000), String::from("End of episode one")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(3000),
+    ///         SimpleTime::from_milliseconds(4000), String::from("Episode two")).unwrap(),
+    /// );
+    /// let cap = Caption::from(None, blocks);
+    /// let parts = cap.split(&[SplitPoint::Time(SimpleTime::from_milliseconds(1500))]).unwrap();
+    /// assert_eq!(parts.len(), 2);
+    /// assert_eq!(parts[1].blocks[0].end().to_milliseconds(), 500);
+    /// assert_eq!(parts[1].blocks[1].start().to_milliseconds(), 1500);
+    /// ```
+    pub fn split(&self, points: &[SplitPoint]) -> Result<Vec<Caption>, SplitError> {
+        let mut bounds = vec!(0);
+        bounds.extend(self.split_times(points)?.iter().map(|t| t.to_milliseconds()));
+        let mut parts = Vec::with_capacity(bounds.len());
+        for (n, from) in bounds.iter().enumerate() {
+            let to = bounds.get(n + 1).cloned();
+            let mut blocks = Vec::new();
+            for b in self.blocks.iter() {
+                let (start, end) = (b.start.to_milliseconds(), b.end.to_milliseconds());
+                // Blocks starting exactly at a split point belong to the later part
+                let inside = start >= *from && to.map_or(true, |to| start < to);
+                let overlaps = end > *from && start < *from;
+                if !inside && !overlaps {
+                    continue;
+                }
+                let mut cb = b.clone();
+                cb.set_start(SimpleTime::from_milliseconds(start.max(*from))).unwrap();
+                if let Some(to) = to {
+                    cb.set_end(SimpleTime::from_milliseconds(end.min(to))).unwrap();
+                }
+                cb.offset_milliseconds(-(*from as isize)).unwrap();
+                blocks.push(cb);
+            }
+            parts.push(Caption { header: self.header.clone(), blocks });
+        }
+        Ok(parts)
+    }
     /// Print a summary report of this caption
     pub fn print_report(&self) {
         let mut map  = HashMap::new();
@@ -1062,7 +5998,7 @@ impl Caption {
                     }
                 },
                 None => {
-                    let x = map.get_mut(&"UNKNOWN".to_string()).unwrap();
+                    let x = map.get_mut("UNKNOWN").unwrap();
                     *x += talk_time;
                 },
             }
@@ -1083,7 +6019,7 @@ impl Caption {
                 let t = time.to_owned();
                 speakers.push(SimpleSpeaker::new(speaker.to_string(), t));
             }
-            speakers.sort_by(|a, b| b.talk_time.cmp(&a.talk_time));
+            speakers.sort_by_key(|s| std::cmp::Reverse(s.talk_time));
             println!("{}", (0..35).map(|_| "-").collect::<String>());
             println!("{:30} | % ", "Speaker");
             println!("{}", (0..35).map(|_| "-").collect::<String>());
@@ -1096,29 +6032,332 @@ impl Caption {
             }
         }
     }
+    /// Replace the text of this caption with a revised transcript, keeping the block timings.
+    ///
+    /// The transcript is aligned with the caption word by word, ignoring case and punctuation.
+    /// Matching words go back into the block they came from, and changed words are shared out
+    /// among the blocks of the words they replace, taking the styling of the words they replace.
+    /// Blocks left without any words are removed.
+    /// Changes of more than a few words in a row, or that fall between two blocks, are reported
+    /// as uncertain so they can be checked by hand.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime};
+    ///
+    /// let blocks = vec!(
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///         SimpleTime::from_milliseconds(1000), String::from("Teh quick brown")).unwrap(),
+    ///     CaptionBlock::from(None, SimpleTime::from_milliseconds(1000),
+    ///         SimpleTime::from_milliseconds(2000), String::from("fox jumsp.")).unwrap(),
+    /// );
+    /// let mut cap = Caption::from(None, blocks);
+    /// let uncertain = cap.apply_transcript("The quick brown fox jumps.");
+    /// assert!(uncertain.is_empty());
+    /// assert_eq!(cap.blocks[0].text(), "The quick brown");
+    /// assert_eq!(cap.blocks[1].text(), "fox jumps.");
+    /// ```
+    pub fn apply_transcript(&mut self, transcript: &str) -> Vec<UncertainRegion> {
+        // Original words, with their block and whether a line break follows them
+        let mut original: Vec<(String, usize, bool)> = Vec::new();
+        for (i, b) in self.blocks.iter().enumerate() {
+            for line in b.text().lines() {
+                for w in line.split_whitespace() {
+                    original.push((w.to_string(), i, false));
+                }
+                if let Some(last) = original.last_mut() {
+                    last.2 = last.1 == i;
+                }
+            }
+            if let Some(last) = original.last_mut() {
+                // No line break after the last word of a block
+                if last.1 == i {
+                    last.2 = false;
+                }
+            }
+        }
+        let revised: Vec<&str> = transcript.split_whitespace().collect();
+        let diff = Caption::diff_words(
+            &original.iter().map(|(w, _, _)| Caption::normalize_word(w)).collect::<Vec<String>>(),
+            &revised.iter().map(|w| Caption::normalize_word(w)).collect::<Vec<String>>(),
+        );
+
+        // Revised words standing in for each original word, with line breaks
+        let mut new_words: Vec<Vec<(String, bool)>> = vec!(Vec::new(); original.len());
+        let mut uncertain: Vec<UncertainRegion> = Vec::new();
+        let mut previous_match: Option<usize> = None;
+        let mut k = 0;
+        while k < diff.len() {
+            if let WordDiff::Match(i, j) = diff[k] {
+                new_words[i].push((revised[j].to_string(), original[i].2));
+                previous_match = Some(i);
+                k += 1;
+                continue;
+            }
+            // Gather a run of changes between matches
+            let mut deleted: Vec<usize> = Vec::new();
+            let mut inserted: Vec<usize> = Vec::new();
+            while k < diff.len() {
+                match diff[k] {
+                    WordDiff::Delete(i) => deleted.push(i),
+                    WordDiff::Insert(j) => inserted.push(j),
+                    WordDiff::Match(_, _) => break,
+                }
+                k += 1;
+            }
+            let next_match = match diff.get(k) {
+                Some(WordDiff::Match(i, _)) => Some(*i),
+                _ => None,
+            };
+            // Words the replacement words can stand in for
+            let slots: Vec<usize> = if !deleted.is_empty() {
+                deleted.clone()
+            }
+            else {
+                previous_match.or(next_match).into_iter().collect()
+            };
+            let targets: Vec<usize> = slots.iter().map(|i| original[*i].1).collect();
+            for (n, j) in inserted.iter().enumerate() {
+                let slot = n * slots.len() / inserted.len();
+                let line_break = !deleted.is_empty() && original[deleted[slot]].2
+                    && (n + 1) * slots.len() / inserted.len() > slot;
+                if let Some(i) = slots.get(slot) {
+                    new_words[*i].push((revised[*j].to_string(), line_break));
+                }
+            }
+            // Decide whether this run needs checking
+            let mut blocks_touched: Vec<usize> = targets.clone();
+            if deleted.is_empty() {
+                if let (Some(p), Some(n)) = (previous_match, next_match) {
+                    blocks_touched = vec!(original[p].1, original[n].1);
+                }
+            }
+            let first_block = blocks_touched.iter().min().cloned();
+            let last_block = blocks_touched.iter().max().cloned();
+            let spans_blocks = first_block != last_block;
+            let long_run = deleted.len().max(inserted.len()) > TRANSCRIPT_UNCERTAIN_RUN;
+            if let (Some(first_block), Some(last_block)) = (first_block, last_block) {
+                if spans_blocks || long_run || targets.is_empty() {
+                    uncertain.push(UncertainRegion {
+                        first_block,
+                        last_block,
+                        original: deleted.iter().map(|i| original[*i].0.as_str()).collect::<Vec<&str>>().join(" "),
+                        revised: inserted.iter().map(|j| revised[*j]).collect::<Vec<&str>>().join(" "),
+                    });
+                }
+            }
+        }
+
+        // Put the words back into their blocks, leaving unchanged blocks alone
+        let mut by_block: Vec<Vec<&Vec<(String, bool)>>> = vec!(Vec::new(); self.blocks.len());
+        for ((_, block, _), words) in original.iter().zip(new_words.iter()) {
+            by_block[*block].push(words);
+        }
+        let mut keep: Vec<bool> = Vec::with_capacity(self.blocks.len());
+        for (b, replacements) in self.blocks.iter_mut().zip(by_block) {
+            let words: Vec<&(String, bool)> = replacements.iter().flat_map(|w| w.iter()).collect();
+            let mut text = String::new();
+            for (n, (w, line_break)) in words.iter().enumerate() {
+                text.push_str(w);
+                if n + 1 < words.len() {
+                    text.push(if *line_break { '\n' } else { ' ' });
+                }
+            }
+            keep.push(!text.is_empty());
+            if text != b.text() {
+                let by_word: Vec<Vec<String>> = replacements.iter()
+                    .map(|w| w.iter().map(|(w, _)| w.clone()).collect())
+                    .collect();
+                let breaks: Vec<bool> = words.iter().map(|(_, line_break)| *line_break).collect();
+                b.text = b.text.replace_words(&by_word, &breaks);
+                // Word timings no longer describe the text
+                b.words.clear();
+            }
+        }
+        let mut n = 0;
+        self.blocks.retain(|_| {
+            n += 1;
+            keep[n - 1]
+        });
+        uncertain
+    }
+    /// Reduce a word to lower case letters and digits for comparison
+    fn normalize_word(w: &str) -> String {
+        let stripped: String = w.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(|c| c.to_lowercase())
+            .collect();
+        if stripped.is_empty() { w.to_string() } else { stripped }
+    }
+    /// Find the longest common subsequence of two lists of words.
+    ///
+    /// Only a band around the diagonal is searched, which keeps long transcripts manageable as
+    /// long as the two lists stay roughly in step.
+    fn diff_words(a: &[String], b: &[String]) -> Vec<WordDiff> {
+        let (n, m) = (a.len(), b.len());
+        if n == 0 || m == 0 {
+            return (0..n).map(WordDiff::Delete).chain((0..m).map(WordDiff::Insert)).collect();
+        }
+        let width = 2 * TRANSCRIPT_DIFF_BAND + 1;
+        // Column of row i that sits at band position zero
+        let band_start = |i: usize| (i * m / n).saturating_sub(TRANSCRIPT_DIFF_BAND);
+        // Scores and the move that led to each cell: 0 diagonal, 1 up (delete), 2 left (insert)
+        let mut score: Vec<Vec<u32>> = vec!(vec!(0; width); n + 1);
+        let mut moves: Vec<Vec<u8>> = vec!(vec!(0; width); n + 1);
+        let get = |score: &Vec<Vec<u32>>, i: usize, j: usize| -> Option<u32> {
+            if i == 0 || j == 0 {
+                return Some(0);
+            }
+            let s = band_start(i);
+            if j < s || j - s >= width { None } else { Some(score[i][j - s]) }
+        };
+        for i in 1..=n {
+            let s = band_start(i);
+            for j in s.max(1)..(s + width).min(m + 1) {
+                let diagonal = if a[i - 1] == b[j - 1] {
+                    get(&score, i - 1, j - 1).map(|v| v + 1)
+                } else {
+                    None
+                };
+                let up = get(&score, i - 1, j);
+                let left = get(&score, i, j - 1);
+                let (value, mv) = match (diagonal, up, left) {
+                    (Some(d), u, l) if d >= u.unwrap_or(0) && d >= l.unwrap_or(0) => (d, 0),
+                    (_, Some(u), l) if u >= l.unwrap_or(0) => (u, 1),
+                    (_, _, Some(l)) => (l, 2),
+                    (_, Some(u), None) => (u, 1),
+                    _ => (0, 1),
+                };
+                score[i][j - s] = value;
+                moves[i][j - s] = mv;
+            }
+        }
+        // Walk back from the end, stepping outside the band along its edge if need be
+        let mut steps: Vec<WordDiff> = Vec::with_capacity(n + m);
+        let (mut i, mut j) = (n, m);
+        while i > 0 || j > 0 {
+            if i == 0 {
+                j -= 1;
+                steps.push(WordDiff::Insert(j));
+                continue;
+            }
+            if j == 0 {
+                i -= 1;
+                steps.push(WordDiff::Delete(i));
+                continue;
+            }
+            let s = band_start(i);
+            if j < s {
+                i -= 1;
+                steps.push(WordDiff::Delete(i));
+                continue;
+            }
+            if j - s >= width {
+                j -= 1;
+                steps.push(WordDiff::Insert(j));
+                continue;
+            }
+            match moves[i][j - s] {
+                0 => {
+                    i -= 1;
+                    j -= 1;
+                    steps.push(WordDiff::Match(i, j));
+                },
+                1 => {
+                    i -= 1;
+                    steps.push(WordDiff::Delete(i));
+                },
+                _ => {
+                    j -= 1;
+                    steps.push(WordDiff::Insert(j));
+                },
+            }
+        }
+        steps.reverse();
+        steps
+    }
     /// Concatenate captions and generate new one; does not retain header information.
     /// This is because concatenating headers doesn't necessarily make sense.
+    ///
+    /// Each caption starts at the last cue of the one before; use Caption::concatenate_with
+    /// when the videos run on past their last cues.
     pub fn concatenate(captions: Vec<Caption>) -> Caption {
+        Caption::concatenate_with(captions, &[], &ConcatenateOptions::default())
+            .expect("Something logically impossible has occured")
+    }
+    /// Concatenate captions, placing each one according to its timing.
+    ///
+    /// Timings are matched with captions in order; captions without one start where the previous
+    /// caption ended, after the gap in `opts`.  A caption ends at the end of its duration if one
+    /// is given, otherwise at its last cue.
+    ///
+    /// # Examples
+    /// ```
+    /// use ccap::{Caption, CaptionBlock, SimpleTime, SegmentTiming, ConcatenateOptions};
+    ///
+    /// let hello = CaptionBlock::from(None, SimpleTime::from_milliseconds(0),
+    ///     SimpleTime::from_milliseconds(1000), String::from("Hello")).unwrap();
+    /// let captions = vec!(Caption::from(None, vec!(hello.clone())), Caption::from(None, vec!(hello)));
+    /// // The first video has a minute of credits after its last cue
+    /// let timings = [SegmentTiming { duration: Some(60_000), ..SegmentTiming::default() }];
+    /// let opts = ConcatenateOptions { gap: 500, ..ConcatenateOptions::default() };
+    /// let cap = Caption::concatenate_with(captions, &timings, &opts).unwrap();
+    /// assert_eq!(cap.blocks[1].start().to_milliseconds(), 60_500);
+    /// ```
+    pub fn concatenate_with(captions: Vec<Caption>, timings: &[SegmentTiming], opts: &ConcatenateOptions)
+    -> Result<Caption, ConcatenateError> {
         let total_blocks = captions.iter()
-            .map(|c| c.blocks.iter().count())
+            .map(|c| c.blocks.len())
             .sum();
         let mut cb: Vec<CaptionBlock> = Vec::with_capacity(total_blocks);
-        let mut last_ts: usize = 0;
-        for cap in captions.iter() {
+        let mut headers: Vec<String> = Vec::new();
+        // End of the previous caption, and of its last cue
+        let mut last_ts: Option<usize> = None;
+        let mut last_cue: usize = 0;
+        for (n, cap) in captions.iter().enumerate() {
+            let timing = timings.get(n).cloned().unwrap_or_default();
+            let tail = cap.blocks.last().map_or(0, |b| b.end.to_milliseconds());
+            let head = cap.blocks.first().map_or(tail, |b| b.start.to_milliseconds());
+            let length = match timing.duration {
+                Some(d) if d < tail => return Err(ConcatenateError::DurationTooShort(n + 1)),
+                Some(d) => d,
+                None => tail,
+            };
+            let start = match (timing.start, last_ts) {
+                (Some(t), _) => Some(t.to_milliseconds()),
+                (None, Some(t)) => t.checked_add(opts.gap),
+                (None, None) => Some(0),
+            };
+            // Every cue ends by the end of the caption, so checking that covers them all
+            let (start, end) = match start.and_then(|s| Some((s, s.checked_add(length)?))) {
+                Some((s, e)) if s <= isize::MAX as usize => (s, e),
+                _ => return Err(ConcatenateError::TooLate(n + 1)),
+            };
+            if !cap.blocks.is_empty() && start + head < last_cue {
+                return Err(ConcatenateError::Overlap(n + 1));
+            }
             for b in cap.blocks.iter() {
                 let mut copy_b = b.clone();
                 // Add on the time from the previous caption
-                copy_b.offset_milliseconds(last_ts as isize)
+                copy_b.offset_milliseconds(start as isize)
                     .expect("Something logically impossible has occured");
                 cb.push(copy_b);
             }
+            if opts.merge_headers {
+                if let Some(h) = &cap.header {
+                    if !headers.contains(h) {
+                        headers.push(h.clone());
+                    }
+                }
+            }
             // Update the last time
-            last_ts += cap.time_tail();
+            last_ts = Some(end);
+            last_cue = last_cue.max(start + tail);
         }
-        Caption {
-            header: None,
+        Ok(Caption {
+            header: if headers.is_empty() { None } else { Some(headers.join("\n")) },
             blocks: cb,
-        }
+        })
     }
 }
 
@@ -1151,6 +6390,7 @@ mod test {
             };
         }
         #[test]
+        #[allow(clippy::bool_assert_comparison)]
         fn partial_eq() {
             let a = super::SimpleTime::from_milliseconds(250);
             let b = super::SimpleTime::from_milliseconds(500);
@@ -1160,6 +6400,68 @@ mod test {
             assert_eq!(a == b, false);
         }
     }
+    mod timecode {
+        use super::*;
+        #[test]
+        fn non_drop_frame() {
+            let tc = Timecode::parse("01:00:00:12", FrameRate::Fps25).expect("Valid timecode");
+            assert_eq!(tc.to_frames(), 90_012);
+            assert_eq!(tc.to_simple_time().to_milliseconds(), 3_600_480);
+            assert_eq!(Timecode::from_frames(90_012, FrameRate::Fps25), tc);
+            let tc = Timecode::parse("00:00:01:00", FrameRate::Fps23_976).expect("Valid timecode");
+            assert_eq!(tc.to_simple_time().to_milliseconds(), 1001);
+        }
+        #[test]
+        fn drop_frame_round_trip() {
+            let rate = FrameRate::Fps29_97Df;
+            assert_eq!(Timecode::from_frames(1799, rate).to_string(), "00:00:59;29");
+            assert_eq!(Timecode::from_frames(1800, rate).to_string(), "00:01:00;02");
+            assert_eq!(Timecode::from_frames(17_982, rate).to_string(), "00:10:00;00");
+            assert_eq!(Timecode::from_frames(107_892, rate).to_string(), "01:00:00;00");
+            for n in (0..200_000).step_by(7) {
+                assert_eq!(Timecode::from_frames(n, rate).to_frames(), n);
+            }
+            let rate = FrameRate::Fps59_94Df;
+            assert_eq!(Timecode::from_frames(3600, rate).to_string(), "00:01:00;04");
+        }
+        #[test]
+        fn semicolon_selects_drop_frame() {
+            let tc = Timecode::parse("01:00:00;00", FrameRate::Fps29_97Ndf).expect("Valid timecode");
+            assert_eq!(tc.rate(), FrameRate::Fps29_97Df);
+            // An hour of drop-frame timecode is an hour of clock time, give or take a frame
+            assert_eq!(tc.to_simple_time().to_milliseconds(), 3_599_996);
+        }
+        #[test]
+        fn invalid_timecodes() {
+            assert!(matches!(
+                Timecode::parse("00:01:00;01", FrameRate::Fps29_97Df),
+                Err(TimecodeError::DroppedFrame(_))
+            ));
+            assert!(matches!(
+                Timecode::parse("00:00:00:25", FrameRate::Fps25),
+                Err(TimecodeError::InvalidTimecode(_))
+            ));
+            // Semicolons are only for the frames of drop-frame timecode
+            assert!(matches!(
+                Timecode::parse("00:00:01;00", FrameRate::Fps25),
+                Err(TimecodeError::InvalidTimecode(_))
+            ));
+            assert!(matches!(
+                Timecode::parse("00;00:01:00", FrameRate::Fps29_97Ndf),
+                Err(TimecodeError::InvalidTimecode(_))
+            ));
+            assert!(!Timecode::is_timecode("00:00:01.000"));
+        }
+        #[test]
+        fn from_simple_time_rounds() {
+            let t = SimpleTime::from_milliseconds(1_019);
+            let tc = Timecode::from_simple_time(&t, FrameRate::Fps25);
+            assert_eq!(tc.to_string(), "00:00:01:00");
+            let t = SimpleTime::from_milliseconds(1_021);
+            let tc = Timecode::from_simple_time(&t, FrameRate::Fps25);
+            assert_eq!(tc.to_string(), "00:00:01:01");
+        }
+    }
     mod caption {
         use super::*;
         #[test]
@@ -1170,7 +6472,8 @@ mod test {
                     speaker: None,
                     start: SimpleTime::from_milliseconds(0),
                     end: SimpleTime::from_milliseconds(1000),
-                    text: "John Dies at the End".to_string(),
+                    text: StyledText::from("John Dies at the End"),
+                    words: Vec::new(),
                 })
             };
             c.offset_milliseconds(500).expect("Should be fine");
@@ -1185,31 +6488,36 @@ mod test {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(0),
                         end: SimpleTime::from_milliseconds(1000),
-                        text: "John Dies at the End".to_string(),
+                        text: StyledText::from("John Dies at the End"),
+                        words: Vec::new(),
                     },
                     CaptionBlock {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(1500),
                         end: SimpleTime::from_milliseconds(2000),
-                        text: "a".to_string(),
+                        text: StyledText::from("a"),
+                        words: Vec::new(),
                     },
                     CaptionBlock {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(2500),
                         end: SimpleTime::from_milliseconds(3000),
-                        text: "b".to_string(),
+                        text: StyledText::from("b"),
+                        words: Vec::new(),
                     },
                     CaptionBlock {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(3500),
                         end: SimpleTime::from_milliseconds(4000),
-                        text: "a".to_string(),
+                        text: StyledText::from("a"),
+                        words: Vec::new(),
                     },
                     CaptionBlock {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(4500),
                         end: SimpleTime::from_milliseconds(5000),
-                        text: "b".to_string(),
+                        text: StyledText::from("b"),
+                        words: Vec::new(),
                     },
                 )
             }
@@ -1291,7 +6599,8 @@ mod test {
                     speaker: None,
                     start: SimpleTime::from_milliseconds(0),
                     end: SimpleTime::from_milliseconds(1000),
-                    text: "John Dies at the End".to_string(),
+                    text: StyledText::from("John Dies at the End"),
+                    words: Vec::new(),
                 })
             };
             let c2 = Caption {
@@ -1300,7 +6609,8 @@ mod test {
                     speaker: None,
                     start: SimpleTime::from_milliseconds(0),
                     end: SimpleTime::from_milliseconds(1000),
-                    text: "John is dead now".to_string(),
+                    text: StyledText::from("John is dead now"),
+                    words: Vec::new(),
                 })
             };
             let c3 = Caption {
@@ -1310,13 +6620,15 @@ mod test {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(0),
                         end: SimpleTime::from_milliseconds(1000),
-                        text: "Go read the book!".to_string(),
+                        text: StyledText::from("Go read the book!"),
+                        words: Vec::new(),
                     },
                     CaptionBlock {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(1200),
                         end: SimpleTime::from_milliseconds(2400),
-                        text: "Seriously.".to_string(),
+                        text: StyledText::from("Seriously."),
+                        words: Vec::new(),
                     },
                 ),
             };
@@ -1336,7 +6648,8 @@ mod test {
                 speaker: None,
                 start: SimpleTime::from_milliseconds(1500),
                 end: SimpleTime::from_milliseconds(2250),
-                text: "Blanky McBlankface".to_string(),
+                text: StyledText::from("Blanky McBlankface"),
+                words: Vec::new(),
             }
         }
         #[test]
@@ -1370,6 +6683,1171 @@ mod test {
                 Err(NegativeSimpleTime),
             ));
         }
+        fn toy_timed_caption() -> Caption {
+            let mut cb = toy_cb();
+            cb.set_words(vec!(
+                TimedWord::from("Blanky", SimpleTime::from_milliseconds(1500), SimpleTime::from_milliseconds(1800)),
+                TimedWord::from("McBlankface", SimpleTime::from_milliseconds(1800), SimpleTime::from_milliseconds(2250)),
+            )).expect("Words are in bounds");
+            Caption::from(None, vec!(cb))
+        }
+        #[test]
+        fn set_words_out_of_bounds() {
+            let mut cb = toy_cb();
+            let r = cb.set_words(vec!(
+                TimedWord::from("Blanky", SimpleTime::from_milliseconds(1000), SimpleTime::from_milliseconds(1800)),
+            ));
+            assert!(matches!(r, Err(CaptionBlockError::WordOutOfBounds(_, _))));
+            assert!(cb.words().is_empty());
+        }
+        #[test]
+        fn offset_and_crop_keep_words() {
+            let mut c = toy_timed_caption();
+            c.offset_milliseconds(-500).expect("Should be fine");
+            assert_eq!(c.blocks[0].words[1].start.to_milliseconds(), 1300);
+            c.crop(Some(SimpleTime::from_milliseconds(1400)), None);
+            let words = c.blocks[0].words();
+            assert_eq!(words.len(), 1);
+            assert_eq!(words[0].text(), "McBlankface");
+            assert_eq!(words[0].start().to_milliseconds(), 0);
+            assert_eq!(words[0].end().to_milliseconds(), 350);
+        }
+        #[test]
+        fn scale_around_anchor() {
+            let mut c = toy_caption();
+            c.scale(0.5, SimpleTime::from_milliseconds(1000)).expect("Should be fine");
+            assert_eq!(c.blocks[0].start.to_milliseconds(), 500);
+            assert_eq!(c.blocks[0].end.to_milliseconds(), 1000);
+            assert_eq!(c.blocks[1].start.to_milliseconds(), 1250);
+            assert_eq!(c.blocks[4].end.to_milliseconds(), 3000);
+            // Times well before the anchor would become negative
+            let r = c.scale(2.0, SimpleTime::from_milliseconds(5000));
+            assert!(matches!(r, Err(NegativeSimpleTime)));
+            assert_eq!(c.blocks[1].start.to_milliseconds(), 1250);
+        }
+        #[test]
+        fn scale_keeps_blocks_and_words() {
+            let mut c = toy_timed_caption();
+            c.scale(0.0004, SimpleTime::from_milliseconds(0)).expect("Should be fine");
+            assert_eq!(c.blocks[0].start.to_milliseconds(), 1);
+            assert_eq!(c.blocks[0].end.to_milliseconds(), 2);
+            let mut c = toy_timed_caption();
+            c.scale(1.0 / 3.0, SimpleTime::from_milliseconds(0)).expect("Should be fine");
+            assert_eq!(c.blocks[0].start.to_milliseconds(), 500);
+            assert_eq!(c.blocks[0].end.to_milliseconds(), 750);
+            assert_eq!(c.blocks[0].words[1].start.to_milliseconds(), 600);
+        }
+        #[test]
+        fn sync_two_points() {
+            let mut c = toy_caption();
+            // Caption runs 1% slow and starts half a second early
+            let t = c.sync(
+                (SyncAnchor::Time(SimpleTime::from_milliseconds(0)), SimpleTime::from_milliseconds(500)),
+                (SyncAnchor::Cue(5), SimpleTime::from_milliseconds(5045)),
+            ).expect("Should be fine");
+            assert_eq!(t.offset, 500.0);
+            assert!((t.rate - 1.01).abs() < 1e-9);
+            assert_eq!(c.blocks[0].start.to_milliseconds(), 500);
+            assert_eq!(c.blocks[2].start.to_milliseconds(), 3025);
+            assert_eq!(c.blocks[4].end.to_milliseconds(), 5550);
+        }
+        #[test]
+        fn sync_invalid_anchors() {
+            let mut c = toy_caption();
+            let t0 = SimpleTime::from_milliseconds(0);
+            let t1 = SimpleTime::from_milliseconds(1000);
+            assert!(matches!(
+                c.sync((SyncAnchor::Cue(1), t0), (SyncAnchor::Time(t0), t1)),
+                Err(SyncError::AnchorsCoincide)
+            ));
+            assert!(matches!(
+                c.sync((SyncAnchor::Cue(1), t1), (SyncAnchor::Cue(2), t0)),
+                Err(SyncError::AnchorsReversed)
+            ));
+            assert!(matches!(
+                c.sync((SyncAnchor::Cue(0), t0), (SyncAnchor::Cue(6), t1)),
+                Err(SyncError::CueOutOfRange(0))
+            ));
+            assert_eq!(c.blocks[1].start.to_milliseconds(), 1500);
+        }
+        /// A block from its speaker, start and end in milliseconds, and text
+        fn block(speaker: Option<&str>, start: usize, end: usize, text: &str) -> CaptionBlock {
+            CaptionBlock::from(
+                speaker.map(String::from),
+                SimpleTime::from_milliseconds(start),
+                SimpleTime::from_milliseconds(end),
+                text.to_string()
+            ).unwrap()
+        }
+        fn irregular_caption() -> Caption {
+            // Cues of uneven length and spacing, from a simple linear congruential generator
+            let mut seed: usize = 12345;
+            let mut next = |m: usize| {
+                seed = (seed * 1103515245 + 12345) % 2147483648;
+                seed % m
+            };
+            let mut t = 2000;
+            let mut blocks = Vec::new();
+            for i in 0..120 {
+                let start = t + next(3000);
+                let end = start + 800 + next(4000);
+                blocks.push(block(None, start, end, &format!("Cue {}", i)));
+                t = end;
+            }
+            Caption::from(None, blocks)
+        }
+        #[test]
+        fn align_offset() {
+            let reference = irregular_caption();
+            let mut c = irregular_caption();
+            c.offset_milliseconds(3217).expect("Should be fine");
+            let a = c.align(&reference, &AlignOptions::default()).expect("Should be fine");
+            assert_eq!(a.transform.offset, -3217.0);
+            assert_eq!(a.transform.rate, 1.0);
+            assert!(a.confidence > 0.99);
+            assert_eq!(c.blocks[7].start, reference.blocks[7].start);
+        }
+        #[test]
+        fn align_drift() {
+            let reference = irregular_caption();
+            let mut c = irregular_caption();
+            c.scale(24.0 / 25.0, SimpleTime::from_milliseconds(0)).expect("Should be fine");
+            c.offset_milliseconds(1500).expect("Should be fine");
+            let opts = AlignOptions { estimate_drift: true, ..AlignOptions::default() };
+            let a = c.align(&reference, &opts).expect("Should be fine");
+            assert!((a.transform.rate - 25.0 / 24.0).abs() < 1e-4, "{:?}", a);
+            assert!(a.confidence > 0.95);
+            for (b, r) in c.blocks.iter().zip(reference.blocks.iter()) {
+                let diff = b.start.to_milliseconds() as isize - r.start.to_milliseconds() as isize;
+                assert!(diff.abs() <= 20, "Cue off by {} ms with {:?}", diff, a);
+            }
+        }
+        #[test]
+        fn align_bounds_drift() {
+            let reference = irregular_caption();
+            let c = irregular_caption();
+            for drift in [1e9, f64::INFINITY, f64::NAN, -1.0].iter() {
+                let opts = AlignOptions { estimate_drift: true, max_drift: *drift, ..AlignOptions::default() };
+                let a = c.estimate_alignment(&reference, &opts);
+                assert!(a.transform.rate > 0.0, "{:?}", a);
+                assert!(a.confidence > 0.99);
+            }
+        }
+        #[test]
+        fn concatenate_keeps_words() {
+            let c = Caption::concatenate(vec!(toy_timed_caption(), toy_timed_caption()));
+            assert_eq!(c.blocks[1].words[0].start.to_milliseconds(), 3750);
+            assert_eq!(c.blocks[1].words[1].end.to_milliseconds(), 4500);
+        }
+        fn transcript_caption(texts: &[&str]) -> Caption {
+            let blocks = texts.iter().enumerate()
+                .map(|(i, t)| block(None, i * 2000, i * 2000 + 1500, t))
+                .collect();
+            Caption::from(None, blocks)
+        }
+        #[test]
+        fn cut_ranges() {
+            let mut c = transcript_caption(&["zero", "one", "two", "three", "four"]);
+            // Blocks at 0-1500, 2000-3500, 4000-5500, 6000-7500, 8000-9500
+            c.cut(&[
+                (SimpleTime::from_milliseconds(7000), SimpleTime::from_milliseconds(6500)),
+                (SimpleTime::from_milliseconds(1000), SimpleTime::from_milliseconds(3000)),
+                (SimpleTime::from_milliseconds(2500), SimpleTime::from_milliseconds(5700)),
+            ]);
+            let times: Vec<(usize, usize)> = c.blocks.iter()
+                .map(|b| (b.start.to_milliseconds(), b.end.to_milliseconds()))
+                .collect();
+            assert_eq!(times, vec!((0, 1000), (1300, 2300), (2800, 4300)));
+            assert_eq!(c.blocks[1].text(), "three");
+            // Blocks with no length are only dropped from inside a range
+            let mut c = transcript_caption(&["zero", "one", "two"]);
+            c.blocks[0].end = c.blocks[0].start;
+            c.blocks[1].end = c.blocks[1].start;
+            c.cut(&[(SimpleTime::from_milliseconds(2000), SimpleTime::from_milliseconds(3000))]);
+            let times: Vec<(usize, usize)> = c.blocks.iter()
+                .map(|b| (b.start.to_milliseconds(), b.end.to_milliseconds()))
+                .collect();
+            assert_eq!(times, vec!((0, 0), (2000, 2000), (3000, 4500)));
+            c.cut(&[(SimpleTime::from_milliseconds(1000), SimpleTime::from_milliseconds(2500))]);
+            assert_eq!(c.blocks.len(), 2);
+            assert_eq!(c.blocks[1].text(), "two");
+        }
+        #[test]
+        fn cut_keeps_words() {
+            let mut c = toy_timed_caption();
+            c.cut(&[(SimpleTime::from_milliseconds(1000), SimpleTime::from_milliseconds(1700))]);
+            let words = c.blocks[0].words();
+            assert_eq!(words.len(), 2);
+            assert_eq!(words[0].start().to_milliseconds(), 1000);
+            assert_eq!(words[0].end().to_milliseconds(), 1100);
+            assert_eq!(words[1].end().to_milliseconds(), 1550);
+            c.cut(&[(SimpleTime::from_milliseconds(1000), SimpleTime::from_milliseconds(1100))]);
+            assert_eq!(c.blocks[0].words().len(), 1);
+        }
+        #[test]
+        fn insert_gaps() {
+            // Blocks at 0-1500, 2000-3500, 4000-5500
+            let gaps = [
+                (SimpleTime::from_milliseconds(4500), 500),
+                (SimpleTime::from_milliseconds(2000), 1000),
+                (SimpleTime::from_milliseconds(1000), 100),
+                (SimpleTime::from_milliseconds(2000), 1000),
+            ];
+            let times = |c: &Caption| -> Vec<(usize, usize)> {
+                c.blocks.iter().map(|b| (b.start.to_milliseconds(), b.end.to_milliseconds())).collect()
+            };
+            let mut c = transcript_caption(&["zero", "one", "two"]);
+            c.insert_gaps(&gaps, Straddle::Split);
+            assert_eq!(times(&c), vec!((0, 1000), (1100, 1600), (4100, 5600), (6100, 6600), (7100, 8100)));
+            assert_eq!(c.blocks[4].text(), "two");
+            let mut c = transcript_caption(&["zero", "one", "two"]);
+            c.insert_gaps(&gaps, Straddle::Before);
+            assert_eq!(times(&c), vec!((0, 1000), (4100, 5600), (6100, 6600)));
+        }
+        #[test]
+        fn insert_gaps_keeps_words() {
+            let mut c = toy_timed_caption();
+            c.insert_gaps(&[(SimpleTime::from_milliseconds(1700), 1000)], Straddle::Split);
+            assert_eq!(c.blocks.len(), 2);
+            let words = c.blocks[0].words();
+            assert_eq!(words.len(), 1);
+            assert_eq!(words[0].end().to_milliseconds(), 1700);
+            let words = c.blocks[1].words();
+            assert_eq!(words.len(), 1);
+            assert_eq!(words[0].start().to_milliseconds(), 2800);
+            assert_eq!(c.blocks[1].end.to_milliseconds(), 3250);
+        }
+        #[test]
+        fn insert_gaps_zero_length() {
+            let mut spoken = block(None, 3000, 5000, "Hello there");
+            spoken.set_words(vec!(
+                TimedWord::from("Hello", SimpleTime::from_milliseconds(4000), SimpleTime::from_milliseconds(4000)),
+                TimedWord::from("there", SimpleTime::from_milliseconds(4000), SimpleTime::from_milliseconds(4500)),
+            )).expect("Words are in bounds");
+            let mut c = Caption::from(None, vec!(block(None, 4000, 4000, "[beep]"), spoken));
+            c.insert_gaps(&[(SimpleTime::from_milliseconds(4000), 500)], Straddle::Split);
+            assert_eq!(c.blocks.len(), 3);
+            assert_eq!(c.blocks[0].start.to_milliseconds(), 4500);
+            assert_eq!(c.blocks[0].end.to_milliseconds(), 4500);
+            assert_eq!(c.blocks[0].length_millis(), 0);
+            let words = c.blocks[2].words();
+            assert_eq!(words.len(), 2);
+            assert_eq!(words[0].start().to_milliseconds(), 4500);
+            assert_eq!(words[0].end().to_milliseconds(), 4500);
+            assert_eq!(words[1].end().to_milliseconds(), 5000);
+        }
+        #[test]
+        fn split_parts() {
+            // Blocks at 0-1500, 2000-3500, 4000-5500, 6000-7500
+            let c = transcript_caption(&["zero", "one", "two", "three"]);
+            let parts = c.split(&[
+                SplitPoint::Cue(3),
+                SplitPoint::Time(SimpleTime::from_milliseconds(1000)),
+                SplitPoint::Time(SimpleTime::from_milliseconds(9000)),
+            ]).expect("Should be fine");
+            let times: Vec<Vec<(usize, usize)>> = parts.iter()
+                .map(|p| p.blocks.iter().map(|b| (b.start.to_milliseconds(), b.end.to_milliseconds())).collect())
+                .collect();
+            assert_eq!(times, vec!(
+                vec!((0, 1000)),
+                vec!((0, 500), (1000, 2500)),
+                vec!((0, 1500), (2000, 3500)),
+                vec!(),
+            ));
+            assert_eq!(parts[1].blocks[0].text(), "zero");
+            assert_eq!(c.split(&[SplitPoint::Cue(5)]).unwrap_err(), SplitError::CueOutOfRange(5));
+            assert_eq!(c.split(&[SplitPoint::Cue(0)]).unwrap_err(), SplitError::CueOutOfRange(0));
+        }
+        #[test]
+        fn concatenate_with_timings() {
+            let parts = || vec!(transcript_caption(&["zero", "one"]), toy_caption(), transcript_caption(&["two"]));
+            let timings = [
+                SegmentTiming { duration: Some(10_000), ..SegmentTiming::default() },
+                SegmentTiming::default(),
+                SegmentTiming { start: Some(SimpleTime::from_milliseconds(100_000)), duration: None },
+            ];
+            let opts = ConcatenateOptions { gap: 1000, ..ConcatenateOptions::default() };
+            let c = Caption::concatenate_with(parts(), &timings, &opts).expect("Should be fine");
+            assert_eq!(c.blocks[2].start.to_milliseconds(), 11_000);
+            assert_eq!(c.blocks.last().unwrap().start.to_milliseconds(), 100_000);
+            assert_eq!(c.header, None);
+            let short = [SegmentTiming { duration: Some(3000), ..SegmentTiming::default() }];
+            assert_eq!(
+                Caption::concatenate_with(parts(), &short, &opts).unwrap_err(),
+                ConcatenateError::DurationTooShort(1)
+            );
+            let early = [
+                SegmentTiming::default(),
+                SegmentTiming { start: Some(SimpleTime::from_milliseconds(2000)), duration: None },
+            ];
+            assert_eq!(
+                Caption::concatenate_with(parts(), &early, &opts).unwrap_err(),
+                ConcatenateError::Overlap(2)
+            );
+        }
+        #[test]
+        fn concatenate_too_late() {
+            let parts = || vec!(transcript_caption(&["zero"]), transcript_caption(&["one"]));
+            let gap = ConcatenateOptions { gap: usize::MAX, ..ConcatenateOptions::default() };
+            assert_eq!(
+                Caption::concatenate_with(parts(), &[], &gap).unwrap_err(),
+                ConcatenateError::TooLate(2)
+            );
+            let long = [SegmentTiming { duration: Some(usize::MAX), ..SegmentTiming::default() }];
+            assert_eq!(
+                Caption::concatenate_with(parts(), &long, &ConcatenateOptions::default()).unwrap_err(),
+                ConcatenateError::TooLate(2)
+            );
+            let late = [
+                SegmentTiming::default(),
+                SegmentTiming { start: Some(SimpleTime::from_milliseconds(isize::MAX as usize + 1)), duration: None },
+            ];
+            assert_eq!(
+                Caption::concatenate_with(parts(), &late, &ConcatenateOptions::default()).unwrap_err(),
+                ConcatenateError::TooLate(2)
+            );
+        }
+        #[test]
+        fn concatenate_merges_headers() {
+            let mut a = transcript_caption(&["zero"]);
+            a.header = Some(String::from("STYLE one"));
+            let mut b = transcript_caption(&["one"]);
+            b.header = Some(String::from("STYLE two"));
+            let parts = vec!(a.clone(), b, a);
+            let opts = ConcatenateOptions { merge_headers: true, ..ConcatenateOptions::default() };
+            let c = Caption::concatenate_with(parts.clone(), &[], &opts).expect("Should be fine");
+            assert_eq!(c.header, Some(String::from("STYLE one\nSTYLE two")));
+            assert_eq!(Caption::concatenate(parts).header, None);
+        }
+        #[test]
+        fn index_matches_scan() {
+            let mut c = irregular_caption();
+            // Add some long cues that span many others
+            for (start, end) in [(0, 400_000), (50_000, 52_000), (100_000, 180_000)] {
+                c.blocks.push(block(None, start, end, "[Music]"));
+            }
+            let index = c.index();
+            for t in (0..420_000).step_by(997) {
+                let found = index.at(SimpleTime::from_milliseconds(t));
+                let expected: Vec<&CaptionBlock> = c.blocks.iter()
+                    .filter(|b| b.start.to_milliseconds() <= t && t < b.end.to_milliseconds())
+                    .collect();
+                assert_eq!(found.len(), expected.len(), "at {}", t);
+                assert!(found.iter().zip(expected.iter()).all(|(a, b)| std::ptr::eq(*a, *b)));
+                let found = index.overlapping(SimpleTime::from_milliseconds(t + 5000), SimpleTime::from_milliseconds(t));
+                let expected = c.blocks.iter()
+                    .filter(|b| b.start.to_milliseconds() < t + 5000 && t < b.end.to_milliseconds())
+                    .count();
+                assert_eq!(found.len(), expected, "from {}", t);
+            }
+            assert!(Caption::from(None, Vec::new()).index().at(SimpleTime::from_milliseconds(0)).is_empty());
+        }
+        #[test]
+        fn lint_rules() {
+            let c = Caption::from(None, vec!(
+                block(None, 0, 10_000, "This line is comfortably longer than forty-two characters"),
+                block(None, 9000, 11_000, "Overlapping"),
+                block(None, 11_000, 13_000, "Chained"),
+                block(None, 13_050, 14_000, "Far too much text to read in a second"),
+                block(None, 15_000, 17_000, "One\nTwo\nThree"),
+            ));
+            let rules: Vec<(usize, LintRule)> = c.lint(&LintRules::default()).iter()
+                .map(|v| (v.block, v.rule))
+                .collect();
+            assert_eq!(rules, vec!(
+                (0, LintRule::LineLength),
+                (1, LintRule::Overlap),
+                (3, LintRule::MinGap),
+                (3, LintRule::ReadingSpeed),
+                (4, LintRule::LineCount),
+            ));
+            let relaxed = LintRules {
+                allow_overlap: true,
+                min_gap: None,
+                max_duration: Some(9000),
+                max_chars_per_second: None,
+                max_line_length: Some(60),
+                max_lines: Some(3),
+                ..LintRules::default()
+            };
+            let violations = c.lint(&relaxed);
+            assert_eq!(violations.len(), 1);
+            assert_eq!(violations[0].rule, LintRule::MaxDuration);
+            assert_eq!(violations[0].message, "shown for 10000 ms, over 9000 ms");
+        }
+        #[test]
+        fn lint_profiles() {
+            for name in LintRules::PROFILES.iter() {
+                assert!(LintRules::profile(name).is_some(), "{}", name);
+            }
+            assert_eq!(LintRules::profile("fcc"), LintRules::profile("dcmp"));
+            let hello = |start: usize, end: usize| block(None, start, end, "Hello");
+            let c = Caption::from(None, vec!(hello(0, 1000), hello(1083, 2000), hello(2300, 3200), hello(3800, 4700)));
+            let netflix = LintRules::profile("netflix-en").unwrap();
+            let violations = c.lint(&netflix);
+            assert_eq!(violations.len(), 1);
+            assert_eq!((violations[0].block, violations[0].rule), (2, LintRule::CloseGap));
+            let profiles = LintRules::profiles_from_toml(
+                "[loose]\nextends = \"netflix-en\"\nclose_gap = 0\nmax_lines = 0\n[looser]\nextends = \"loose\"\nallow_overlap = true\n"
+            ).expect("Should be fine");
+            assert!(c.lint(&profiles["loose"]).is_empty());
+            assert_eq!(profiles["looser"].max_lines, None);
+            assert!(profiles["looser"].allow_overlap);
+        }
+        #[test]
+        fn lint_profile_errors() {
+            let error = |s: &str| LintRules::profiles_from_toml(s).unwrap_err();
+            assert!(matches!(error("[a"), LintProfileError::InvalidToml(_)));
+            assert_eq!(error("[a]\nextends = \"nope\""), LintProfileError::UnknownProfile(String::from("nope")));
+            assert_eq!(error("[a]\nmax_cps = 3"), LintProfileError::UnknownKey(String::from("a.max_cps")));
+            assert_eq!(error("[a]\nmax_lines = -1"), LintProfileError::InvalidValue(String::from("a.max_lines")));
+            assert_eq!(error("[a]\nmin_gap_frames = 2"), LintProfileError::InvalidValue(String::from("a.fps")));
+            assert!(matches!(error("[a]\nextends = \"b\"\n[b]\nextends = \"a\""), LintProfileError::InvalidValue(_)));
+        }
+        #[test]
+        fn fix_timing() {
+            let hello = |start: usize, end: usize| block(None, start, end, "Hello");
+            let mut c = Caption::from(None, vec!(
+                hello(9000, 11_000),
+                hello(0, 10_000),
+                hello(11_000, 13_000),
+                hello(13_050, 14_000),
+                hello(15_000, 15_200),
+            ));
+            let fixes: Vec<(usize, LintRule, usize, usize)> = c.fix_timing(&LintRules::default()).iter()
+                .map(|f| (f.block, f.rule, f.after.0.to_milliseconds(), f.after.1.to_milliseconds()))
+                .collect();
+            assert_eq!(fixes, vec!(
+                (0, LintRule::Overlap, 0, 8917),
+                (2, LintRule::MinGap, 11_000, 12_967),
+                (4, LintRule::MinDuration, 15_000, 15_833),
+            ));
+            assert!(c.lint(&LintRules::default()).is_empty());
+            // Squeezed between neighbours, a short cue grows both ways
+            let mut c = Caption::from(None, vec!(hello(0, 1000), hello(1500, 1700), hello(1900, 3000)));
+            let fixes = c.fix_timing(&LintRules::default());
+            assert_eq!(fixes.len(), 1);
+            assert_eq!((c.blocks[1].start.to_milliseconds(), c.blocks[1].end.to_milliseconds()), (1083, 1817));
+            assert_eq!(fixes[0].to_string(), "cue 2: min-duration: 00:00:01.500 --> 00:00:01.700 is now 00:00:01.083 --> 00:00:01.817");
+            // Only the earlier cue is shortened, even if there is no room left for the gap
+            let mut c = Caption::from(None, vec!(hello(0, 10_000), hello(50, 2000)));
+            let fixes = c.fix_timing(&LintRules::default());
+            assert_eq!(fixes.len(), 1);
+            assert_eq!((c.blocks[0].start.to_milliseconds(), c.blocks[0].end.to_milliseconds()), (0, 50));
+            assert_eq!((c.blocks[1].start.to_milliseconds(), c.blocks[1].end.to_milliseconds()), (50, 2000));
+            // Cues starting together are left overlapping
+            let rules = LintRules { max_duration: Some(5000), ..LintRules::default() };
+            let mut c = Caption::from(None, vec!(hello(0, 8000), hello(0, 1000)));
+            let rules_hit: Vec<LintRule> = c.fix_timing(&rules).iter().map(|f| f.rule).collect();
+            assert_eq!(rules_hit, vec!(LintRule::MaxDuration));
+            assert_eq!(c.blocks[1].start.to_milliseconds(), 0);
+            assert_eq!(c.lint(&rules)[0].rule, LintRule::Overlap);
+        }
+        #[test]
+        fn reflow_blocks() {
+            let mut c = transcript_caption(&[
+                "This caption was recognised from speech and is one very long line of text",
+                "Short\nlines",
+                "And this one is far too long to ever fit in just two lines of forty-two characters each, however it is broken up",
+            ]);
+            let overflowing = c.reflow(&ReflowOptions::default());
+            assert_eq!(overflowing, vec!(2));
+            assert_eq!(c.blocks[0].text(), "This caption was recognised from speech\nand is one very long line of text");
+            assert_eq!(c.blocks[1].text(), "Short lines");
+            assert_eq!(c.blocks[2].text().lines().count(), 3);
+        }
+        #[test]
+        fn split_long_cues() {
+            let opts = CueLengthOptions { max_chars: 10, ..CueLengthOptions::default() };
+            // Word timings decide where the pieces start
+            let mut c = toy_timed_caption();
+            assert_eq!(c.split_long_cues(&opts), 1);
+            assert_eq!(c.blocks.len(), 2);
+            assert_eq!((c.blocks[0].text(), c.blocks[0].end.to_milliseconds()), (String::from("Blanky"), 1800));
+            assert_eq!(c.blocks[1].words().len(), 1);
+            assert_eq!(c.blocks[1].words()[0].text(), "McBlankface");
+            // Styling is split along with the text, and pieces split again until they fit
+            let mut c = transcript_caption(&["<i>One, two.\nThree</i> four five"]);
+            c.blocks[0].set_text(StyledText::from_srt("<i>One, two.\nThree</i> four five"));
+            assert_eq!(c.split_long_cues(&opts), 1);
+            let pieces: Vec<(String, usize, usize)> = c.blocks.iter()
+                .map(|b| (b.styled_text().to_srt(), b.start.to_milliseconds(), b.end.to_milliseconds()))
+                .collect();
+            assert_eq!(pieces, vec!(
+                (String::from("<i>One, two.</i>"), 0, 540),
+                (String::from("<i>Three</i>"), 540, 860),
+                (String::from("four five"), 860, 1500),
+            ));
+            // Long on screen but with a single word, there is nothing to split
+            let mut c = transcript_caption(&["Supercalifragilistic"]);
+            assert_eq!(c.split_long_cues(&CueLengthOptions { max_duration: 1000, ..opts }), 0);
+        }
+        #[test]
+        fn merge_short_cues() {
+            let mut c = Caption::from(None, vec!(
+                block(Some("Ann"), 0, 300, "So"),
+                block(Some("Ann"), 400, 700, "what"),
+                block(Some("Ann"), 800, 2000, "happened next?"),
+                block(Some("Bob"), 2000, 2200, "Well"),
+                block(Some("Bob"), 3000, 3200, "nothing."),
+                block(Some("Bob"), 3300, 10_500, "It was a very long and very uneventful afternoon"),
+            ));
+            assert_eq!(c.merge_short_cues(&CueLengthOptions::default()), 2);
+            let texts: Vec<String> = c.blocks.iter().map(|b| b.text()).collect();
+            assert_eq!(texts, vec!("So what happened next?", "Well", "nothing.", "It was a very long and very uneventful afternoon"));
+            assert_eq!(c.blocks[0].end.to_milliseconds(), 2000);
+            // The last would be on screen too long if merged
+            assert_eq!(c.merge_short_cues(&CueLengthOptions { max_merge_gap: 1000, ..CueLengthOptions::default() }), 1);
+            assert_eq!(c.blocks[1].text(), "Well nothing.");
+        }
+        #[test]
+        fn dedupe_rolling_captions() {
+            let s = "WEBVTT\n\n\
+                     1\n00:00:00.000 --> 00:00:02.000\n\
+                     hello<00:00:00.500><c> everyone</c><00:00:01.000><c> and</c>\n\n\
+                     2\n00:00:02.000 --> 00:00:02.010\nhello everyone and\n\n\
+                     3\n00:00:02.010 --> 00:00:04.000\nhello everyone and\n\
+                     welcome<00:00:02.500><c> to</c><00:00:03.000><c> the</c><00:00:03.500><c> show</c>\n\n\
+                     4\n00:00:04.000 --> 00:00:04.010\nwelcome to the show\n";
+            let mut c = VttParser::parse(s).expect("Should parse");
+            assert_eq!(c.dedupe(), 3);
+            let blocks: Vec<(String, usize, usize)> = c.blocks.iter()
+                .map(|b| (b.text(), b.start.to_milliseconds(), b.end.to_milliseconds()))
+                .collect();
+            assert_eq!(blocks, vec!(
+                (String::from("hello everyone and"), 0, 2010),
+                (String::from("welcome to the show"), 2010, 4010),
+            ));
+            let words: Vec<String> = c.blocks[1].words().iter().map(|w| w.text()).collect();
+            assert_eq!(words, vec!("welcome", "to", "the", "show"));
+            // Zoom-style lines that grow, overlapping the cue before
+            let mut c = Caption::from(None, vec!(
+                block(None, 0, 3000, "So, the"),
+                block(None, 1000, 4000, "So the plan is"),
+                block(None, 4000, 5000, "is it?"),
+                block(None, 7000, 8000, "Is it really?"),
+            ));
+            assert_eq!(c.dedupe(), 1);
+            let texts: Vec<String> = c.blocks.iter().map(|b| b.text()).collect();
+            assert_eq!(texts, vec!("So, the", "plan is", "is it?", "Is it really?"));
+            assert_eq!(c.blocks[0].end.to_milliseconds(), 1000);
+            // Two speakers saying the same thing are both kept
+            let mut c = Caption::from(None, vec!(block(Some("Ann"), 0, 1000, "No."), block(Some("Bob"), 1200, 2000, "No.")));
+            assert_eq!(c.dedupe(), 0);
+            assert_eq!(c.blocks.len(), 2);
+            assert_eq!(c.blocks[0].end.to_milliseconds(), 1000);
+        }
+        #[test]
+        fn replace_text() {
+            let rule = |p: &str, r: &str| ReplaceRule::new(p, r, false, false).unwrap();
+            // Word timings follow the words when they change one for one
+            let mut c = toy_timed_caption();
+            let changes = c.replace(&[rule("Blanky", "Blankety")], &ReplaceScope::default());
+            assert_eq!(changes, vec!(Replacement {
+                block: 0,
+                target: ReplaceTarget::Text,
+                before: String::from("Blanky McBlankface"),
+                after: String::from("Blankety McBlankface"),
+            }));
+            assert_eq!(c.blocks[0].words()[0].text(), "Blankety");
+            c.replace(&[rule(r"(\w+) (\w+)", "$2, $1 $2")], &ReplaceScope::default());
+            assert_eq!(c.blocks[0].text(), "McBlankface, Blankety McBlankface");
+            assert!(c.blocks[0].words().is_empty());
+            // Styling is kept
+            let mut c = transcript_caption(&["um hello", "um, <b>well</b> um", "Umbrella"]);
+            c.blocks[1].set_text(StyledText::from_srt("um, <b>well</b> um"));
+            let rules = vec!(ReplaceRule::new("um", "er", true, true).unwrap());
+            assert_eq!(c.replace(&rules, &ReplaceScope::default()).len(), 2);
+            let texts: Vec<String> = c.blocks.iter().map(|b| b.styled_text().to_srt()).collect();
+            assert_eq!(texts, vec!("er hello", "er, <b>well</b> er", "Umbrella"));
+            // Only in scope
+            let mut c = transcript_caption(&["one", "one", "one", "one"]);
+            let scope = ReplaceScope {
+                cues: Some((2, 4)),
+                to: Some(SimpleTime::from_milliseconds(4000)),
+                ..ReplaceScope::default()
+            };
+            let changed: Vec<usize> = c.replace(&[rule("one", "two")], &scope).iter().map(|r| r.block).collect();
+            assert_eq!(changed, vec!(1));
+            let scope = ReplaceScope { from: Some(SimpleTime::from_milliseconds(5000)), ..ReplaceScope::default() };
+            let changed: Vec<usize> = c.replace(&[rule("one", "two")], &scope).iter().map(|r| r.block).collect();
+            assert_eq!(changed, vec!(2, 3));
+        }
+        #[test]
+        fn replace_speakers() {
+            let said_by = |speaker: &str| block(Some(speaker), 0, 1000, speaker);
+            let mut c = Caption::from(None, vec!(said_by("Dr Smith"), said_by("Dr. Smith"), said_by("Narrator")));
+            let rules = ReplaceRule::parse_list("Dr\\.? Smith => Dr Smith\nNarrator =>\n", false, false).unwrap();
+            let scope = ReplaceScope { target: ReplaceTarget::Speaker, ..ReplaceScope::default() };
+            let changes = c.replace(&rules, &scope);
+            assert_eq!(changes.len(), 2);
+            assert_eq!(changes[1].to_string(), "cue 3 speaker: \"Narrator\" -> \"\"");
+            assert_eq!(c.blocks[1].speaker(), Some(String::from("Dr Smith")));
+            assert_eq!(c.blocks[1].text(), "Dr. Smith");
+            assert_eq!(c.blocks[2].speaker(), None);
+        }
+        #[test]
+        fn replace_rule_errors() {
+            assert_eq!(
+                ReplaceRule::parse_list("# comment\n\nno arrow here", false, false).unwrap_err(),
+                ReplaceError::InvalidLine(String::from("no arrow here"))
+            );
+            assert!(matches!(ReplaceRule::parse_line("=> nothing", false, false), Err(ReplaceError::InvalidLine(_))));
+            assert!(matches!(ReplaceRule::parse_line("(unclosed => x", false, false), Err(ReplaceError::InvalidPattern(_, _))));
+        }
+        #[test]
+        fn search_blocks() {
+            let c = transcript_caption(&["We need to talk about the budget.", "The BUDGET? Again?", "Budgets, budgets", "no money"]);
+            let query = |pattern: SearchPattern| SearchQuery { pattern, speaker: None, from: None, to: None };
+            assert_eq!(c.search(&query(SearchPattern::regex(r"\bbudget\b", true).unwrap())), vec!(0, 1));
+            assert_eq!(c.search(&query(SearchPattern::regex("budget", false).unwrap())), vec!(0, 2));
+            assert_eq!(c.search(&query(SearchPattern::fuzzy("budget"))), vec!(0, 1, 2));
+            assert_eq!(c.search(&query(SearchPattern::fuzzy("abut the budgit"))), vec!(0));
+            assert!(c.search(&query(SearchPattern::fuzzy("bidgets again"))).is_empty());
+            let ranged = SearchQuery {
+                from: Some(SimpleTime::from_milliseconds(1500)),
+                to: Some(SimpleTime::from_milliseconds(4000)),
+                ..query(SearchPattern::fuzzy("budget"))
+            };
+            assert_eq!(c.search(&ranged), vec!(1));
+            // Blocks with no speaker never match a speaker
+            let speaker = SearchQuery { speaker: Some(String::from("a")), ..query(SearchPattern::fuzzy("")) };
+            assert!(c.search(&speaker).is_empty());
+            assert_eq!(edit_distance("kitten", "sitting"), 3);
+            assert_eq!(edit_distance("", "abc"), 3);
+        }
+        #[test]
+        fn manage_speakers() {
+            let mut c = Caption::from(None, vec!(
+                block(Some("Dr Smith"), 0, 1000, "Hello"),
+                block(Some("SMITH"), 1000, 2000, "Hi"),
+                block(None, 2000, 3000, "Who?"),
+                block(Some("Dr. Smith"), 3000, 4000, "Me"),
+                block(Some("Jones"), 4000, 5000, "Bye"),
+            ));
+            c.blocks[2].set_text(StyledText::from_vtt("<v Jones>Who?</v>"));
+            let names: Vec<(String, usize)> = c.speakers().iter().map(|s| (s.name.clone(), s.cues)).collect();
+            assert_eq!(names, vec!(
+                (String::from("Dr Smith"), 1),
+                (String::from("SMITH"), 1),
+                (String::from("Dr. Smith"), 1),
+                (String::from("Jones"), 1),
+            ));
+            let map = SpeakerMap::parse("smith|dr. smith => Dr Smith\njones => Mr Jones").unwrap();
+            assert_eq!(c.rename_speakers(&map), 4);
+            assert_eq!(c.speakers(), vec!(
+                SpeakerSummary { name: String::from("Dr Smith"), cues: 3, talk_time: 3000 },
+                SpeakerSummary { name: String::from("Mr Jones"), cues: 1, talk_time: 1000 },
+            ));
+            assert_eq!(c.blocks[2].styled_text().to_vtt(), "<v Mr Jones>Who?</v>");
+            let key = c.anonymise_speakers("Participant");
+            assert_eq!(key, vec!(
+                (String::from("Dr Smith"), String::from("Participant 1")),
+                (String::from("Mr Jones"), String::from("Participant 2")),
+            ));
+            assert_eq!(c.blocks[2].styled_text().to_vtt(), "<v Participant 2>Who?</v>");
+            // Mapping to nothing removes the speaker
+            let mut map = SpeakerMap::default();
+            map.insert("Participant 2", "");
+            c.rename_speakers(&map);
+            assert_eq!(c.blocks[4].speaker(), None);
+            assert_eq!(c.blocks[2].styled_text().to_vtt(), "Who?");
+            assert_eq!(SpeakerMap::parse("Ann -> Anne"), Err(SpeakerMapError::InvalidLine(String::from("Ann -> Anne"))));
+            assert!(SpeakerMap::parse("Ann | => Anne").is_err());
+        }
+        #[test]
+        fn extract_speaker_labels() {
+            let preset = |name: &str, text: &str| SpeakerPattern::preset(name).unwrap().find(text).map(|(n, _)| n);
+            assert_eq!(preset("dash", "– Dr. Smith: Yes"), Some(String::from("Dr. Smith")));
+            assert_eq!(preset("dash", "- yes"), None);
+            assert_eq!(preset("brackets", "[Ann] Yes"), Some(String::from("Ann")));
+            assert_eq!(preset("parens", "(laughs) Yes"), None);
+            assert_eq!(preset("caps", "DR. SMITH: Yes"), Some(String::from("DR. SMITH")));
+            assert_eq!(preset("caps", "John: Yes"), None);
+            assert_eq!(preset("colon", "Mary Ann Jones: Yes"), Some(String::from("Mary Ann Jones")));
+            assert_eq!(preset("colon", "So this is it: yes"), None);
+            assert!(SpeakerPattern::preset("nope").is_none());
+            // Sound descriptions and notes are not speakers to the default presets
+            let texts = ["(LAUGHS) That's funny", "Note: the shop is shut", "- Look at the time: it's late",
+                         "- Look here: it's late"];
+            let defaults: Vec<Option<String>> = texts.iter()
+                .map(|text| SpeakerPattern::DEFAULT_PRESETS.iter()
+                    .find_map(|name| SpeakerPattern::preset(name).unwrap().find(text).map(|(n, _)| n)))
+                .collect();
+            assert_eq!(defaults, vec!(None, None, None, None));
+            assert!(matches!(SpeakerPattern::new(r"\w+:"), Err(SpeakerPatternError::MissingGroup(_))));
+            assert!(matches!(SpeakerPattern::new(r"(\w+"), Err(SpeakerPatternError::InvalidPattern(_, _))));
+            // Labels are moved out of the text, keeping styling, unless there is a speaker already
+            let s = "1\n00:00:00,000 --> 00:00:01,000\n<i>JOHN:</i> <b>Hi</b>\n\n\
+                     2\n00:00:01,000 --> 00:00:02,000\n[Mary] ANN: Hello\n\n\
+                     3\n00:00:02,000 --> 00:00:03,000\nNo label here\n";
+            let mut c = SrtParser::parse(s).expect("Should parse");
+            assert_eq!(c.extract_speakers(&[SpeakerPattern::preset("caps").unwrap()]), 1);
+            assert_eq!(c.blocks[0].speaker(), Some(String::from("JOHN")));
+            assert_eq!(c.blocks[1].text(), "ANN: Hello");
+            assert_eq!(SrtWriter::write(&c).lines().nth(2), Some("[JOHN] <b>Hi</b>"));
+            // And put back again
+            c.render_speakers("{SPEAKER}: ", false);
+            assert_eq!(c.blocks[1].text(), "MARY: ANN: Hello");
+            let patterns = vec!(SpeakerPattern::new(r"(?P<speaker>[A-Z]+):").unwrap());
+            assert_eq!(c.extract_speakers(&patterns), 2);
+            assert_eq!(c.blocks[1].speaker(), Some(String::from("MARY")));
+        }
+        #[test]
+        fn apply_transcript_corrections() {
+            let mut c = transcript_caption(&["Teh quick brown\nfox", "jumsp over the", "lazy dog."]);
+            let uncertain = c.apply_transcript("The quick, brown fox jumps over the lazy dog.");
+            assert!(uncertain.is_empty());
+            assert_eq!(c.blocks.len(), 3);
+            assert_eq!(c.blocks[0].text(), "The quick, brown\nfox");
+            assert_eq!(c.blocks[1].text(), "jumps over the");
+            assert_eq!(c.blocks[2].text(), "lazy dog.");
+            assert_eq!(c.blocks[2].start.to_milliseconds(), 4000);
+        }
+        #[test]
+        fn apply_transcript_keeps_unchanged_blocks() {
+            let mut c = Caption::concatenate(vec!(toy_timed_caption(), transcript_caption(&["an extra line"])));
+            c.blocks[0].set_text(StyledText::from_vtt("<i>Blanky</i> McBlankface"));
+            let original = c.blocks[0].clone();
+            let uncertain = c.apply_transcript("Blanky McBlankface an extra word");
+            assert!(uncertain.is_empty());
+            assert_eq!(c.blocks[0].styled_text(), original.styled_text());
+            assert_eq!(c.blocks[0].words().len(), 2);
+            assert_eq!(c.blocks[1].text(), "an extra word");
+            assert!(c.blocks[1].words().is_empty());
+        }
+        #[test]
+        fn apply_transcript_flags_uncertain() {
+            let mut c = transcript_caption(&["one two three", "four five six", "seven eight nine"]);
+            let uncertain = c.apply_transcript(
+                "one two three four a b c d e six seven eight nine"
+            );
+            assert_eq!(uncertain, vec!(UncertainRegion {
+                first_block: 1,
+                last_block: 1,
+                original: String::from("five"),
+                revised: String::from("a b c d e"),
+            }));
+            assert_eq!(c.blocks[1].text(), "four a b c d e six");
+            let mut c = transcript_caption(&["one two three", "four five six"]);
+            let uncertain = c.apply_transcript("one two alpha five six");
+            assert_eq!(uncertain.len(), 1);
+            assert_eq!((uncertain[0].first_block, uncertain[0].last_block), (0, 1));
+            assert_eq!(uncertain[0].original, "three four");
+            assert_eq!(c.blocks[0].text(), "one two alpha");
+            assert_eq!(c.blocks[1].text(), "five six");
+        }
+        #[test]
+        fn apply_transcript_keeps_styling() {
+            let mut c = transcript_caption(&["", "", ""]);
+            c.blocks[0].set_text(StyledText::from_srt("<i>Teh quick</i> brown\n<b>fox</b>"));
+            c.blocks[1].set_text(StyledText::from_vtt("<v Ann>jumsp <u>over</u> the</v>"));
+            c.blocks[2].set_text(StyledText::from_srt("<i>um lazy</i> dog."));
+            let uncertain = c.apply_transcript("The quick, brown fox jumps over the lazy dog.");
+            assert!(uncertain.is_empty());
+            assert_eq!(c.blocks[0].styled_text().to_srt(), "<i>The quick,</i> brown\n<b>fox</b>");
+            assert_eq!(c.blocks[1].styled_text().to_vtt(), "<v Ann>jumps <u>over</u> the</v>");
+            assert_eq!(c.blocks[2].styled_text().to_srt(), "<i>lazy</i> dog.");
+        }
+        #[test]
+        fn apply_transcript_removes_empty_blocks() {
+            let mut c = transcript_caption(&["hello there", "um", "general Kenobi"]);
+            c.apply_transcript("hello there general Kenobi");
+            assert_eq!(c.blocks.len(), 2);
+            assert_eq!(c.blocks[1].text(), "general Kenobi");
+            assert_eq!(c.blocks[1].start.to_milliseconds(), 4000);
+        }
+    }
+    mod chapter {
+        use super::*;
+        #[test]
+        fn parse_ffmetadata() {
+            let contents = ";FFMETADATA1\ntitle=Stream\n\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=90500\nEND=120000\ntitle=Second\n\n[CHAPTER]\nTIMEBASE=1/10\nSTART=0\ntitle=First\n\n[STREAM]\ntitle=ignored\n";
+            let chapters = Chapter::parse_list(contents).expect("Should be fine");
+            assert_eq!(chapters, vec!(
+                Chapter { start: SimpleTime::from_milliseconds(0), title: String::from("First") },
+                Chapter { start: SimpleTime::from_milliseconds(90500), title: String::from("Second") },
+            ));
+        }
+        #[test]
+        fn parse_invalid() {
+            assert!(matches!(Chapter::parse_list("0:00 Intro\nsee you soon"), Err(ChapterError::InvalidLine(_))));
+            assert!(Chapter::parse_list("1:75 Intro").is_err());
+            assert!(Chapter::parse_list(";FFMETADATA1\n[CHAPTER]\ntitle=No start\n").is_err());
+        }
+    }
+    mod media {
+        use super::*;
+        use std::io::Cursor;
+        fn mp4_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+            let mut b = (8 + contents.len() as u32).to_be_bytes().to_vec();
+            b.extend_from_slice(kind);
+            b.extend_from_slice(contents);
+            b
+        }
+        fn mp4_header(version: u8, timescale: u32, duration: u64) -> Vec<u8> {
+            let mut h = vec!(version, 0, 0, 0);
+            if version == 1 {
+                h.extend_from_slice(&[0; 16]);
+                h.extend_from_slice(&timescale.to_be_bytes());
+                h.extend_from_slice(&duration.to_be_bytes());
+            }
+            else {
+                h.extend_from_slice(&[0; 8]);
+                h.extend_from_slice(&timescale.to_be_bytes());
+                h.extend_from_slice(&(duration as u32).to_be_bytes());
+            }
+            h
+        }
+        fn ebml(id: u32, contents: &[u8]) -> Vec<u8> {
+            let mut e: Vec<u8> = id.to_be_bytes().iter().cloned().skip_while(|b| *b == 0).collect();
+            e.push(0x01);
+            e.extend_from_slice(&(contents.len() as u64).to_be_bytes()[1..]);
+            e.extend_from_slice(contents);
+            e
+        }
+        #[test]
+        fn mp4_movie_header() {
+            let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            // A large size mdat before the movie box
+            data.extend_from_slice(&1u32.to_be_bytes());
+            data.extend_from_slice(b"mdat");
+            data.extend_from_slice(&20u64.to_be_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend(mp4_box(b"moov", &mp4_box(b"mvhd", &mp4_header(1, 90_000, 5_400_045))));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Ok(60_001));
+        }
+        #[test]
+        fn mp4_track_header() {
+            let track = |d| mp4_box(b"trak", &mp4_box(b"mdia", &mp4_box(b"mdhd", &mp4_header(0, 1000, d))));
+            let mut moov = mp4_box(b"mvhd", &mp4_header(0, 1000, 0));
+            moov.extend(track(12_000));
+            moov.extend(track(12_500));
+            let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            data.extend(mp4_box(b"moov", &moov));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Ok(12_500));
+            let data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Err(MediaError::MissingDuration));
+            // A movie header too long to use falls back to the tracks
+            let mut moov = mp4_box(b"mvhd", &mp4_header(1, 1, u64::MAX - 1));
+            moov.extend(track(12_000));
+            let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            data.extend(mp4_box(b"moov", &moov));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Ok(12_000));
+        }
+        #[test]
+        fn corrupt_durations() {
+            // Box sizes running past the end of the file, or past the end of u64
+            let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            data.extend_from_slice(&1u32.to_be_bytes());
+            data.extend_from_slice(b"moov");
+            data.extend_from_slice(&u64::MAX.to_be_bytes());
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Err(MediaError::Truncated));
+            let mut moov = 1u32.to_be_bytes().to_vec();
+            moov.extend_from_slice(b"mvhd");
+            moov.extend_from_slice(&(u64::MAX - 4).to_be_bytes());
+            let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            data.extend(mp4_box(b"moov", &moov));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Err(MediaError::Truncated));
+            // An Info element claiming far more than the file holds is read as far as it goes
+            let mut info = ebml(MKV_TIMECODE_SCALE, &[0x0F, 0x42, 0x40]);
+            info.extend(ebml(MKV_DURATION, &1234.5f64.to_be_bytes()));
+            let mut segment = MKV_INFO.to_be_bytes().to_vec();
+            segment.extend_from_slice(&[0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+            segment.extend(info);
+            let mut data = ebml(MKV_EBML, &[]);
+            data.extend(ebml(MKV_SEGMENT, &segment));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Ok(1235));
+            // But its children must fit inside it
+            let mut info = ebml(MKV_TIMECODE_SCALE, &[0x0F, 0x42, 0x40]);
+            info.extend_from_slice(&[0x44, 0x89, 0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+            info.extend_from_slice(&1234.5f64.to_be_bytes());
+            let mut data = ebml(MKV_EBML, &[]);
+            data.extend(ebml(MKV_SEGMENT, &ebml(MKV_INFO, &info)));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Err(MediaError::Truncated));
+        }
+        #[test]
+        fn matroska_duration() {
+            let mut data = ebml(MKV_EBML, &ebml(0x4282, b"webm"));
+            let mut info = ebml(MKV_TIMECODE_SCALE, &[0x0F, 0x42, 0x40]);
+            info.extend(ebml(MKV_DURATION, &1234.5f64.to_be_bytes()));
+            let mut segment = ebml(0x114D_9B74, &[0; 10]);
+            segment.extend(ebml(MKV_INFO, &info));
+            segment.extend(ebml(MKV_CLUSTER, &[0; 10]));
+            data.extend(ebml(MKV_SEGMENT, &segment));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Ok(1235));
+            // Durations may also be 32 bit floats and in other units
+            let mut data = ebml(MKV_EBML, &[]);
+            let mut info = ebml(MKV_TIMECODE_SCALE, &[0x3B, 0x9A, 0xCA, 0x00]);
+            info.extend(ebml(MKV_DURATION, &90.0f32.to_be_bytes()));
+            data.extend(ebml(MKV_SEGMENT, &ebml(MKV_INFO, &info)));
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Ok(90_000));
+            // Infinite and absurdly large durations are no use
+            for d in [f64::INFINITY, f64::NAN, 1e300].iter() {
+                let mut info = ebml(MKV_TIMECODE_SCALE, &[0x0F, 0x42, 0x40]);
+                info.extend(ebml(MKV_DURATION, &d.to_be_bytes()));
+                let mut data = ebml(MKV_EBML, &[]);
+                data.extend(ebml(MKV_SEGMENT, &ebml(MKV_INFO, &info)));
+                assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Err(MediaError::MissingDuration));
+            }
+        }
+        /// Track ID, codec and samples with their durations
+        type TestTrack<'a> = (u32, &'a [u8; 4], Vec<(u32, Vec<u8>)>);
+        /// Build an MP4 file with one chunk per track
+        fn mp4_file(tracks: &[TestTrack]) -> Vec<u8> {
+            let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            let mut mdat = Vec::new();
+            let mut offsets = Vec::new();
+            for (_, _, samples) in tracks.iter() {
+                offsets.push(data.len() + 8 + mdat.len());
+                for (_, sample) in samples.iter() {
+                    mdat.extend_from_slice(sample);
+                }
+            }
+            data.extend(mp4_box(b"mdat", &mdat));
+            let table = |entries: Vec<Vec<u32>>| -> Vec<u8> {
+                let mut t = vec!(0, 0, 0, 0);
+                t.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for e in entries.iter().flatten() {
+                    t.extend_from_slice(&e.to_be_bytes());
+                }
+                t
+            };
+            let mut moov = mp4_box(b"mvhd", &mp4_header(0, 1000, 0));
+            for ((id, codec, samples), offset) in tracks.iter().zip(offsets) {
+                let mut tkhd = vec!(0u8; 12);
+                tkhd.extend_from_slice(&id.to_be_bytes());
+                tkhd.extend_from_slice(&[0; 68]);
+                let mut mdhd = mp4_header(0, 1000, 0);
+                // "eng" packed into five bit letters
+                mdhd.extend_from_slice(&((5u16 << 10) | (14 << 5) | 7).to_be_bytes());
+                mdhd.extend_from_slice(&[0, 0]);
+                let mut hdlr = vec!(0u8; 8);
+                hdlr.extend_from_slice(b"sbtl");
+                hdlr.extend_from_slice(&[0; 12]);
+                hdlr.extend_from_slice(b"Subtitles\0");
+                let mut stsd = vec!(0, 0, 0, 0, 0, 0, 0, 1);
+                stsd.extend(mp4_box(codec, &[0; 8]));
+                let mut stsz = vec!(0u8; 8);
+                stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+                for (_, sample) in samples.iter() {
+                    stsz.extend_from_slice(&(sample.len() as u32).to_be_bytes());
+                }
+                let mut stbl = mp4_box(b"stsd", &stsd);
+                stbl.extend(mp4_box(b"stts", &table(samples.iter().map(|(d, _)| vec!(1, *d)).collect())));
+                stbl.extend(mp4_box(b"stsc", &table(vec!(vec!(1, samples.len() as u32, 1)))));
+                stbl.extend(mp4_box(b"stsz", &stsz));
+                stbl.extend(mp4_box(b"stco", &table(vec!(vec!(offset as u32)))));
+                let mut mdia = mp4_box(b"mdhd", &mdhd);
+                mdia.extend(mp4_box(b"hdlr", &hdlr));
+                mdia.extend(mp4_box(b"minf", &mp4_box(b"stbl", &stbl)));
+                let mut trak = mp4_box(b"tkhd", &tkhd);
+                trak.extend(mp4_box(b"mdia", &mdia));
+                moov.extend(mp4_box(b"trak", &trak));
+            }
+            data.extend(mp4_box(b"moov", &moov));
+            data
+        }
+        fn tx3g_sample(text: &str, styles: &[(u16, u16, u8)]) -> Vec<u8> {
+            let mut sample = (text.len() as u16).to_be_bytes().to_vec();
+            sample.extend_from_slice(text.as_bytes());
+            if !styles.is_empty() {
+                let mut styl = (styles.len() as u16).to_be_bytes().to_vec();
+                for (start, end, flags) in styles.iter() {
+                    styl.extend_from_slice(&start.to_be_bytes());
+                    styl.extend_from_slice(&end.to_be_bytes());
+                    styl.extend_from_slice(&[0, 1, *flags, 18, 255, 255, 255, 255]);
+                }
+                sample.extend(mp4_box(b"styl", &styl));
+            }
+            sample
+        }
+        #[test]
+        fn mp4_subtitles() {
+            let mut cues = mp4_box(b"vttc", &mp4_box(b"payl", b"<i>Hi</i>"));
+            cues.extend(mp4_box(b"vttc", &mp4_box(b"payl", b"There")));
+            let data = mp4_file(&[
+                (1, b"avc1", vec!((1000, vec!(0; 16)))),
+                (2, b"tx3g", vec!(
+                    (1000, tx3g_sample("Hello world", &[(6, 11, 1)])),
+                    (500, tx3g_sample("", &[])),
+                    (2000, tx3g_sample("Bye", &[])),
+                )),
+                (3, b"wvtt", vec!((750, mp4_box(b"vtte", &[])), (1250, cues))),
+            ]);
+            let tracks = MediaFile::subtitles(&mut Cursor::new(data)).expect("Should be fine");
+            assert_eq!(tracks.len(), 2);
+            let tx3g = &tracks[0];
+            assert_eq!((tx3g.number, tx3g.codec.as_str()), (2, "tx3g"));
+            assert_eq!(tx3g.language, Some(String::from("eng")));
+            assert_eq!(tx3g.name, Some(String::from("Subtitles")));
+            let blocks = &tx3g.caption.blocks;
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[0].styled_text().to_vtt(), "Hello <b>world</b>");
+            assert_eq!((blocks[1].start.to_milliseconds(), blocks[1].end.to_milliseconds()), (1500, 3500));
+            let blocks = &tracks[1].caption.blocks;
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[0].styled_text().to_vtt(), "<i>Hi</i>");
+            assert_eq!(blocks[1].text(), "There");
+            assert_eq!((blocks[1].start.to_milliseconds(), blocks[1].end.to_milliseconds()), (750, 2000));
+        }
+        /// Build an MP4 file with one tx3g track from its sample table and some data
+        fn mp4_stbl_file(stbl: &[u8]) -> Vec<u8> {
+            let mut stsd = vec!(0, 0, 0, 0, 0, 0, 0, 1);
+            stsd.extend(mp4_box(b"tx3g", &[0; 8]));
+            let mut full_stbl = mp4_box(b"stsd", &stsd);
+            full_stbl.extend_from_slice(stbl);
+            let mut mdhd = mp4_header(0, 1000, 0);
+            mdhd.extend_from_slice(&[0; 4]);
+            let mut mdia = mp4_box(b"mdhd", &mdhd);
+            mdia.extend(mp4_box(b"minf", &mp4_box(b"stbl", &full_stbl)));
+            let mut trak = mp4_box(b"tkhd", &[0; 16]);
+            trak.extend(mp4_box(b"mdia", &mdia));
+            let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+            data.extend(mp4_box(b"mdat", &tx3g_sample("Hello", &[])));
+            data.extend(mp4_box(b"moov", &mp4_box(b"trak", &trak)));
+            data
+        }
+        #[test]
+        fn corrupt_mp4_subtitles() {
+            let full_box = |kind: &[u8; 4], words: &[u32]| {
+                let mut contents = vec!(0u8; 4);
+                for w in words.iter() {
+                    contents.extend_from_slice(&w.to_be_bytes());
+                }
+                mp4_box(kind, &contents)
+            };
+            let read = |stbl: Vec<u8>| MediaFile::subtitles(&mut Cursor::new(mp4_stbl_file(&stbl))).map(|t| t.len());
+            let mut timing = full_box(b"stts", &[1, 1, 1000]);
+            timing.extend(full_box(b"stsc", &[1, 1, 1, 1]));
+            let with = |boxes: &[Vec<u8>]| {
+                let mut stbl = timing.clone();
+                stbl.extend(boxes.concat());
+                stbl
+            };
+            // The sample is just after the ftyp box and the mdat header
+            assert_eq!(read(with(&[full_box(b"stsz", &[0, 1, 7]), full_box(b"stco", &[1, 24])])), Ok(1));
+            // Billions of samples of a fixed size cannot fit in the file
+            assert_eq!(read(with(&[full_box(b"stsz", &[1, u32::MAX]), full_box(b"stco", &[1, 24])])),
+                       Err(MediaError::Truncated));
+            // Samples past the end of the file, or past the end of u64
+            assert_eq!(read(with(&[full_box(b"stsz", &[0, 1, 7]), full_box(b"stco", &[1, 1_000_000])])),
+                       Err(MediaError::Truncated));
+            assert_eq!(read(with(&[full_box(b"stsz", &[0, 1, 7]), full_box(b"co64", &[1, u32::MAX, u32::MAX - 2])])),
+                       Err(MediaError::Truncated));
+            // A table with fewer entries than its count, and a box running past its parent
+            assert_eq!(read(with(&[full_box(b"stsz", &[0, 3, 7]), full_box(b"stco", &[1, 24])])),
+                       Err(MediaError::Truncated));
+            let mut stsz = full_box(b"stsz", &[0, 1, 7]);
+            stsz[3] += 100;
+            assert_eq!(read(with(&[stsz, full_box(b"stco", &[1, 24])])), Err(MediaError::Truncated));
+        }
+        #[test]
+        fn corrupt_matroska_subtitles() {
+            let read = |segment: Vec<u8>| {
+                let mut data = ebml(MKV_EBML, &[]);
+                data.extend(ebml(MKV_SEGMENT, &segment));
+                MediaFile::subtitles(&mut Cursor::new(data)).map(|t| t.len())
+            };
+            // An element of a cluster of unknown size claiming far more than the file holds
+            let mut segment = MKV_CLUSTER.to_be_bytes().to_vec();
+            segment.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+            segment.push(MKV_SIMPLE_BLOCK as u8);
+            segment.extend_from_slice(&[0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+            segment.extend(mkv_block(1, 0, b"Hello"));
+            assert_eq!(read(segment), Err(MediaError::Truncated));
+            // A track entry running past the end of the tracks
+            let mut tracks = MKV_TRACK_ENTRY.to_be_bytes()[3..].to_vec();
+            tracks.push(0x90);
+            tracks.extend(ebml(MKV_TRACK_NUMBER, &[1]));
+            assert_eq!(read(ebml(MKV_TRACKS, &tracks)), Err(MediaError::Truncated));
+            // A truncated file stops at the end of what is there
+            let mut data = ebml(MKV_EBML, &[]);
+            data.extend(ebml(MKV_SEGMENT, &ebml(MKV_TRACKS, &[])));
+            data.truncate(data.len() - 3);
+            assert!(MediaFile::subtitles(&mut Cursor::new(data)).is_err());
+            // Durations and timestamps at the end of u64 saturate rather than overflow
+            let mut entry = ebml(MKV_TRACK_NUMBER, &[1]);
+            entry.extend(ebml(MKV_TRACK_TYPE, &[0x11]));
+            entry.extend(ebml(MKV_CODEC_ID, b"S_TEXT/UTF8"));
+            let mut group = ebml(MKV_BLOCK, &mkv_block(1, 0, b"Hello"));
+            group.extend(ebml(MKV_BLOCK_DURATION, &[0xFF; 8]));
+            let mut cluster = ebml(MKV_TIMESTAMP, &[0x03, 0xE8]);
+            cluster.extend(ebml(MKV_BLOCK_GROUP, &group));
+            cluster.extend(ebml(MKV_TIMESTAMP, &[0xFF; 8]));
+            cluster.extend(ebml(MKV_SIMPLE_BLOCK, &mkv_block(1, i16::MAX, b"Bye")));
+            let mut segment = ebml(MKV_TRACKS, &ebml(MKV_TRACK_ENTRY, &entry));
+            segment.extend(ebml(MKV_CLUSTER, &cluster));
+            let mut data = ebml(MKV_EBML, &[]);
+            data.extend(ebml(MKV_SEGMENT, &segment));
+            let tracks = MediaFile::subtitles(&mut Cursor::new(data)).expect("Should be fine");
+            let blocks = &tracks[0].caption.blocks;
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[0].start.to_milliseconds(), 1000);
+            assert!(blocks[0].end >= blocks[0].start && blocks[1].end >= blocks[1].start);
+        }
+        fn mkv_block(track: u8, relative: i16, payload: &[u8]) -> Vec<u8> {
+            let mut block = vec!(0x80 | track);
+            block.extend_from_slice(&relative.to_be_bytes());
+            block.push(0x80);
+            block.extend_from_slice(payload);
+            block
+        }
+        #[test]
+        fn matroska_subtitles() {
+            let track = |number: u8, kind: u8, codec: &str, extra: Vec<u8>| {
+                let mut entry = ebml(MKV_TRACK_NUMBER, &[number]);
+                entry.extend(ebml(MKV_TRACK_TYPE, &[kind]));
+                entry.extend(ebml(MKV_CODEC_ID, codec.as_bytes()));
+                entry.extend(extra);
+                ebml(MKV_TRACK_ENTRY, &entry)
+            };
+            let mut tracks = track(1, 0x11, "S_TEXT/UTF8", ebml(MKV_LANGUAGE, b"fre"));
+            let mut extra = ebml(MKV_LANGUAGE_BCP47, b"en-GB");
+            extra.extend(ebml(MKV_NAME, b"Signs"));
+            tracks.extend(track(2, 0x11, "S_TEXT/ASS", extra));
+            tracks.extend(track(3, 0x01, "V_VP9", Vec::new()));
+            let mut cluster = ebml(MKV_TIMESTAMP, &[0x03, 0xE8]);
+            let mut group = ebml(MKV_BLOCK, &mkv_block(1, 0, b"<i>Bonjour</i>"));
+            group.extend(ebml(MKV_BLOCK_DURATION, &[0x05, 0xDC]));
+            cluster.extend(ebml(MKV_BLOCK_GROUP, &group));
+            cluster.extend(ebml(MKV_SIMPLE_BLOCK, &mkv_block(3, 0, &[0; 8])));
+            cluster.extend(ebml(MKV_SIMPLE_BLOCK, &mkv_block(2, 500, b"0,0,Default,Alice,0,0,0,,Hi\\Nthere")));
+            let mut segment = ebml(MKV_INFO, &ebml(MKV_TIMECODE_SCALE, &[0x0F, 0x42, 0x40]));
+            segment.extend(ebml(MKV_TRACKS, &tracks));
+            segment.extend(ebml(MKV_CLUSTER, &cluster));
+            // A cluster of unknown size, as written by live streams
+            segment.extend_from_slice(&MKV_CLUSTER.to_be_bytes());
+            segment.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+            segment.extend(ebml(MKV_TIMESTAMP, &[0x13, 0x88]));
+            let mut group = ebml(MKV_BLOCK, &mkv_block(1, -100, b"Au revoir"));
+            group.extend(ebml(MKV_BLOCK_DURATION, &[0x03, 0xE8]));
+            segment.extend(ebml(MKV_BLOCK_GROUP, &group));
+            segment.extend(ebml(MKV_CLUSTER, &ebml(MKV_TIMESTAMP, &[0x17, 0x70])));
+            let mut data = ebml(MKV_EBML, &ebml(0x4282, b"matroska"));
+            data.extend(ebml(MKV_SEGMENT, &segment));
+            let tracks = MediaFile::subtitles(&mut Cursor::new(data)).expect("Should be fine");
+            assert_eq!(tracks.len(), 2);
+            assert_eq!(tracks[0].language, Some(String::from("fre")));
+            let blocks = &tracks[0].caption.blocks;
+            let times: Vec<(usize, usize)> = blocks.iter()
+                .map(|b| (b.start.to_milliseconds(), b.end.to_milliseconds()))
+                .collect();
+            assert_eq!(times, vec!((1000, 2500), (4900, 5900)));
+            assert_eq!(blocks[0].styled_text().to_srt(), "<i>Bonjour</i>");
+            assert_eq!((tracks[1].number, tracks[1].codec.as_str()), (2, "S_TEXT/ASS"));
+            assert_eq!(tracks[1].language, Some(String::from("en-GB")));
+            assert_eq!(tracks[1].name, Some(String::from("Signs")));
+            let blocks = &tracks[1].caption.blocks;
+            assert_eq!(blocks[0].speaker(), Some(String::from("Alice")));
+            assert_eq!(blocks[0].text(), "Hi\nthere");
+            assert_eq!((blocks[0].start.to_milliseconds(), blocks[0].end.to_milliseconds()), (1500, 3500));
+        }
+        #[test]
+        fn unknown_container() {
+            let data = b"WEBVTT\n\n1\n00:00:00.000 --> 00:00:01.000\nHello".to_vec();
+            assert_eq!(MediaFile::duration(&mut Cursor::new(data)), Err(MediaError::UnknownContainer));
+        }
     }
     mod vtt_writer {
         use super::*;
@@ -1382,7 +7860,8 @@ mod test {
                         speaker: Some("Pete Molfese".to_string()),
                         start: SimpleTime::from_milliseconds(0),
                         end: SimpleTime::from_milliseconds(1000),
-                        text: "Hello, world!".to_string(),
+                        text: StyledText::from("Hello, world!"),
+                        words: Vec::new(),
                     }
                 )
             };
@@ -1405,13 +7884,15 @@ mod test {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(0),
                         end: SimpleTime::from_milliseconds(1500),
-                        text: "We're doing very cool things".to_string(),
+                        text: StyledText::from("We're doing very cool things"),
+                        words: Vec::new(),
                     },
                     CaptionBlock {
                         speaker: None,
                         start: SimpleTime::from_milliseconds(1500),
                         end: SimpleTime::from_milliseconds(2500),
-                        text: "The COOLEST things!".to_string(),
+                        text: StyledText::from("The COOLEST things!"),
+                        words: Vec::new(),
                     }
                 ),
             };
@@ -1533,6 +8014,12 @@ mod test {
             }
         }
         #[test]
+        fn test_parse_block_header_name_with_digits() {
+            let (name, start, _) = VttParser::block_header("Speaker 2 00:00:03.000 --> 00:00:04.000").unwrap();
+            assert_eq!(name, Some(String::from("Speaker 2")));
+            assert_eq!(start.to_milliseconds(), 3000);
+        }
+        #[test]
         fn test_parse_block_header_missing_start() {
             // Test that we fail for no block start
             let test_str_3 = "--> 00:00:01.001";
@@ -1571,6 +8058,49 @@ mod test {
             assert_eq!(cb.text(), text);
         }
         #[test]
+        fn parse_multiline_blocks() {
+            let s = format!(
+                "WEBVTT\n\n{}\n{}\n{}\n{}\n\n{}\n{}\n{}\n",
+                1,
+                "00:00:00.000 --> 00:00:01.000",
+                "First line",
+                "second line",
+                2,
+                "00:00:01.000 --> 00:00:02.000",
+                "Only line"
+            );
+            let cap = VttParser::parse(&s).expect("Should have passed!");
+            assert_eq!(cap.blocks.len(), 2);
+            assert_eq!(cap.blocks[0].text(), "First line\nsecond line");
+            assert_eq!(cap.blocks[1].text(), "Only line");
+            assert_eq!(VttWriter::write(&cap), s);
+        }
+        #[test]
+        fn parse_inline_timestamps() {
+            let s = format!(
+                "WEBVTT\n\n{}\n{}\n{}\n",
+                1,
+                "00:00:01.000 --> 00:00:03.000",
+                "Never <00:00:01.500>gonna <00:02.250>give you up"
+            );
+            let cap = VttParser::parse(&s).expect("Should have passed!");
+            let block = &cap.blocks[0];
+            assert_eq!(block.text(), "Never gonna give you up");
+            let words = block.words();
+            assert_eq!(words.len(), 3);
+            assert_eq!(words[0].text(), "Never");
+            assert_eq!(words[0].start().to_milliseconds(), 1000);
+            assert_eq!(words[0].end().to_milliseconds(), 1500);
+            assert_eq!(words[2].text(), "give you up");
+            assert_eq!(words[2].start().to_milliseconds(), 2250);
+            assert_eq!(words[2].end().to_milliseconds(), 3000);
+            // Written back out with full timestamps
+            assert_eq!(
+                VttWriter::write(&cap),
+                s.replace("<00:02.250>", "<00:00:02.250>")
+            );
+        }
+        #[test]
         fn test_parse_block_fails_insufficient_lines() {
             // Test to make sure we fail for no blank
             let x = VttParser::block("thing\n");
@@ -1683,6 +8213,30 @@ mod test {
             assert_eq!(cb.text(), text);
         }
         #[test]
+        fn parse_multiline_blocks() {
+            let s = format!(
+                "{}\n{}\n{}\n{}\n",
+                1,
+                "00:00:00,000 --> 00:00:01,000",
+                "[Peter Molfese] Hello,",
+                "welcome to the caption tool!"
+            );
+            let cap = SrtParser::parse(&s).expect("Should have passed!");
+            assert_eq!(cap.blocks.len(), 1);
+            assert_eq!(cap.blocks[0].speaker(), Some("Peter Molfese".to_string()));
+            assert_eq!(cap.blocks[0].text(), "Hello,\nwelcome to the caption tool!");
+        }
+        #[test]
+        fn parse_brackets_after_first_line() {
+            let s = "1\n00:00:00,000 --> 00:00:01,000\nHello\n[music]\n\n\
+                     2\n00:00:01,000 --> 00:00:02,000\n[Ann] Hi\n[door slams]\n";
+            let cap = SrtParser::parse(s).expect("Should have passed!");
+            assert_eq!(cap.blocks[0].speaker(), None);
+            assert_eq!(cap.blocks[0].text(), "Hello\n[music]");
+            assert_eq!(cap.blocks[1].speaker(), Some("Ann".to_string()));
+            assert_eq!(cap.blocks[1].text(), "Hi\n[door slams]");
+        }
+        #[test]
         fn test_parse_block_fails_insufficient_lines() {
             // Test to make sure we fail for no blank
             let x = SrtParser::block("thing\n");
@@ -1692,6 +8246,201 @@ mod test {
             };
         }
     }
+    mod asr_parser {
+        use super::*;
+        fn word(text: &str, start: usize, end: usize, speaker: Option<&str>) -> AsrWord {
+            AsrWord {
+                text: text.to_string(),
+                start: SimpleTime::from_milliseconds(start),
+                end: SimpleTime::from_milliseconds(end),
+                confidence: None,
+                speaker: speaker.map(|s| s.to_string()),
+                punctuation: false,
+            }
+        }
+        #[test]
+        fn whisper() {
+            let json = r#"{"segments": [{"start": 0.0, "end": 1.2, "text": " Hi there",
+                "speaker": "SPEAKER_00",
+                "words": [{"word": " Hi", "start": 0.0, "end": 0.5, "probability": 0.9},
+                          {"word": " there", "start": 0.6, "end": 1.2, "probability": 0.8}]}]}"#;
+            let words = AsrParser::words(json, None).expect("Should parse");
+            assert_eq!(words.len(), 2);
+            assert_eq!(words[1].text, "there");
+            assert_eq!(words[1].start.to_milliseconds(), 600);
+            assert_eq!(words[1].confidence, Some(0.8));
+            assert_eq!(words[1].speaker, Some("SPEAKER_00".to_string()));
+        }
+        #[test]
+        fn whisper_segments_without_words() {
+            let json = r#"{"segments": [{"start": 1.0, "end": 2.0, "text": " Hi there"},
+                                        {"start": 3.0, "end": 2.5, "text": " Backwards"}]}"#;
+            let words = AsrParser::words(json, None).expect("Should parse");
+            let times: Vec<(usize, usize)> = words.iter()
+                .map(|w| (w.start.to_milliseconds(), w.end.to_milliseconds()))
+                .collect();
+            assert_eq!(times, vec!((1000, 1285), (1285, 2000), (3000, 3000)));
+        }
+        #[test]
+        fn aws_transcribe() {
+            let json = r#"{"results": {"items": [
+                {"type": "pronunciation", "start_time": "0.5", "end_time": "0.9",
+                 "speaker_label": "spk_0",
+                 "alternatives": [{"confidence": "0.99", "content": "Hello"}]},
+                {"type": "punctuation",
+                 "alternatives": [{"confidence": "0.0", "content": ","}]},
+                {"type": "pronunciation", "start_time": "1.0", "end_time": "1.4",
+                 "speaker_label": "spk_0",
+                 "alternatives": [{"confidence": "0.95", "content": "world"}]}
+            ]}}"#;
+            let cap = AsrParser::parse(json, None, &SegmentationOptions::default())
+                .expect("Should parse");
+            assert_eq!(cap.blocks.len(), 1);
+            assert_eq!(cap.blocks[0].text(), "Hello, world");
+            assert_eq!(cap.blocks[0].speaker(), Some("spk_0".to_string()));
+            assert_eq!(cap.blocks[0].start().to_milliseconds(), 500);
+            assert_eq!(cap.blocks[0].end().to_milliseconds(), 1400);
+        }
+        #[test]
+        fn google_stt_diarized() {
+            let json = r#"{"results": [
+                {"alternatives": [{"transcript": "hi", "words": [
+                    {"startTime": "0s", "endTime": "0.400s", "word": "hi"}]}]},
+                {"alternatives": [{"transcript": "hi", "words": [
+                    {"startTime": "0s", "endTime": "0.400s", "word": "hi", "speakerTag": 1},
+                    {"startTime": "2.100s", "endTime": "2.500s", "word": "hello", "speakerTag": 2}]}]}
+            ]}"#;
+            let words = AsrParser::words(json, Some(AsrFormat::GoogleStt)).expect("Should parse");
+            assert_eq!(words.len(), 2);
+            assert_eq!(words[0].speaker, Some("Speaker 1".to_string()));
+            assert_eq!(words[1].start.to_milliseconds(), 2100);
+        }
+        #[test]
+        fn unknown_format() {
+            let r = AsrParser::words(r#"{"foo": 1}"#, None);
+            assert!(matches!(r, Err(AsrParserError::UnknownFormat)));
+        }
+        #[test]
+        fn segment_line_limits() {
+            let words = vec!(
+                word("aaaa", 0, 100, None),
+                word("bbbb", 100, 200, None),
+                word("cccc", 200, 300, None),
+                word("dddd", 300, 400, None),
+                word("eeee", 400, 500, None),
+            );
+            let opts = SegmentationOptions {
+                max_chars_per_line: 9,
+                max_lines: 2,
+                ..SegmentationOptions::default()
+            };
+            let blocks = AsrParser::segment(&words, &opts);
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[0].text(), "aaaa bbbb\ncccc dddd");
+            assert_eq!(blocks[0].end().to_milliseconds(), 400);
+            assert_eq!(blocks[0].words().len(), 4);
+            assert_eq!(blocks[0].words()[3].start().to_milliseconds(), 300);
+            assert_eq!(blocks[1].text(), "eeee");
+            assert_eq!(blocks[1].start().to_milliseconds(), 400);
+        }
+        #[test]
+        fn segment_breaks() {
+            let words = vec!(
+                word("one", 0, 100, Some("A")),
+                word("two", 100, 200, Some("B")),
+                word("three", 3000, 3200, Some("B")),
+            );
+            let blocks = AsrParser::segment(&words, &SegmentationOptions::default());
+            assert_eq!(blocks.len(), 3);
+            assert_eq!(blocks[0].speaker(), Some("A".to_string()));
+            let opts = SegmentationOptions {
+                break_on_speaker_change: false,
+                max_pause: None,
+                ..SegmentationOptions::default()
+            };
+            let blocks = AsrParser::segment(&words, &opts);
+            assert_eq!(blocks.len(), 1);
+            assert_eq!(blocks[0].text(), "one two three");
+        }
+    }
+    mod styled_text {
+        use super::*;
+        #[test]
+        fn srt_tags() {
+            let t = StyledText::from_srt("<I>Hi</I> <b>there <u>you</u></b> <font face=\"Arial\" color=\"#FF0000\">red</font>");
+            assert_eq!(t.to_plain(), "Hi there you red");
+            assert_eq!(t.spans()[0], TextSpan::Styled(TextStyle::Italic, vec!(TextSpan::Text("Hi".to_string()))));
+            assert_eq!(t.to_srt(), "<i>Hi</i> <b>there <u>you</u></b> <font color=\"#FF0000\">red</font>");
+            assert_eq!(t.to_vtt(), "<i>Hi</i> <b>there <u>you</u></b> <c.red>red</c>");
+        }
+        #[test]
+        fn vtt_tags() {
+            let s = "<v Mary><c.loud.yellow>Hey</c> <lang ja><ruby>漢<rt>kan</rt></ruby></lang> &amp; &lt;3</v>";
+            let t = StyledText::from_vtt(s);
+            assert_eq!(t.to_plain(), "Hey 漢 & <3");
+            assert_eq!(t.to_vtt(), s);
+            assert_eq!(t.to_srt(), "<font color=\"yellow\">Hey</font> 漢 & <3");
+        }
+        #[test]
+        fn unbalanced_tags() {
+            let t = StyledText::from_srt("</b>a <i>b <b>c</i> d < e");
+            assert_eq!(t.to_plain(), "a b c d < e");
+            assert_eq!(t.to_srt(), "a <i>b <b>c</b></i> d < e");
+        }
+        #[test]
+        fn ass_override_codes() {
+            let t = StyledText::from_ass(r"{\i1}Hello {\b1\c&H00FFFF&}world{\r}!\Nbye{comment}");
+            assert_eq!(t.to_plain(), "Hello world!\nbye");
+            assert_eq!(t.to_srt(), "<i>Hello <b><font color=\"#ffff00\">world</font></b></i>!\nbye");
+            // Override codes in SRT files are understood too
+            assert_eq!(StyledText::from_srt(r"{\i1}Hi{\i0}").to_vtt(), "<i>Hi</i>");
+        }
+        #[test]
+        fn styled_block_with_word_timings() {
+            let s = "WEBVTT\n\n1\n00:00:00.000 --> 00:00:02.000\n<i>Good</i> <00:00:01.000><b>night</b>\n";
+            let cap = VttParser::parse(s).expect("Should parse");
+            assert_eq!(cap.blocks[0].text(), "Good night");
+            assert_eq!(cap.blocks[0].words()[1].text(), "night");
+            assert_eq!(VttWriter::write(&cap), s.replace("<00:00:01.000><b>", "<b><00:00:01.000>"));
+            assert_eq!(SrtWriter::write(&cap), "1\n00:00:00,000 --> 00:00:02,000\n<i>Good</i> <b>night</b>\n");
+        }
+        #[test]
+        fn reflow_lines() {
+            let reflow = |s: &str, max_line_length: usize, shape: LineShape| {
+                let mut t = StyledText::from_vtt(s);
+                let fits = t.reflow(&ReflowOptions { max_line_length, shape, ..ReflowOptions::default() });
+                (fits, t.to_vtt())
+            };
+            // Short text is joined onto one line
+            assert_eq!(reflow("Hello\n  there ", 42, LineShape::BottomHeavy), (true, String::from("Hello there")));
+            // Never end a line on an article or preposition
+            assert_eq!(reflow("I went to the shop", 14, LineShape::Balanced), (true, String::from("I went\nto the shop")));
+            // Punctuation is the best place to break, even against the shape
+            assert_eq!(
+                reflow("Well, I suppose that is that.", 22, LineShape::BottomHeavy),
+                (true, String::from("Well, I suppose\nthat is that."))
+            );
+            assert_eq!(
+                reflow("one two three four five six", 20, LineShape::BottomHeavy),
+                (true, String::from("one two three\nfour five six"))
+            );
+            assert_eq!(reflow("Yes indeed sir", 12, LineShape::BottomHeavy), (true, String::from("Yes\nindeed sir")));
+            assert_eq!(reflow("Yes indeed sir", 12, LineShape::TopHeavy), (true, String::from("Yes indeed\nsir")));
+            // Styling and voices survive
+            assert_eq!(
+                reflow("<v Ann>It's <i>very</i>\n<b>important</b> to stay calm</v>", 20, LineShape::BottomHeavy),
+                (true, String::from("<v Ann>It's <i>very</i> <b>important</b>\nto stay calm</v>"))
+            );
+            // Wide characters take two columns
+            assert_eq!(reflow("漢字の 字幕です", 10, LineShape::BottomHeavy), (true, String::from("漢字の\n字幕です")));
+            // Too much text for two lines is filled, and reported
+            assert_eq!(
+                reflow("one two three four five six seven", 10, LineShape::Balanced),
+                (false, String::from("one two\nthree four\nfive six\nseven"))
+            );
+            assert!(!reflow("supercalifragilistic", 10, LineShape::Balanced).0);
+        }
+    }
     mod srt_writer {
         use super::*;
         #[test]
@@ -1703,7 +8452,8 @@ mod test {
                         speaker: Some("Pete Molfese".to_string()),
                         start: SimpleTime::from_milliseconds(0),
                         end: SimpleTime::from_milliseconds(1000),
-                        text: "Hello, world!".to_string(),
+                        text: StyledText::from("Hello, world!"),
+                        words: Vec::new(),
                     }
                 )
             };
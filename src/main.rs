@@ -1,20 +1,47 @@
-use std::{error::Error, path::PathBuf};
-use clap::{App, Arg, SubCommand};
+use std::{error::Error, path::PathBuf, collections::HashMap};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use ccap::{
     SimpleTime,
     write_caption, parse_file,
     VttParser, VttWriter, SrtWriter,
-    Caption
+    Caption,
+    AsrParser, AsrFormat, SegmentationOptions,
+    FrameRate, Timecode,
+    SyncAnchor, AlignOptions,
+    Straddle, SplitPoint, Chapter,
+    SegmentTiming, ConcatenateOptions,
+    MediaFile,
+    LintRules, LintRule,
+    ReflowOptions, LineShape,
+    CueLengthOptions,
+    ReplaceRule, ReplaceScope, ReplaceTarget,
+    SearchPattern, SearchQuery,
+    SpeakerMap, SpeakerPattern
 };
 
-fn parse_time(time: Option<&str>, as_millis: bool) -> Result<Option<SimpleTime>, Box<dyn Error>> {
+/// Parse a HH:MM:SS.mmm timestamp, or HH:MM:SS:FF timecode when a frame rate is given
+fn parse_timestamp(t: &str, fps: Option<&str>) -> Result<SimpleTime, Box<dyn Error>> {
+    if Timecode::is_timecode(t) {
+        let rate = match fps {
+            Some(name) => FrameRate::from_name(name)
+                .ok_or_else(|| format!("unknown frame rate {}", name))?,
+            None => return Err(format!("timecode {} needs a frame rate (--fps)", t).into()),
+        };
+        Ok(Timecode::parse(t, rate)?.to_simple_time())
+    }
+    else {
+        Ok(VttParser::block_timestamp(t)?)
+    }
+}
+
+fn parse_time(time: Option<&str>, as_millis: bool, fps: Option<&str>) -> Result<Option<SimpleTime>, Box<dyn Error>> {
     let t = match time {
         Some(t) => {
             if as_millis {
                 Some(SimpleTime::from_milliseconds(t.parse::<usize>()?))
             }
             else {
-                Some(VttParser::block_timestamp(&t)?)
+                Some(parse_timestamp(t, fps)?)
             }
         },
         None => None,
@@ -22,6 +49,172 @@ fn parse_time(time: Option<&str>, as_millis: bool) -> Result<Option<SimpleTime>,
     Ok(t)
 }
 
+/// Parse a frame rate given by name (e.g. 29.97df) or as a number of frames per second
+fn parse_fps(s: &str) -> Result<f64, Box<dyn Error>> {
+    match FrameRate::from_name(s) {
+        Some(rate) => Ok(rate.fps()),
+        None => {
+            let fps = s.parse::<f64>()?;
+            if fps.is_finite() && fps > 0.0 {
+                Ok(fps)
+            }
+            else {
+                Err(format!("frame rate must be a positive number (got {})", s).into())
+            }
+        },
+    }
+}
+
+/// Parse a sync anchor of the form CUE=TIME or TIME=TIME
+fn parse_anchor(s: &str, fps: Option<&str>) -> Result<(SyncAnchor, SimpleTime), Box<dyn Error>> {
+    let (from, to) = match s.find('=') {
+        Some(n) => (&s[..n], &s[n + 1..]),
+        None => return Err(format!("anchor {} should look like CUE=TIME or TIME=TIME", s).into()),
+    };
+    let anchor = if from.chars().all(|c| c.is_ascii_digit()) {
+        SyncAnchor::Cue(from.parse::<usize>()?)
+    }
+    else {
+        SyncAnchor::Time(parse_timestamp(from, fps)?)
+    };
+    Ok((anchor, parse_timestamp(to, fps)?))
+}
+
+/// Parse a time range of the form START-END
+fn parse_range(s: &str, as_millis: bool, fps: Option<&str>) -> Result<(SimpleTime, SimpleTime), Box<dyn Error>> {
+    match s.find('-') {
+        Some(n) => Ok((
+            parse_time(Some(&s[..n]), as_millis, fps)?.unwrap(),
+            parse_time(Some(&s[n + 1..]), as_millis, fps)?.unwrap(),
+        )),
+        None => Err(format!("range {} should look like START-END", s).into()),
+    }
+}
+
+/// Parse a gap of the form TIME=LENGTH, where the length is in milliseconds or a timestamp
+fn parse_gap(s: &str, as_millis: bool, fps: Option<&str>) -> Result<(SimpleTime, usize), Box<dyn Error>> {
+    let (at, length) = match s.find('=') {
+        Some(n) => (&s[..n], &s[n + 1..]),
+        None => return Err(format!("gap {} should look like TIME=LENGTH", s).into()),
+    };
+    Ok((parse_time(Some(at), as_millis, fps)?.unwrap(), parse_length(length, fps)?))
+}
+
+/// Parse a length in milliseconds or as a timestamp
+fn parse_length(s: &str, fps: Option<&str>) -> Result<usize, Box<dyn Error>> {
+    if s.chars().all(|c| c.is_ascii_digit()) {
+        Ok(s.parse::<usize>()?)
+    }
+    else {
+        Ok(parse_timestamp(s, fps)?.to_milliseconds())
+    }
+}
+
+/// Parse a cue number or range of cue numbers such as `3-10`
+fn parse_cues(s: &str) -> Result<(usize, usize), Box<dyn Error>> {
+    match s.find('-') {
+        Some(n) => Ok((s[..n].parse::<usize>()?, s[n + 1..].parse::<usize>()?)),
+        None => {
+            let n = s.parse::<usize>()?;
+            Ok((n, n))
+        },
+    }
+}
+
+/// Argument for the frame rate of timecode given on the command line
+fn fps_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("fps")
+        .long("fps")
+        .takes_value(true)
+        .possible_values(&["23.976", "24", "25", "29.97", "29.97df", "29.97ndf",
+                           "30", "50", "59.94", "59.94df", "59.94ndf", "60"])
+        .help("Frame rate for times given as HH:MM:SS:FF timecode (HH:MM:SS;FF for drop-frame)")
+}
+
+
+/// Arguments choosing the profile and timing rules, shared by lint and fix-timing
+fn timing_rule_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec!(
+        Arg::with_name("profile")
+            .long("profile")
+            .takes_value(true)
+            .help("Style guide to follow: netflix-en, bbc, dcmp (or fcc), youtube, or one from --profile-file"),
+        Arg::with_name("profile-file")
+            .long("profile-file")
+            .takes_value(true)
+            .help("TOML file of profiles, each a table of rules, which may change the built-in ones"),
+        Arg::with_name("allow-overlap")
+            .long("allow-overlap")
+            .help("Allow cues to overlap"),
+        Arg::with_name("min-gap")
+            .long("min-gap")
+            .takes_value(true)
+            .help("Shortest gap between cues in milliseconds, 0 to allow any (default 83)"),
+        Arg::with_name("close-gap")
+            .long("close-gap")
+            .takes_value(true)
+            .help("Gaps shorter than this many milliseconds should be closed to the minimum gap, 0 to allow any (default 0)"),
+        Arg::with_name("min-duration")
+            .long("min-duration")
+            .takes_value(true)
+            .help("Shortest cue in milliseconds, 0 to allow any (default 833)"),
+        Arg::with_name("max-duration")
+            .long("max-duration")
+            .takes_value(true)
+            .help("Longest cue in milliseconds (default no limit)"),
+    )
+}
+
+/// Build the rules from a profile and any options changing it
+fn parse_lint_rules(matches: &ArgMatches) -> Result<LintRules, Box<dyn Error>> {
+    // Zero turns a limit off
+    let limit = |name: &str| -> Result<Option<Option<usize>>, Box<dyn Error>> {
+        match matches.value_of(name) {
+            Some(n) => {
+                let n = n.parse::<usize>()?;
+                Ok(Some(if n > 0 { Some(n) } else { None }))
+            },
+            None => Ok(None),
+        }
+    };
+    let profiles = match matches.value_of("profile-file") {
+        Some(fname) => LintRules::profiles_from_file(fname)?,
+        None => HashMap::new(),
+    };
+    let mut rules = match matches.value_of("profile") {
+        Some(name) => match profiles.get(name) {
+            Some(rules) => rules.clone(),
+            None => LintRules::profile(name).ok_or_else(|| format!("unknown profile {}", name))?,
+        },
+        None => LintRules::default(),
+    };
+    if matches.is_present("allow-overlap") {
+        rules.allow_overlap = true;
+    }
+    if let Some(n) = limit("min-gap")? {
+        rules.min_gap = n;
+    }
+    if let Some(n) = limit("close-gap")? {
+        rules.close_gap = n;
+    }
+    if let Some(n) = limit("min-duration")? {
+        rules.min_duration = n;
+    }
+    if let Some(n) = limit("max-duration")? {
+        rules.max_duration = n;
+    }
+    if let Some(n) = matches.value_of("max-cps") {
+        let n = n.parse::<f64>()?;
+        rules.max_chars_per_second = if n > 0.0 { Some(n) } else { None };
+    }
+    if let Some(n) = limit("max-line-length")? {
+        rules.max_line_length = n;
+    }
+    if let Some(n) = limit("max-lines")? {
+        rules.max_lines = n;
+    }
+    Ok(rules)
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("Captain Caption")
@@ -34,6 +227,41 @@ fn main() -> Result<(), Box<dyn Error>> {
                              .required(true)
                              .takes_value(true)
                              .help("The file to get information for")))
+                    .subcommand(
+                        SubCommand::with_name("lint")
+                        .about("Check caption files against quality rules")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .multiple(true)
+                             .help("The files to check"))
+                        .args(&timing_rule_args())
+                        .arg(Arg::with_name("max-cps")
+                             .long("max-cps")
+                             .takes_value(true)
+                             .help("Highest reading speed in characters per second, 0 for no limit (default 17)"))
+                        .arg(Arg::with_name("max-line-length")
+                             .long("max-line-length")
+                             .takes_value(true)
+                             .help("Longest line in characters, 0 for no limit (default 42)"))
+                        .arg(Arg::with_name("max-lines")
+                             .long("max-lines")
+                             .takes_value(true)
+                             .help("Most lines in a cue, 0 for no limit (default 2)"))
+                        .after_help("Options given along with a profile change its rules. Exits with status 1 if any rule is broken"))
+                    .subcommand(
+                        SubCommand::with_name("fix-timing")
+                        .about("Fix overlapping, crowded, short and long cues")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("Name of the resulting file"))
+                        .args(&timing_rule_args())
+                        .after_help("Options given along with a profile change its rules. Each change made is printed, then any overlap that could not be fixed"))
                     .subcommand(
                         SubCommand::with_name("offset")
                         .about("offset a caption file by some time")
@@ -48,13 +276,33 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .arg(Arg::with_name("OFFSET")
                              .required(true)
                              .takes_value(true)
-                             .help("Offset to apply as HH:MM:SS.mmm"))
+                             .help("Offset to apply as HH:MM:SS.mmm or HH:MM:SS:FF with --fps"))
                         .arg(Arg::with_name("millis")
                              .long("millis")
                              .help("Supply offset in milliseconds instead"))
                         .arg(Arg::with_name("subtract")
                              .long("subtract")
-                             .help("Subtract instead of add offset")))
+                             .help("Subtract instead of add offset"))
+                        .arg(fps_arg()))
+                    .subcommand(
+                        SubCommand::with_name("at")
+                        .about("Show the cues on screen at a time")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("TIME")
+                             .required(true)
+                             .takes_value(true)
+                             .help("Time to look at"))
+                        .arg(Arg::with_name("to")
+                             .long("to")
+                             .takes_value(true)
+                             .help("Show every cue on screen from TIME up to this time instead"))
+                        .arg(Arg::with_name("millis")
+                             .long("millis")
+                             .help("Supply times in milliseconds instead"))
+                        .arg(fps_arg()))
                     .subcommand(
                         SubCommand::with_name("concatenate")
                         .about("Concatenate multiple caption files")
@@ -66,7 +314,32 @@ fn main() -> Result<(), Box<dyn Error>> {
                              .required(true)
                              .takes_value(true)
                              .min_values(2)
-                             .help("The files to concatenate")))
+                             .help("The files to concatenate"))
+                        .arg(Arg::with_name("durations")
+                             .long("durations")
+                             .takes_value(true)
+                             .use_delimiter(true)
+                             .help("Comma-separated length of each video, in milliseconds or as timestamps"))
+                        .arg(Arg::with_name("media")
+                             .long("media")
+                             .takes_value(true)
+                             .multiple(true)
+                             .conflicts_with("durations")
+                             .help("Video file for each caption, to read its length from (MP4 or Matroska)"))
+                        .arg(Arg::with_name("starts")
+                             .long("starts")
+                             .takes_value(true)
+                             .use_delimiter(true)
+                             .help("Comma-separated time each caption starts at"))
+                        .arg(Arg::with_name("gap")
+                             .long("gap")
+                             .takes_value(true)
+                             .help("Time between one video and the next, in milliseconds or as a timestamp"))
+                        .arg(Arg::with_name("merge-headers")
+                             .long("merge-headers")
+                             .help("Keep the headers (such as styles) of the inputs"))
+                        .arg(fps_arg())
+                        .after_help("Without --durations, --media or --starts each caption starts at the last cue of the one before"))
                     .subcommand(
                         SubCommand::with_name("convert")
                         .about("Convert caption formats")
@@ -103,16 +376,544 @@ fn main() -> Result<(), Box<dyn Error>> {
                              .takes_value(true)
                              .required_unless("from")
                              .help("Time to crop to (inclusive)"))
+                        .arg(fps_arg())
                         .after_help("Creates a new file that is cropped"))
+                    .subcommand(
+                        SubCommand::with_name("cut")
+                        .about("Remove time ranges from a caption, closing the gaps")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("RANGE")
+                             .required(true)
+                             .multiple(true)
+                             .help("Range to remove, as START-END"))
+                        .arg(Arg::with_name("millis")
+                             .long("millis")
+                             .help("Supply ranges in milliseconds instead"))
+                        .arg(fps_arg())
+                        .after_help("For example, to remove two advert breaks\n    ccap cut in.vtt out.vtt 00:10:00.000-00:12:30.000 00:25:00.000-00:27:00.000"))
+                    .subcommand(
+                        SubCommand::with_name("insert-gap")
+                        .about("Insert gaps in a caption, e.g. for advert breaks")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("GAP")
+                             .required(true)
+                             .multiple(true)
+                             .help("Gap to insert, as TIME=LENGTH"))
+                        .arg(Arg::with_name("millis")
+                             .long("millis")
+                             .help("Supply insertion times in milliseconds instead"))
+                        .arg(Arg::with_name("straddle")
+                             .long("straddle")
+                             .takes_value(true)
+                             .possible_values(&["split", "before"])
+                             .default_value("split")
+                             .help("Show a block on screen at a gap on both sides of it, or only before it"))
+                        .arg(fps_arg())
+                        .after_help("LENGTH is in milliseconds or a timestamp, for example\n    ccap insert-gap in.vtt out.vtt 00:10:00.000=00:02:30.000 00:25:00.000=90000"))
+                    .subcommand(
+                        SubCommand::with_name("extract")
+                        .about("Extract subtitle tracks from an MP4 or Matroska video")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The video filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required_unless("list")
+                             .takes_value(true)
+                             .help("The output filename; with several tracks, each gets its number and language, e.g. out.3.eng.vtt"))
+                        .arg(Arg::with_name("track")
+                             .long("track")
+                             .takes_value(true)
+                             .help("Only extract the track with this number"))
+                        .arg(Arg::with_name("list")
+                             .long("list")
+                             .help("List the subtitle tracks instead"))
+                        .after_help("Reads tx3g and wvtt tracks from MP4, and S_TEXT/UTF8, S_TEXT/WEBVTT and S_TEXT/ASS tracks from Matroska"))
+                    .subcommand(
+                        SubCommand::with_name("import")
+                        .about("Import word-level speech recognition JSON")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The JSON file from Whisper, AWS Transcribe or Google STT"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("format")
+                             .long("format")
+                             .takes_value(true)
+                             .possible_values(&["whisper", "aws", "google"])
+                             .help("Source of the JSON (detected if not given)"))
+                        .arg(Arg::with_name("max-chars")
+                             .long("max-chars")
+                             .takes_value(true)
+                             .help("Maximum characters per line (default 42)"))
+                        .arg(Arg::with_name("max-lines")
+                             .long("max-lines")
+                             .takes_value(true)
+                             .help("Maximum lines per block (default 2)"))
+                        .arg(Arg::with_name("max-duration")
+                             .long("max-duration")
+                             .takes_value(true)
+                             .help("Maximum block length in milliseconds (default 7000)"))
+                        .arg(Arg::with_name("max-pause")
+                             .long("max-pause")
+                             .takes_value(true)
+                             .help("Start a new block after a pause of this many milliseconds (default 1500)"))
+                        .arg(Arg::with_name("no-speaker-break")
+                             .long("no-speaker-break")
+                             .help("Do not start a new block when the speaker changes")))
+                    .subcommand(
+                        SubCommand::with_name("retime")
+                        .about("Scale all times, e.g. for a frame rate conversion")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("from-fps")
+                             .long("from-fps")
+                             .takes_value(true)
+                             .requires("to-fps")
+                             .required_unless("factor")
+                             .help("Frame rate the captions were timed for"))
+                        .arg(Arg::with_name("to-fps")
+                             .long("to-fps")
+                             .takes_value(true)
+                             .requires("from-fps")
+                             .help("Frame rate the video has been converted to"))
+                        .arg(Arg::with_name("factor")
+                             .long("factor")
+                             .takes_value(true)
+                             .conflicts_with_all(&["from-fps", "to-fps"])
+                             .help("Multiply all times by this factor instead"))
+                        .arg(Arg::with_name("anchor")
+                             .long("anchor")
+                             .takes_value(true)
+                             .help("Time that stays fixed while scaling (default 00:00:00.000)"))
+                        .arg(fps_arg())
+                        .after_help("Converting from 23.976 to 25 fps (PAL speed-up) scales times by 23.976/25"))
+                    .subcommand(
+                        SubCommand::with_name("split")
+                        .about("Split a caption into several files")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename; parts are numbered, e.g. out-1.vtt, out-2.vtt"))
+                        .arg(Arg::with_name("at")
+                             .long("at")
+                             .takes_value(true)
+                             .multiple(true)
+                             .number_of_values(1)
+                             .required_unless_one(&["cue", "chapters"])
+                             .help("Time to split at"))
+                        .arg(Arg::with_name("cue")
+                             .long("cue")
+                             .takes_value(true)
+                             .multiple(true)
+                             .number_of_values(1)
+                             .help("Cue to start a new part at, counting from 1"))
+                        .arg(Arg::with_name("chapters")
+                             .long("chapters")
+                             .takes_value(true)
+                             .help("File of chapters to split at, one `[H:]MM:SS Title` per line or ffmpeg metadata"))
+                        .arg(Arg::with_name("millis")
+                             .long("millis")
+                             .help("Supply times in milliseconds instead"))
+                        .arg(fps_arg())
+                        .after_help("Each part starts at 00:00:00.000, and a cue on screen at a split is kept in both parts"))
+                    .subcommand(
+                        SubCommand::with_name("sync")
+                        .about("Correct offset and drift using two anchor points")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("FIRST")
+                             .required(true)
+                             .takes_value(true)
+                             .help("First anchor as CUE=TIME or TIME=TIME"))
+                        .arg(Arg::with_name("SECOND")
+                             .required(true)
+                             .takes_value(true)
+                             .help("Second anchor as CUE=TIME or TIME=TIME"))
+                        .arg(fps_arg())
+                        .after_help("Each anchor pairs a cue number (counting from 1) or a time in the caption\nwith the time it should be at in the video, for example\n    ccap sync in.srt out.srt 3=00:00:12.500 00:41:00.000=00:41:07.250"))
+                    .subcommand(
+                        SubCommand::with_name("align")
+                        .about("Estimate and correct timing against a reference caption file")
+                        .arg(Arg::with_name("REFERENCE")
+                             .required(true)
+                             .takes_value(true)
+                             .help("Correctly timed caption file"))
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("Caption file to be corrected"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("drift")
+                             .long("drift")
+                             .help("Also estimate drift (a difference in rate)"))
+                        .arg(Arg::with_name("max-offset")
+                             .long("max-offset")
+                             .takes_value(true)
+                             .help("Largest offset to consider in milliseconds (default 60000)"))
+                        .arg(Arg::with_name("max-drift")
+                             .long("max-drift")
+                             .takes_value(true)
+                             .help("Largest drift to consider as a fraction (default 0.05)"))
+                        .after_help("Matches the times cues are on screen, so the text may be in another language"))
+                    .subcommand(
+                        SubCommand::with_name("apply-transcript")
+                        .about("Replace the caption text with a corrected transcript, keeping the timing")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("TRANSCRIPT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("Plain text file with the corrected transcript"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .after_help("Stretches that could not be matched with confidence are listed for review"))
+                    .subcommand(
+                        SubCommand::with_name("reflow")
+                        .about("Re-break the lines of caption text to fit a width and line count")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("max-line-length")
+                             .long("max-line-length")
+                             .takes_value(true)
+                             .help("Widest line in display columns (default 42)"))
+                        .arg(Arg::with_name("max-lines")
+                             .long("max-lines")
+                             .takes_value(true)
+                             .help("Most lines in a cue (default 2)"))
+                        .arg(Arg::with_name("shape")
+                             .long("shape")
+                             .takes_value(true)
+                             .possible_values(&["balanced", "bottom-heavy", "top-heavy"])
+                             .help("Preferred shape of the lines (default bottom-heavy)"))
+                        .after_help("Cues with too much text for the lines are listed, to be split or shortened"))
+                    .subcommand(
+                        SubCommand::with_name("resegment")
+                        .about("Split cues that are too long and merge cues that are too short")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("max-duration")
+                             .long("max-duration")
+                             .takes_value(true)
+                             .help("Longest cue in milliseconds (default 7000)"))
+                        .arg(Arg::with_name("max-chars")
+                             .long("max-chars")
+                             .takes_value(true)
+                             .help("Most characters in a cue (default 84)"))
+                        .arg(Arg::with_name("min-duration")
+                             .long("min-duration")
+                             .takes_value(true)
+                             .help("Cues shorter than this many milliseconds are merged (default 833)"))
+                        .arg(Arg::with_name("max-gap")
+                             .long("max-gap")
+                             .takes_value(true)
+                             .help("Longest gap in milliseconds between cues that are merged (default 500)"))
+                        .arg(Arg::with_name("no-split")
+                             .long("no-split")
+                             .help("Only merge short cues"))
+                        .arg(Arg::with_name("no-merge")
+                             .long("no-merge")
+                             .conflicts_with("no-split")
+                             .help("Only split long cues"))
+                        .after_help("Long cues are split at the end of a sentence or clause where possible, sharing out their time by the length of each piece. Only cues from the same speaker are merged"))
+                    .subcommand(
+                        SubCommand::with_name("dedupe")
+                        .about("Remove the repeated lines of rolling automatic captions")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .after_help("Text each cue repeats from the end of the one before is removed, along with cues left empty, and cues no longer overlap"))
+                    .subcommand(
+                        SubCommand::with_name("replace")
+                        .about("Find and replace text in captions with regular expressions")
+                        .arg(Arg::with_name("INPUT")
+                             .required(true)
+                             .takes_value(true)
+                             .help("The input filename"))
+                        .arg(Arg::with_name("OUTPUT")
+                             .required_unless("dry-run")
+                             .takes_value(true)
+                             .help("The output filename"))
+                        .arg(Arg::with_name("rule")
+                             .short("e")
+                             .long("rule")
+                             .takes_value(true)
+                             .multiple(true)
+                             .number_of_values(1)
+                             .required_unless("rules")
+                             .help("Rule written as 'PATTERN => REPLACEMENT', where the replacement may use $1 or ${name} for capture groups"))
+                        .arg(Arg::with_name("rules")
+                             .long("rules")
+                             .takes_value(true)
+                             .help("File of rules, one per line, with # for comments"))
+                        .arg(Arg::with_name("ignore-case")
+                             .short("i")
+                             .long("ignore-case")
+                             .help("Match regardless of case"))
+                        .arg(Arg::with_name("whole-word")
+                             .short("w")
+                             .long("whole-word")
+                             .help("Only match whole words"))
+                        .arg(Arg::with_name("target")
+                             .long("target")
+                             .takes_value(true)
+                             .possible_values(&["text", "speaker", "both"])
+                             .help("Change the text, the speaker names or both (default text)"))
+                        .arg(Arg::with_name("cues")
+                             .long("cues")
+                             .takes_value(true)
+                             .help("Only change these cues, given as N or FIRST-LAST counting from 1"))
+                        .arg(Arg::with_name("from")
+                             .long("from")
+                             .takes_value(true)
+                             .help("Only change cues on screen from this time"))
+                        .arg(Arg::with_name("to")
+                             .long("to")
+                             .takes_value(true)
+                             .help("Only change cues on screen up to this time"))
+                        .arg(Arg::with_name("millis")
+                             .long("millis")
+                             .help("Supply times in milliseconds instead"))
+                        .arg(fps_arg())
+                        .arg(Arg::with_name("dry-run")
+                             .long("dry-run")
+                             .help("List the changes without writing them"))
+                        .after_help("Rules given with --rule are applied after those in --rules, each in turn. Every change is listed"))
+                    .subcommand(
+                        SubCommand::with_name("grep")
+                        .about("Search caption files for cues by text, speaker or time")
+                        .arg(Arg::with_name("PATTERN")
+                             .required(true)
+                             .takes_value(true)
+                             .help("Regular expression to look for, or words with --fuzzy"))
+                        .arg(Arg::with_name("FILES")
+                             .required(true)
+                             .takes_value(true)
+                             .multiple(true)
+                             .help("The files to search"))
+                        .arg(Arg::with_name("ignore-case")
+                             .short("i")
+                             .long("ignore-case")
+                             .help("Match regardless of case"))
+                        .arg(Arg::with_name("fuzzy")
+                             .long("fuzzy")
+                             .help("Look for the words in order, ignoring case and punctuation and allowing for typos"))
+                        .arg(Arg::with_name("speaker")
+                             .long("speaker")
+                             .takes_value(true)
+                             .help("Only cues whose speaker contains this, ignoring case"))
+                        .arg(Arg::with_name("from")
+                             .long("from")
+                             .takes_value(true)
+                             .help("Only cues on screen from this time"))
+                        .arg(Arg::with_name("to")
+                             .long("to")
+                             .takes_value(true)
+                             .help("Only cues on screen up to this time"))
+                        .arg(Arg::with_name("millis")
+                             .long("millis")
+                             .help("Supply times in milliseconds instead"))
+                        .arg(fps_arg())
+                        .arg(Arg::with_name("output")
+                             .long("output")
+                             .takes_value(true)
+                             .help("Write the matching cues to a caption file as well"))
+                        .arg(Arg::with_name("json")
+                             .long("json")
+                             .help("Print the matches as JSON"))
+                        .after_help("Exits with status 1 if nothing matches"))
+                    .subcommand(
+                        SubCommand::with_name("speakers")
+                        .about("List, rename, merge and anonymise speakers")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .subcommand(
+                            SubCommand::with_name("list")
+                            .about("List the speakers with their cues and talk time")
+                            .arg(Arg::with_name("INPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The input filename")))
+                        .subcommand(
+                            SubCommand::with_name("rename")
+                            .about("Rename speakers, merging those given the same name")
+                            .arg(Arg::with_name("INPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The input filename"))
+                            .arg(Arg::with_name("OUTPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The output filename"))
+                            .arg(Arg::with_name("map")
+                                 .long("map")
+                                 .takes_value(true)
+                                 .required_unless("rename")
+                                 .help("File of names, one 'OLD => NEW' per line, where OLD may list names separated by |"))
+                            .arg(Arg::with_name("rename")
+                                 .long("rename")
+                                 .takes_value(true)
+                                 .multiple(true)
+                                 .number_of_values(1)
+                                 .help("Names written as 'OLD => NEW'"))
+                            .after_help("Names are matched ignoring case. Renaming a speaker to nothing removes it"))
+                        .subcommand(
+                            SubCommand::with_name("anonymise")
+                            .about("Replace speaker names with numbered labels")
+                            .arg(Arg::with_name("INPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The input filename"))
+                            .arg(Arg::with_name("OUTPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The output filename"))
+                            .arg(Arg::with_name("label")
+                                 .long("label")
+                                 .takes_value(true)
+                                 .help("Label to number the speakers with (default Speaker)"))
+                            .arg(Arg::with_name("key")
+                                 .long("key")
+                                 .takes_value(true)
+                                 .help("Write the real names to this file, which can be used with rename --map to restore them"))
+                            .after_help("Speakers are numbered in order of first appearance. Names mentioned in the text are left alone"))
+                        .subcommand(
+                            SubCommand::with_name("extract")
+                            .about("Move speaker labels written in the text into the speaker")
+                            .arg(Arg::with_name("INPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The input filename"))
+                            .arg(Arg::with_name("OUTPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The output filename"))
+                            .arg(Arg::with_name("preset")
+                                 .long("preset")
+                                 .takes_value(true)
+                                 .multiple(true)
+                                 .number_of_values(1)
+                                 .possible_values(&SpeakerPattern::PRESETS)
+                                 .help("Label style to look for: dash (- John: text), brackets ([John] text), parens ((John) text), caps (JOHN: text) or colon (John: text)"))
+                            .arg(Arg::with_name("pattern")
+                                 .long("pattern")
+                                 .takes_value(true)
+                                 .multiple(true)
+                                 .number_of_values(1)
+                                 .help("Regular expression for a label at the start of the text, with the name in a group called speaker or the first group"))
+                            .after_help("Patterns are tried in the order given, with the dash, brackets and caps presets used if none are given (parens and colon can mistake sound descriptions and other text for speakers). Cues that already have a speaker are left alone"))
+                        .subcommand(
+                            SubCommand::with_name("render")
+                            .about("Write the speakers into the text, for formats without speakers")
+                            .arg(Arg::with_name("INPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The input filename"))
+                            .arg(Arg::with_name("OUTPUT")
+                                 .required(true)
+                                 .takes_value(true)
+                                 .help("The output filename"))
+                            .arg(Arg::with_name("template")
+                                 .long("template")
+                                 .takes_value(true)
+                                 .help("Label to write before the text, with {speaker} for the name or {SPEAKER} for it in capitals (default '{SPEAKER}: ')"))
+                            .arg(Arg::with_name("changes-only")
+                                 .long("changes-only")
+                                 .help("Only label a cue when the speaker changes"))))
                     .get_matches();
    
     // Get the subcommand to run and run it
     if let Some(info_matches) = matches.subcommand_matches("info") {
         let input = info_matches.value_of("INPUT").unwrap();
-        let caption = parse_file(&input)?;
+        let caption = parse_file(input)?;
         println!("File: {}", input);
         caption.print_report();
     }
+    if let Some(lint_matches) = matches.subcommand_matches("lint") {
+        let rules = parse_lint_rules(lint_matches)?;
+        let mut total = 0;
+        for input in lint_matches.values_of("INPUT").unwrap() {
+            let caption = parse_file(input)?;
+            let violations = caption.lint(&rules);
+            for v in violations.iter() {
+                println!("{}: {}", input, v);
+            }
+            total += violations.len();
+        }
+        if total > 0 {
+            println!("{} problems found", total);
+            std::process::exit(1);
+        }
+    }
+    if let Some(fix_matches) = matches.subcommand_matches("fix-timing") {
+        let input = fix_matches.value_of("INPUT").unwrap();
+        let output = fix_matches.value_of("OUTPUT").unwrap();
+        let rules = parse_lint_rules(fix_matches)?;
+        let mut caption = parse_file(input)?;
+        for fix in caption.fix_timing(&rules).iter() {
+            println!("{}", fix);
+        }
+        for v in caption.lint(&rules).iter().filter(|v| v.rule == LintRule::Overlap) {
+            println!("not fixed: {}", v);
+        }
+        write_caption(output, &caption)?;
+    }
     if let Some(offset_matches) = matches.subcommand_matches("offset") {
         let input = offset_matches.value_of("INPUT").unwrap();
         let output = offset_matches.value_of("OUTPUT").unwrap();
@@ -122,7 +923,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                 offset_str.parse::<isize>()?
             }
             else {
-                let st = VttParser::block_timestamp(&offset_str)?;
+                let st = parse_timestamp(offset_str, offset_matches.value_of("fps"))?;
                 st.to_milliseconds() as isize
             }
         };
@@ -134,9 +935,27 @@ fn main() -> Result<(), Box<dyn Error>> {
                 offset_millis
             }
         };
-        let mut cap = parse_file(&input)?;
+        let mut cap = parse_file(input)?;
         cap.offset_milliseconds(offset)?;
-        write_caption(&output, &cap)?;
+        write_caption(output, &cap)?;
+    }
+    if let Some(at_matches) = matches.subcommand_matches("at") {
+        let caption = parse_file(at_matches.value_of("INPUT").unwrap())?;
+        let use_millis = at_matches.is_present("millis");
+        let fps = at_matches.value_of("fps");
+        let time = parse_time(at_matches.value_of("TIME"), use_millis, fps)?.unwrap();
+        let index = caption.index();
+        let cues = match parse_time(at_matches.value_of("to"), use_millis, fps)? {
+            Some(to) => index.overlapping(time, to),
+            None => index.at(time),
+        };
+        for cb in cues {
+            match cb.speaker() {
+                Some(speaker) => println!("{} --> {} {}:", cb.start(), cb.end(), speaker),
+                None => println!("{} --> {}", cb.start(), cb.end()),
+            }
+            println!("{}\n", cb.text());
+        }
     }
     if let Some(concatenate_matches) = matches.subcommand_matches("concatenate") {
         let output = concatenate_matches.value_of("OUTPUT").unwrap();
@@ -145,14 +964,50 @@ fn main() -> Result<(), Box<dyn Error>> {
             .collect();
         let mut captions: Vec<Caption> = Vec::with_capacity(files.len());
         for f in files.iter() {
-            captions.push(parse_file(&f)?);
+            captions.push(parse_file(f)?);
         }
-        let mega_caption = Caption::concatenate(captions);
-        write_caption(&output, &mega_caption)?;
+        let fps = concatenate_matches.value_of("fps");
+        let mut timings = vec!(SegmentTiming::default(); captions.len());
+        // Each of these options gives one value per caption
+        let check_count = |name: &str, count: usize| -> Result<(), Box<dyn Error>> {
+            if count == captions.len() {
+                Ok(())
+            }
+            else {
+                Err(format!("--{} needs one value for each of the {} captions (got {})", name, captions.len(), count).into())
+            }
+        };
+        if let Some(durations) = concatenate_matches.values_of("durations") {
+            check_count("durations", durations.len())?;
+            for (timing, d) in timings.iter_mut().zip(durations) {
+                timing.duration = Some(parse_length(d, fps)?);
+            }
+        }
+        if let Some(media) = concatenate_matches.values_of("media") {
+            check_count("media", media.len())?;
+            for (timing, m) in timings.iter_mut().zip(media) {
+                timing.duration = Some(MediaFile::duration_from_file(m)?);
+            }
+        }
+        if let Some(starts) = concatenate_matches.values_of("starts") {
+            check_count("starts", starts.len())?;
+            for (timing, t) in timings.iter_mut().zip(starts) {
+                timing.start = Some(parse_timestamp(t, fps)?);
+            }
+        }
+        let opts = ConcatenateOptions {
+            gap: match concatenate_matches.value_of("gap") {
+                Some(g) => parse_length(g, fps)?,
+                None => 0,
+            },
+            merge_headers: concatenate_matches.is_present("merge-headers"),
+        };
+        let mega_caption = Caption::concatenate_with(captions, &timings, &opts)?;
+        write_caption(output, &mega_caption)?;
     }
     if let Some(convert_matches) = matches.subcommand_matches("convert") {
         let input = convert_matches.value_of("INPUT").unwrap();
-        let caption = parse_file(&input)?;
+        let caption = parse_file(input)?;
         if convert_matches.is_present("srt") {
             let mut path = PathBuf::from(&input);
             path.set_extension("srt");
@@ -167,12 +1022,436 @@ fn main() -> Result<(), Box<dyn Error>> {
     if let Some(crop_matches) = matches.subcommand_matches("crop") {
         let input = crop_matches.value_of("INPUT").unwrap();
         let output = crop_matches.value_of("OUTPUT").unwrap();
-        let mut caption = parse_file(&input)?;
+        let mut caption = parse_file(input)?;
         let use_millis = crop_matches.is_present("millis");
-        let from = parse_time(crop_matches.value_of("from"), use_millis)?;
-        let to = parse_time(crop_matches.value_of("to"), use_millis)?;
+        let fps = crop_matches.value_of("fps");
+        let from = parse_time(crop_matches.value_of("from"), use_millis, fps)?;
+        let to = parse_time(crop_matches.value_of("to"), use_millis, fps)?;
         caption.crop(from, to);
-        write_caption(&output, &caption)?;
+        write_caption(output, &caption)?;
+    }
+    if let Some(cut_matches) = matches.subcommand_matches("cut") {
+        let input = cut_matches.value_of("INPUT").unwrap();
+        let output = cut_matches.value_of("OUTPUT").unwrap();
+        let use_millis = cut_matches.is_present("millis");
+        let fps = cut_matches.value_of("fps");
+        let ranges = cut_matches.values_of("RANGE").unwrap()
+            .map(|r| parse_range(r, use_millis, fps))
+            .collect::<Result<Vec<(SimpleTime, SimpleTime)>, Box<dyn Error>>>()?;
+        let mut caption = parse_file(input)?;
+        caption.cut(&ranges);
+        write_caption(output, &caption)?;
+    }
+    if let Some(gap_matches) = matches.subcommand_matches("insert-gap") {
+        let input = gap_matches.value_of("INPUT").unwrap();
+        let output = gap_matches.value_of("OUTPUT").unwrap();
+        let use_millis = gap_matches.is_present("millis");
+        let fps = gap_matches.value_of("fps");
+        let gaps = gap_matches.values_of("GAP").unwrap()
+            .map(|g| parse_gap(g, use_millis, fps))
+            .collect::<Result<Vec<(SimpleTime, usize)>, Box<dyn Error>>>()?;
+        let straddle = match gap_matches.value_of("straddle") {
+            Some("before") => Straddle::Before,
+            _ => Straddle::Split,
+        };
+        let mut caption = parse_file(input)?;
+        caption.insert_gaps(&gaps, straddle);
+        write_caption(output, &caption)?;
+    }
+    if let Some(retime_matches) = matches.subcommand_matches("retime") {
+        let input = retime_matches.value_of("INPUT").unwrap();
+        let output = retime_matches.value_of("OUTPUT").unwrap();
+        let factor = match retime_matches.value_of("factor") {
+            Some(f) => {
+                let factor = f.parse::<f64>()?;
+                if factor <= 0.0 || !factor.is_finite() {
+                    return Err(format!("factor must be positive (got {})", f).into());
+                }
+                factor
+            },
+            None => {
+                let factor = parse_fps(retime_matches.value_of("from-fps").unwrap())?
+                    / parse_fps(retime_matches.value_of("to-fps").unwrap())?;
+                if factor <= 0.0 || !factor.is_finite() {
+                    return Err(format!("frame rates give an unusable factor ({})", factor).into());
+                }
+                factor
+            },
+        };
+        let anchor = parse_time(retime_matches.value_of("anchor"), false, retime_matches.value_of("fps"))?
+            .unwrap_or_else(|| SimpleTime::from_milliseconds(0));
+        let mut caption = parse_file(input)?;
+        caption.scale(factor, anchor)?;
+        write_caption(output, &caption)?;
+    }
+    if let Some(split_matches) = matches.subcommand_matches("split") {
+        let input = split_matches.value_of("INPUT").unwrap();
+        let output = PathBuf::from(split_matches.value_of("OUTPUT").unwrap());
+        let use_millis = split_matches.is_present("millis");
+        let fps = split_matches.value_of("fps");
+        let mut points: Vec<SplitPoint> = Vec::new();
+        let mut titles: Vec<(SimpleTime, String)> = Vec::new();
+        if let Some(times) = split_matches.values_of("at") {
+            for t in times {
+                points.push(SplitPoint::Time(parse_time(Some(t), use_millis, fps)?.unwrap()));
+            }
+        }
+        if let Some(cues) = split_matches.values_of("cue") {
+            for n in cues {
+                points.push(SplitPoint::Cue(n.parse::<usize>()?));
+            }
+        }
+        if let Some(fname) = split_matches.value_of("chapters") {
+            for chapter in Chapter::from_file(fname)? {
+                // A chapter at the very start doesn't need a split
+                if chapter.start.to_milliseconds() > 0 {
+                    points.push(SplitPoint::Time(chapter.start));
+                }
+                titles.push((chapter.start, chapter.title));
+            }
+        }
+        let caption = parse_file(input)?;
+        let parts = caption.split(&points)?;
+        let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let extension = output.extension().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let fnames: Vec<String> = (1..=parts.len())
+            .map(|n| output.with_file_name(format!("{}-{}.{}", stem, n, extension)).to_string_lossy().to_string())
+            .collect();
+        for (fname, part) in fnames.iter().zip(parts.iter()) {
+            write_caption(fname, part)?;
+        }
+        if !titles.is_empty() {
+            // Say which file each chapter ended up in
+            let mut starts = caption.split_times(&points)?;
+            starts.insert(0, SimpleTime::from_milliseconds(0));
+            for (t, title) in titles {
+                let n = starts.iter().rposition(|s| *s <= t).unwrap();
+                println!("{}: {} ({})", fnames[n], title, t);
+            }
+        }
+    }
+    if let Some(sync_matches) = matches.subcommand_matches("sync") {
+        let input = sync_matches.value_of("INPUT").unwrap();
+        let output = sync_matches.value_of("OUTPUT").unwrap();
+        let fps = sync_matches.value_of("fps");
+        let first = parse_anchor(sync_matches.value_of("FIRST").unwrap(), fps)?;
+        let second = parse_anchor(sync_matches.value_of("SECOND").unwrap(), fps)?;
+        let mut caption = parse_file(input)?;
+        let transform = caption.sync(first, second)?;
+        println!("Offset: {:+.0} ms", transform.offset);
+        println!("Rate: {:.6} ({:+.0} ppm)", transform.rate, (transform.rate - 1.0) * 1e6);
+        write_caption(output, &caption)?;
+    }
+    if let Some(align_matches) = matches.subcommand_matches("align") {
+        let reference = parse_file(align_matches.value_of("REFERENCE").unwrap())?;
+        let input = align_matches.value_of("INPUT").unwrap();
+        let output = align_matches.value_of("OUTPUT").unwrap();
+        let mut opts = AlignOptions {
+            estimate_drift: align_matches.is_present("drift"),
+            ..AlignOptions::default()
+        };
+        if let Some(n) = align_matches.value_of("max-offset") {
+            opts.max_offset = n.parse::<usize>()?;
+        }
+        if let Some(n) = align_matches.value_of("max-drift") {
+            opts.max_drift = n.parse::<f64>()?;
+            if !(opts.max_drift > 0.0 && opts.max_drift < 1.0) {
+                return Err(format!("max drift must be a fraction between 0 and 1 (got {})", n).into());
+            }
+        }
+        let mut caption = parse_file(input)?;
+        let alignment = caption.align(&reference, &opts)?;
+        println!("Offset: {:+.0} ms", alignment.transform.offset);
+        if opts.estimate_drift {
+            println!("Rate: {:.6} ({:+.0} ppm)", alignment.transform.rate,
+                     (alignment.transform.rate - 1.0) * 1e6);
+        }
+        println!("Confidence: {:.2}", alignment.confidence);
+        write_caption(output, &caption)?;
+    }
+    if let Some(transcript_matches) = matches.subcommand_matches("apply-transcript") {
+        let input = transcript_matches.value_of("INPUT").unwrap();
+        let output = transcript_matches.value_of("OUTPUT").unwrap();
+        let transcript = std::fs::read_to_string(transcript_matches.value_of("TRANSCRIPT").unwrap())?;
+        let mut caption = parse_file(input)?;
+        let original = caption.clone();
+        let uncertain = caption.apply_transcript(&transcript);
+        for region in uncertain.iter() {
+            println!("Check cues {}-{} ({} --> {})",
+                     region.first_block + 1, region.last_block + 1,
+                     original.blocks[region.first_block].start(),
+                     original.blocks[region.last_block].end());
+            println!("    was: {}", region.original);
+            println!("    now: {}", region.revised);
+        }
+        write_caption(output, &caption)?;
+    }
+    if let Some(reflow_matches) = matches.subcommand_matches("reflow") {
+        let input = reflow_matches.value_of("INPUT").unwrap();
+        let output = reflow_matches.value_of("OUTPUT").unwrap();
+        let mut opts = ReflowOptions::default();
+        if let Some(n) = reflow_matches.value_of("max-line-length") {
+            opts.max_line_length = n.parse::<usize>()?;
+        }
+        if let Some(n) = reflow_matches.value_of("max-lines") {
+            opts.max_lines = n.parse::<usize>()?;
+        }
+        opts.shape = match reflow_matches.value_of("shape") {
+            Some("balanced") => LineShape::Balanced,
+            Some("top-heavy") => LineShape::TopHeavy,
+            _ => LineShape::BottomHeavy,
+        };
+        let mut caption = parse_file(input)?;
+        for i in caption.reflow(&opts) {
+            println!("Cue {} at {} does not fit", i + 1, caption.blocks[i].start());
+        }
+        write_caption(output, &caption)?;
+    }
+    if let Some(resegment_matches) = matches.subcommand_matches("resegment") {
+        let input = resegment_matches.value_of("INPUT").unwrap();
+        let output = resegment_matches.value_of("OUTPUT").unwrap();
+        let mut opts = CueLengthOptions::default();
+        if let Some(n) = resegment_matches.value_of("max-duration") {
+            opts.max_duration = n.parse::<usize>()?;
+        }
+        if let Some(n) = resegment_matches.value_of("max-chars") {
+            opts.max_chars = n.parse::<usize>()?;
+        }
+        if let Some(n) = resegment_matches.value_of("min-duration") {
+            opts.min_duration = n.parse::<usize>()?;
+        }
+        if let Some(n) = resegment_matches.value_of("max-gap") {
+            opts.max_merge_gap = n.parse::<usize>()?;
+        }
+        let mut caption = parse_file(input)?;
+        if !resegment_matches.is_present("no-split") {
+            println!("Split {} long cues", caption.split_long_cues(&opts));
+        }
+        if !resegment_matches.is_present("no-merge") {
+            println!("Merged {} short cues", caption.merge_short_cues(&opts));
+        }
+        write_caption(output, &caption)?;
+    }
+    if let Some(dedupe_matches) = matches.subcommand_matches("dedupe") {
+        let input = dedupe_matches.value_of("INPUT").unwrap();
+        let output = dedupe_matches.value_of("OUTPUT").unwrap();
+        let mut caption = parse_file(input)?;
+        let before = caption.blocks.len();
+        let deduped = caption.dedupe();
+        println!("Removed repeats from {} cues, leaving {} of {} cues", deduped, caption.blocks.len(), before);
+        write_caption(output, &caption)?;
+    }
+    if let Some(replace_matches) = matches.subcommand_matches("replace") {
+        let input = replace_matches.value_of("INPUT").unwrap();
+        let ignore_case = replace_matches.is_present("ignore-case");
+        let whole_word = replace_matches.is_present("whole-word");
+        let mut rules = match replace_matches.value_of("rules") {
+            Some(fname) => ReplaceRule::from_file(fname, ignore_case, whole_word)?,
+            None => Vec::new(),
+        };
+        if let Some(lines) = replace_matches.values_of("rule") {
+            for line in lines {
+                rules.push(ReplaceRule::parse_line(line, ignore_case, whole_word)?);
+            }
+        }
+        let mut caption = parse_file(input)?;
+        let use_millis = replace_matches.is_present("millis");
+        let fps = replace_matches.value_of("fps");
+        let from = parse_time(replace_matches.value_of("from"), use_millis, fps)?;
+        let to = parse_time(replace_matches.value_of("to"), use_millis, fps)?;
+        let scope = ReplaceScope {
+            target: match replace_matches.value_of("target") {
+                Some("speaker") => ReplaceTarget::Speaker,
+                Some("both") => ReplaceTarget::Both,
+                _ => ReplaceTarget::Text,
+            },
+            cues: replace_matches.value_of("cues").map(parse_cues).transpose()?,
+            from,
+            to,
+        };
+        let changes = caption.replace(&rules, &scope);
+        for change in changes.iter() {
+            println!("{}", change);
+        }
+        println!("{} changes", changes.len());
+        if let Some(output) = replace_matches.value_of("OUTPUT") {
+            if !replace_matches.is_present("dry-run") {
+                write_caption(output, &caption)?;
+            }
+        }
+    }
+    if let Some(grep_matches) = matches.subcommand_matches("grep") {
+        let pattern = grep_matches.value_of("PATTERN").unwrap();
+        let use_millis = grep_matches.is_present("millis");
+        let fps = grep_matches.value_of("fps");
+        let from = parse_time(grep_matches.value_of("from"), use_millis, fps)?;
+        let to = parse_time(grep_matches.value_of("to"), use_millis, fps)?;
+        let query = SearchQuery {
+            pattern: if grep_matches.is_present("fuzzy") {
+                SearchPattern::fuzzy(pattern)
+            }
+            else {
+                SearchPattern::regex(pattern, grep_matches.is_present("ignore-case"))?
+            },
+            speaker: grep_matches.value_of("speaker").map(String::from),
+            from,
+            to,
+        };
+        let mut found = Vec::new();
+        let mut json = Vec::new();
+        for input in grep_matches.values_of("FILES").unwrap() {
+            let caption = parse_file(input)?;
+            for i in caption.search(&query) {
+                let b = &caption.blocks[i];
+                if grep_matches.is_present("json") {
+                    json.push(serde_json::json!({
+                        "file": input,
+                        "cue": i + 1,
+                        "start": b.start().to_string(),
+                        "end": b.end().to_string(),
+                        "speaker": b.speaker(),
+                        "text": b.text(),
+                    }));
+                }
+                else {
+                    let speaker = b.speaker().map(|s| format!("[{}] ", s)).unwrap_or_default();
+                    println!("{}:{}: {} --> {}: {}{}", input, i + 1, b.start(), b.end(), speaker,
+                             b.text().replace('\n', " / "));
+                }
+                found.push(b.clone());
+            }
+        }
+        if grep_matches.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        if let Some(output) = grep_matches.value_of("output") {
+            write_caption(output, &Caption::from(None, found.clone()))?;
+        }
+        if found.is_empty() {
+            std::process::exit(1);
+        }
+    }
+    if let Some(speakers_matches) = matches.subcommand_matches("speakers") {
+        if let Some(list_matches) = speakers_matches.subcommand_matches("list") {
+            let caption = parse_file(list_matches.value_of("INPUT").unwrap())?;
+            let speakers = caption.speakers();
+            let total: usize = caption.blocks.iter().map(|b| b.length_millis()).sum();
+            println!("{:30} | {:>5} | {:12} | %", "Speaker", "Cues", "Talk time");
+            println!("{}", (0..60).map(|_| "-").collect::<String>());
+            for s in speakers.iter() {
+                println!("{:30} | {:5} | {} | {:02}", s.name, s.cues, SimpleTime::from_milliseconds(s.talk_time),
+                         (s.talk_time * 100).checked_div(total).unwrap_or(0));
+            }
+            let unknown = caption.blocks.iter().filter(|b| b.speaker().is_none()).count();
+            if unknown > 0 {
+                println!("{} cues have no speaker", unknown);
+            }
+        }
+        if let Some(rename_matches) = speakers_matches.subcommand_matches("rename") {
+            let mut map = match rename_matches.value_of("map") {
+                Some(fname) => SpeakerMap::from_file(fname)?,
+                None => SpeakerMap::default(),
+            };
+            if let Some(lines) = rename_matches.values_of("rename") {
+                for line in lines {
+                    map.parse_line(line)?;
+                }
+            }
+            let mut caption = parse_file(rename_matches.value_of("INPUT").unwrap())?;
+            println!("Renamed speakers in {} cues", caption.rename_speakers(&map));
+            write_caption(rename_matches.value_of("OUTPUT").unwrap(), &caption)?;
+        }
+        if let Some(anonymise_matches) = speakers_matches.subcommand_matches("anonymise") {
+            let mut caption = parse_file(anonymise_matches.value_of("INPUT").unwrap())?;
+            let key = caption.anonymise_speakers(anonymise_matches.value_of("label").unwrap_or("Speaker"));
+            println!("Anonymised {} speakers", key.len());
+            if let Some(fname) = anonymise_matches.value_of("key") {
+                let lines: Vec<String> = key.iter().map(|(name, label)| format!("{} => {}\n", label, name)).collect();
+                std::fs::write(fname, lines.concat())?;
+            }
+            write_caption(anonymise_matches.value_of("OUTPUT").unwrap(), &caption)?;
+        }
+        if let Some(extract_matches) = speakers_matches.subcommand_matches("extract") {
+            let mut patterns = Vec::new();
+            if let Some(names) = extract_matches.values_of("preset") {
+                patterns.extend(names.map(|name| SpeakerPattern::preset(name).unwrap()));
+            }
+            if let Some(regexes) = extract_matches.values_of("pattern") {
+                for regex in regexes {
+                    patterns.push(SpeakerPattern::new(regex)?);
+                }
+            }
+            if patterns.is_empty() {
+                patterns.extend(SpeakerPattern::DEFAULT_PRESETS.iter().map(|name| SpeakerPattern::preset(name).unwrap()));
+            }
+            let mut caption = parse_file(extract_matches.value_of("INPUT").unwrap())?;
+            println!("Found speakers in {} cues", caption.extract_speakers(&patterns));
+            write_caption(extract_matches.value_of("OUTPUT").unwrap(), &caption)?;
+        }
+        if let Some(render_matches) = speakers_matches.subcommand_matches("render") {
+            let mut caption = parse_file(render_matches.value_of("INPUT").unwrap())?;
+            let template = render_matches.value_of("template").unwrap_or("{SPEAKER}: ");
+            caption.render_speakers(template, render_matches.is_present("changes-only"));
+            write_caption(render_matches.value_of("OUTPUT").unwrap(), &caption)?;
+        }
+    }
+    if let Some(extract_matches) = matches.subcommand_matches("extract") {
+        let input = extract_matches.value_of("INPUT").unwrap();
+        let mut tracks = MediaFile::subtitles_from_file(input)?;
+        if let Some(n) = extract_matches.value_of("track") {
+            let n = n.parse::<u64>()?;
+            tracks.retain(|t| t.number == n);
+            if tracks.is_empty() {
+                return Err(format!("{} has no subtitle track {}", input, n).into());
+            }
+        }
+        if extract_matches.is_present("list") {
+            for t in tracks.iter() {
+                println!("Track {}: {}, {}{}, {} cues", t.number, t.codec,
+                         t.language.as_deref().unwrap_or("unknown language"),
+                         t.name.as_ref().map(|n| format!(" ({})", n)).unwrap_or_default(),
+                         t.caption.blocks.len());
+            }
+        }
+        else if tracks.is_empty() {
+            return Err(format!("{} has no text subtitle tracks", input).into());
+        }
+        else if tracks.len() == 1 {
+            write_caption(extract_matches.value_of("OUTPUT").unwrap(), &tracks[0].caption)?;
+        }
+        else {
+            let output = PathBuf::from(extract_matches.value_of("OUTPUT").unwrap());
+            let stem = output.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            let extension = output.extension().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            for t in tracks.iter() {
+                let fname = output.with_file_name(format!("{}.{}.{}.{}", stem, t.number,
+                                                           t.language.as_deref().unwrap_or("und"), extension));
+                write_caption(&fname.to_string_lossy(), &t.caption)?;
+                println!("Wrote track {} to {}", t.number, fname.to_string_lossy());
+            }
+        }
+    }
+    if let Some(import_matches) = matches.subcommand_matches("import") {
+        let input = import_matches.value_of("INPUT").unwrap();
+        let output = import_matches.value_of("OUTPUT").unwrap();
+        let format = import_matches.value_of("format").and_then(AsrFormat::from_name);
+        let mut opts = SegmentationOptions::default();
+        if let Some(n) = import_matches.value_of("max-chars") {
+            opts.max_chars_per_line = n.parse::<usize>()?;
+        }
+        if let Some(n) = import_matches.value_of("max-lines") {
+            opts.max_lines = n.parse::<usize>()?;
+        }
+        if let Some(n) = import_matches.value_of("max-duration") {
+            opts.max_duration = n.parse::<usize>()?;
+        }
+        if let Some(n) = import_matches.value_of("max-pause") {
+            opts.max_pause = Some(n.parse::<usize>()?);
+        }
+        opts.break_on_speaker_change = !import_matches.is_present("no-speaker-break");
+        let contents = std::fs::read_to_string(input)?;
+        let caption = AsrParser::parse(&contents, format, &opts)?;
+        write_caption(output, &caption)?;
     }
 
     Ok(())